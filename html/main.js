@@ -5,6 +5,16 @@ let app ;
 let user = {is_logged_in:false};
 let gobal_config = {};
 
+// Calls the REST API (state changes) with a JSON body; returns a promise of the JSON response
+function api_call ( method , path , body ) {
+    return fetch(new Request("/api/v1"+path, {
+            method:method,
+            headers:{'Content-Type':'application/json'},
+            body:JSON.stringify(body||{}),
+        }))
+        .then((response) => response.json());
+}
+
 $(document).ready ( function () {
     Promise.all ( [
             vue_components.loadComponents ( [
@@ -13,6 +23,7 @@ $(document).ready ( function () {
                 'vue_components/main_page.html',
                 'vue_components/entity_page.html',
                 'vue_components/access_page.html',
+                'vue_components/consent_page.html',
                 'vue_components/search_dropdown.html',
                 ] ) ,
             new Promise(function(resolve, reject) {
@@ -36,6 +47,7 @@ $(document).ready ( function () {
             { path: '/:group_id', component: MainPage , props:true },
             { path: '/entity/:entity_id', component: EntityPage , props:true },
             { path: '/access/:entity_id/:user_id/:rights', component: AccessPage , props:true },
+            { path: '/consent/:token', component: ConsentPage , props:true },
         ] ;
         router = new VueRouter({routes}) ;
         app = new Vue ( { router } ) .$mount('#app') ;
@@ -0,0 +1,29 @@
+-- Per-entity approval policy: number of distinct admins that need to approve a new right
+CREATE TABLE `approval_policy` (
+  `entity_id` int(10) unsigned NOT NULL,
+  `required_approvals` int(10) unsigned NOT NULL DEFAULT 1,
+  PRIMARY KEY (`entity_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Partial approvals for rights on entities with an approval policy
+CREATE TABLE `access_approval` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `entity_id` int(10) unsigned NOT NULL,
+  `user_id` int(10) unsigned NOT NULL,
+  `right` varchar(64) NOT NULL,
+  `approver_id` int(10) unsigned NOT NULL,
+  `timestamp` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `entity_user_right_approver` (`entity_id`,`user_id`,`right`,`approver_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Approvals for lowering the required approvals of an entity; as many admins as the current policy requires must agree
+CREATE TABLE `approval_policy_change` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `entity_id` int(10) unsigned NOT NULL,
+  `required_approvals` int(10) unsigned NOT NULL,
+  `approver_id` int(10) unsigned NOT NULL,
+  `timestamp` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `entity_approvals_approver` (`entity_id`,`required_approvals`,`approver_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,14 @@
+-- Track when access requests were made, and when they were escalated to admins of ancestor entities
+ALTER TABLE `access_request`
+  ADD `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  ADD `escalated` timestamp NULL DEFAULT NULL;
+
+CREATE TABLE `access_request_escalation` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `request_id` int(10) unsigned NOT NULL,
+  `ancestor_id` int(10) unsigned NOT NULL,
+  `timestamp` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `request_ancestor` (`request_id`,`ancestor_id`),
+  KEY `ancestor_id` (`ancestor_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,4 @@
+-- Whether the login system has verified the email address of this identity; only verified addresses are trusted
+-- for invitations and auto-grant rules
+ALTER TABLE `user`
+  ADD `email_verified` tinyint(1) NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- Grants created for a GitHub organisation in `systems.github.org_grants`; these are removed again when the user
+-- is no longer a member of the organisation
+ALTER TABLE `access`
+  ADD `github_org` varchar(255) DEFAULT NULL;
@@ -0,0 +1,4 @@
+-- Linked accounts: a user row with a principal_id is an additional identity of that principal user
+ALTER TABLE `user`
+  ADD `principal_id` int(10) unsigned NULL DEFAULT NULL,
+  ADD KEY `principal_id` (`principal_id`);
@@ -0,0 +1,16 @@
+-- Personal API tokens, sent as `Authorization: Bearer <token>`. Only a SHA-256 hash of the token is stored.
+-- `rights` and `entity_ids` are comma-separated; empty means no restriction.
+CREATE TABLE `api_token` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `user_id` int(10) unsigned NOT NULL,
+  `name` varchar(255) NOT NULL,
+  `token_hash` char(64) NOT NULL,
+  `rights` text NOT NULL,
+  `entity_ids` text NOT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  `expires` timestamp NOT NULL,
+  `last_used` timestamp NULL DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `token_hash` (`token_hash`),
+  KEY `user_id` (`user_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
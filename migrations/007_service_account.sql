@@ -0,0 +1,11 @@
+-- Service accounts are `user` rows with `system`='service' and the client ID as `external_id`.
+-- They are owned (and managed) by the admins of an entity; only a SHA-256 hash of the client secret is stored.
+CREATE TABLE `service_account` (
+  `user_id` int(10) unsigned NOT NULL,
+  `entity_id` int(10) unsigned NOT NULL,
+  `secret_hash` char(64) NOT NULL,
+  `created_by` int(10) unsigned NOT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`user_id`),
+  KEY `entity_id` (`entity_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,7 @@
+-- Session lifetime: absolute expiry, and last use for the idle timeout
+ALTER TABLE `session`
+  ADD `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  ADD `last_used` timestamp NOT NULL DEFAULT current_timestamp(),
+  ADD `expires` timestamp NULL DEFAULT NULL,
+  ADD KEY `expires` (`expires`),
+  ADD KEY `last_used` (`last_used`);
@@ -0,0 +1,5 @@
+-- Session metadata, so users can review and revoke their sessions
+ALTER TABLE `session`
+  ADD `user_id` int(10) unsigned NULL DEFAULT NULL,
+  ADD `user_agent` varchar(255) NOT NULL DEFAULT '',
+  ADD KEY `user_id` (`user_id`);
@@ -0,0 +1,23 @@
+-- One-time authorization codes issued when sauron acts as an OpenID Connect identity provider.
+-- Only a SHA-256 hash of the code is stored; codes are deleted when redeemed.
+CREATE TABLE `idp_code` (
+  `code_hash` char(64) NOT NULL,
+  `client_id` varchar(255) NOT NULL,
+  `user_id` int(10) unsigned NOT NULL,
+  `redirect_uri` varchar(1024) NOT NULL,
+  `scope` varchar(255) NOT NULL DEFAULT '',
+  `nonce` varchar(255) DEFAULT NULL,
+  `code_challenge` varchar(128) DEFAULT NULL,
+  `expires` timestamp NOT NULL,
+  PRIMARY KEY (`code_hash`),
+  KEY `expires` (`expires`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Scopes a user has consented to for a client of the identity provider; trusted clients do not need consent
+CREATE TABLE `idp_consent` (
+  `user_id` int(10) unsigned NOT NULL,
+  `client_id` varchar(255) NOT NULL,
+  `scope` varchar(1024) NOT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`user_id`,`client_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
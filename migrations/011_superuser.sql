@@ -0,0 +1,19 @@
+-- Global superusers, independent of the entity tree. Superusers can also be bootstrapped via `superusers` in config.json.
+CREATE TABLE `superuser` (
+  `user_id` int(10) unsigned NOT NULL,
+  `granted_by` int(10) unsigned DEFAULT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`user_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Actions that were only allowed because of superuser rights
+CREATE TABLE `superuser_log` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `user_id` int(10) unsigned NOT NULL,
+  `action` varchar(255) NOT NULL,
+  `details` text NOT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`id`),
+  KEY `user_id` (`user_id`),
+  KEY `created` (`created`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,3 @@
+-- Deactivated users can not log in, and their rights are ignored; they are kept for reactivation
+ALTER TABLE `user`
+  ADD `deactivated` tinyint(1) NOT NULL DEFAULT 0;
@@ -0,0 +1,17 @@
+-- Pending grants for people who have not logged in yet. `target` is an ORCID iD, a lower-case email address,
+-- or (for invite links) a SHA-256 hash of the link token. `entity_ids` and `rights` are comma-separated.
+CREATE TABLE `invitation` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `kind` enum('orcid','email','link') NOT NULL,
+  `target` varchar(255) NOT NULL,
+  `entity_ids` varchar(255) NOT NULL,
+  `rights` varchar(255) NOT NULL,
+  `created_by` int(10) unsigned NOT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  `expires` timestamp NOT NULL,
+  `accepted_by` int(10) unsigned DEFAULT NULL,
+  `accepted` timestamp NULL DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `kind_target` (`kind`,`target`),
+  KEY `accepted_by` (`accepted_by`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,19 @@
+-- Rules that grant rights on an entity to everyone whose login matches, e.g. an email domain.
+-- `field` is a JSON pointer into `user.bespoke_data` for kind 'bespoke_data', and empty otherwise. `rights` is comma-separated.
+CREATE TABLE `auto_grant_rule` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `entity_id` int(10) unsigned NOT NULL,
+  `kind` enum('email_domain','system','bespoke_data') NOT NULL,
+  `field` varchar(255) NOT NULL DEFAULT '',
+  `value` varchar(255) NOT NULL,
+  `rights` varchar(255) NOT NULL,
+  `created_by` int(10) unsigned NOT NULL,
+  `created` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`id`),
+  KEY `entity_id` (`entity_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Grants created by an auto-grant rule; these are removed again when the rule no longer matches
+ALTER TABLE `access`
+  ADD `auto_grant_rule_id` int(10) unsigned DEFAULT NULL,
+  ADD KEY `auto_grant_rule_id` (`auto_grant_rule_id`);
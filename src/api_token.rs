@@ -0,0 +1,188 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use axum::{async_trait, extract::FromRequestParts, TypedHeader};
+use headers::authorization::{Authorization, Bearer};
+use http::request::Parts;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::app_state::AppState;
+use crate::database_abstraction_layer::DatabaseAbstractionLayer;
+use crate::error::RingError;
+use crate::external_system::ExternalSystemUser;
+
+pub static TOKEN_PREFIX: &str = "sauron_";
+static MAX_SCOPE_RIGHTS: usize = 100;
+static MAX_SCOPE_ENTITIES: usize = 1000;
+
+/// A personal API token. The token itself is only shown once, at creation; the database only has its hash.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: usize,
+    pub user_id: usize,
+    pub name: String,
+    pub rights: Vec<String>, // Empty means all rights of the user
+    pub entity_ids: Vec<usize>, // Subtree roots; empty means all entities
+    pub created: String,
+    pub expires: String,
+    pub last_used: Option<String>,
+}
+
+impl ApiToken {
+    pub fn from_row(row: &mysql_async::Row) -> Self {
+        let rights: String = row.get(3).unwrap();
+        let entity_ids: String = row.get(4).unwrap();
+        Self {
+            id: row.get(0).unwrap(),
+            user_id: row.get(1).unwrap(),
+            name: row.get(2).unwrap(),
+            rights: rights.split(',').filter(|s|!s.is_empty()).map(|s|s.to_string()).collect(),
+            entity_ids: entity_ids.split(',').filter_map(|s|s.parse::<usize>().ok()).collect(),
+            created: row.get(5).unwrap(),
+            expires: row.get(6).unwrap(),
+            last_used: row.get(7).unwrap(),
+        }
+    }
+
+    /// Generates a new random token, and returns it together with its hash
+    pub fn generate() -> (String,String) {
+        let token = random_string(TOKEN_PREFIX,32);
+        let hash = Self::hash(&token);
+        (token,hash)
+    }
+
+    pub fn hash(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+
+    /// Checks the rights and entity IDs a new token is restricted to
+    pub fn validate_scope(rights: &[String], entity_ids: &[usize]) -> Result<(),RingError> {
+        if rights.len()>MAX_SCOPE_RIGHTS || entity_ids.len()>MAX_SCOPE_ENTITIES {
+            return Err(RingError::Validation(format!("An API token can be restricted to at most {MAX_SCOPE_RIGHTS} rights and {MAX_SCOPE_ENTITIES} entities")));
+        }
+        if let Some(right) = rights.iter().find(|r|r.len()>64 || !r.chars().all(|c|c.is_alphanumeric() || c=='_' || c=='-')) {
+            return Err(RingError::Validation(format!("Invalid right '{right}'")));
+        }
+        Ok(())
+    }
+
+    /// Checks if this token may be used for the given right
+    pub fn allows_right(&self, right: &str) -> bool {
+        self.rights.is_empty() || self.rights.iter().any(|r|r==right)
+    }
+
+    /// Checks if an entity is within the subtrees this token is restricted to
+    pub async fn covers(&self, dal: &DatabaseAbstractionLayer, entity_id: usize) -> Result<bool,RingError> {
+        if self.entity_ids.is_empty() || self.entity_ids.contains(&entity_id) {
+            return Ok(true);
+        }
+        let ancestors = dal.get_entity_ancestors(entity_id).await?;
+        Ok(ancestors.iter().any(|id|self.entity_ids.contains(id)))
+    }
+}
+
+/// Returns a prefixed random hex string from `num_bytes` random bytes, for tokens, secrets and IDs
+pub fn random_string(prefix: &str, num_bytes: usize) -> String {
+    let mut bytes = vec![0u8; num_bytes];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{prefix}{}",hex::encode(bytes))
+}
+
+/// The credentials sent with a request: the session cookie, and/or an `Authorization: Bearer` API token
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+    pub cookies: Option<TypedHeader<headers::Cookie>>,
+    pub bearer: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Credentials
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let cookies = Option::<TypedHeader<headers::Cookie>>::from_request_parts(parts, state).await?;
+        let bearer = Option::<TypedHeader<Authorization<Bearer>>>::from_request_parts(parts, state).await?
+            .map(|TypedHeader(auth)|auth.token().to_string());
+        Ok(Self { cookies, bearer })
+    }
+}
+
+impl Credentials {
+    /// Returns the current user, and the API token that was used, if any.
+    /// A bearer token takes precedence over the session cookie. Deactivated users are not logged in.
+    pub async fn user(&self, state: &Arc<AppState>) -> Result<Option<(ExternalSystemUser,Option<ApiToken>)>,RingError> {
+        let (user,token) = match self.user_unchecked(state).await? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let user_id = match user.id {
+            Some(id) => id as usize,
+            None => return Ok(None),
+        };
+        match state.dal.read().await.is_user_deactivated(user_id).await? {
+            false => Ok(Some((user,token))),
+            true => Ok(None),
+        }
+    }
+
+    async fn user_unchecked(&self, state: &Arc<AppState>) -> Result<Option<(ExternalSystemUser,Option<ApiToken>)>,RingError> {
+        match &self.bearer {
+            Some(token) => {
+                let token = match state.dal.read().await.get_api_token(&ApiToken::hash(token)).await? {
+                    Some(token) => token,
+                    None => return Ok(None),
+                };
+                let user = state.dal.read().await.get_user(token.user_id).await?;
+                Ok(Some((user,Some(token))))
+            }
+            None => Ok(ExternalSystemUser::from_cookies(state, &self.cookies).await.map(|user|(user,None))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(rights: &[&str]) -> ApiToken {
+        ApiToken {
+            id: 1,
+            user_id: 1,
+            name: "test".to_string(),
+            rights: rights.iter().map(|r|r.to_string()).collect(),
+            entity_ids: vec![],
+            created: String::new(),
+            expires: String::new(),
+            last_used: None,
+        }
+    }
+
+    #[test]
+    fn tokens_without_rights_allow_all_rights() {
+        assert!(token(&[]).allows_right("admin"));
+        assert!(token(&["read","write"]).allows_right("write"));
+        assert!(!token(&["read"]).allows_right("admin"));
+    }
+
+    #[test]
+    fn token_scopes_are_limited() {
+        let rights = |rights: &[&str]|rights.iter().map(|r|r.to_string()).collect::<Vec<String>>();
+        assert!(ApiToken::validate_scope(&rights(&["read","edit-pages","some_right"]),&[1,2]).is_ok());
+        assert!(ApiToken::validate_scope(&rights(&["read,admin"]),&[]).is_err());
+        assert!(ApiToken::validate_scope(&rights(&["read admin"]),&[]).is_err());
+        assert!(ApiToken::validate_scope(&rights(&[&"x".repeat(65)]),&[]).is_err());
+        assert!(ApiToken::validate_scope(&vec!["read".to_string();MAX_SCOPE_RIGHTS+1],&[]).is_err());
+        assert!(ApiToken::validate_scope(&[],&vec![1;MAX_SCOPE_ENTITIES+1]).is_err());
+    }
+
+    #[test]
+    fn tokens_are_stored_as_hashes() {
+        let (token,hash) = ApiToken::generate();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(ApiToken::hash(&token),hash);
+        assert_ne!(token,hash);
+    }
+}
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::{env, fs::File};
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokio::sync::RwLock;
 use crate::error::RingError;
 use crate::database_abstraction_layer::DatabaseAbstractionLayer;
+use crate::notification::Notifier;
+use crate::oidc::OidcProvider;
+use crate::idp::Idp;
+use crate::external_system::{ExternalSystem, ExternalSystemUser};
 
 
 // ************************************************************************************************
@@ -15,6 +20,9 @@ pub struct AppState {
     pub port_https: u16,
     pub server: String,
     pub dal: Arc<RwLock<DatabaseAbstractionLayer>>,
+    pub notifier: Option<Notifier>,
+    pub oidc_providers: HashMap<String,OidcProvider>,
+    pub idp: Option<Idp>, // Set if sauron acts as an identity provider
 }
 
 impl AppState {
@@ -29,17 +37,66 @@ impl AppState {
 
     /// Creatre an AppState object from a config JSON object
     pub async fn from_config(config: Value) -> Result<Self,RingError> {
-        let ret = Self {
+        let mut ret = Self {
             port_http: config["port_http"].as_u64().expect("Port number in config file missing or not an integer") as u16,
             port_https: config["port_https"].as_u64().expect("Port number in config file missing or not an integer") as u16,
             server: config["server"].as_str().expect("server URL not in config").to_string(),
             dal: Arc::new(RwLock::new(DatabaseAbstractionLayer::new(&config).await?)),
-            config: config,
+            notifier: Notifier::from_config(&config["notifications"])?,
+            oidc_providers: OidcProvider::all_from_config(&config["systems"])?,
+            idp: None,
+            config,
         };
+        ret.idp = Idp::from_config(&ret.config["idp"],&ret.get_redirect_server())?;
         Ok(ret)
     }
 
 
+    /// Returns the URL of the user page in the external system, if any
+    pub fn external_url(&self, user: &ExternalSystemUser) -> String {
+        match &user.system {
+            ExternalSystem::OIDC(key) => self.oidc_providers.get(key).map(|p|p.as_url(&user.external_id)).unwrap_or_default(),
+            ExternalSystem::WIKIMEDIA => format!("{}/wiki/User:{}",self.wikimedia_base_url(),user.external_name().replace(' ',"_")),
+            _ => user.external_url(),
+        }
+    }
+
+    /// Base URL of the Wikimedia OAuth 2.0 server; can be overridden to use a local mock server
+    pub fn wikimedia_base_url(&self) -> String {
+        self.config["systems"]["wikimedia"]["base_url"].as_str().unwrap_or("https://meta.wikimedia.org").trim_end_matches('/').to_string()
+    }
+
+    /// Returns user data safe to show to others, with external URL, name and service account marker
+    pub fn public_user_json(&self, mut user: ExternalSystemUser) -> Value {
+        // Both use the bespoke data, which is stripped below
+        let external_url = self.external_url(&user);
+        let external_name = user.external_name();
+        user.strip_private_data(); // Prevent private data from leaking
+        let mut user_j = json!(user);
+        user_j["external_url"] = json!(external_url);
+        user_j["external_name"] = json!(external_name);
+        user_j["service_account"] = json!(user.is_service_account());
+        user_j
+    }
+
+    /// Checks if a user is a superuser, either via the DB flag, or because one of their identities
+    /// is listed in `superusers` in the config file (to bootstrap the first superuser)
+    pub async fn is_superuser(&self, user_id: usize) -> Result<bool,RingError> {
+        let dal = self.dal.read().await;
+        if dal.has_superuser_flag(user_id).await? {
+            return Ok(true);
+        }
+        let bootstrap = match self.config["superusers"].as_array() {
+            Some(bootstrap) if !bootstrap.is_empty() => bootstrap,
+            _ => return Ok(false),
+        };
+        let principal_id = dal.get_principal_id(user_id).await?;
+        let identities = dal.get_linked_users(principal_id).await?;
+        Ok(identities.iter().any(|user|{
+            bootstrap.iter().any(|su|su["system"].as_str()==Some(user.system.as_str()) && su["external_id"].as_str()==Some(&user.external_id))
+        }))
+    }
+
     pub fn get_redirect_server(&self) -> String {
         match self.port_https {
             443 => format!("https://{}",self.server),
@@ -0,0 +1,261 @@
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::app_state::AppState;
+use crate::database_abstraction_layer::DatabaseAbstractionLayer;
+use crate::error::RingError;
+use crate::external_system::{ExternalSystem, ExternalSystemUser};
+
+pub static KIND_EMAIL_DOMAIN: &str = "email_domain";
+pub static KIND_SYSTEM: &str = "system";
+pub static KIND_BESPOKE_DATA: &str = "bespoke_data";
+
+/// Grants rights on an entity to every user with a matching login, e.g. everyone with an email address @example.org.
+/// Rules are evaluated at login, and periodically for all users; grants whose rule no longer matches are removed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AutoGrantRule {
+    pub id: usize,
+    pub entity_id: usize,
+    pub kind: String,
+    pub field: String, // JSON pointer into bespoke_data, e.g. /affiliations
+    pub value: String,
+    pub rights: Vec<String>,
+    pub created_by: usize,
+    pub created: String,
+}
+
+impl AutoGrantRule {
+    pub fn from_row(row: &mysql_async::Row) -> Self {
+        let rights: String = row.get(5).unwrap();
+        Self {
+            id: row.get(0).unwrap(),
+            entity_id: row.get(1).unwrap(),
+            kind: row.get(2).unwrap(),
+            field: row.get(3).unwrap(),
+            value: row.get(4).unwrap(),
+            rights: rights.split(',').filter(|s|!s.is_empty()).map(|s|s.to_string()).collect(),
+            created_by: row.get(6).unwrap(),
+            created: row.get(7).unwrap(),
+        }
+    }
+
+    /// Normalises and checks the field and value of a new rule. Returns (field,value).
+    pub fn normalize(kind: &str, field: &str, value: &str) -> Result<(String,String),RingError> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(RingError::Validation("An auto-grant rule needs a value to match".into()));
+        }
+        if kind==KIND_EMAIL_DOMAIN {
+            let domain = value.trim_start_matches('@').to_lowercase();
+            if domain.is_empty() || domain.contains(['@',' ']) {
+                return Err(RingError::Validation(format!("Invalid email domain '{value}'")));
+            }
+            Ok((String::new(),domain))
+        } else if kind==KIND_SYSTEM {
+            match ExternalSystem::from_name(value) {
+                ExternalSystem::SERVICE | ExternalSystem::Unknown => Err(RingError::Validation(format!("Invalid login system '{value}'"))),
+                system => Ok((String::new(),system.as_str().to_string())),
+            }
+        } else if kind==KIND_BESPOKE_DATA {
+            let field = field.trim();
+            if !field.starts_with('/') {
+                return Err(RingError::Validation(format!("Field '{field}' must be a JSON pointer, e.g. /affiliations")));
+            }
+            Ok((field.to_string(),value.to_string()))
+        } else {
+            Err(RingError::Validation(format!("Unknown auto-grant rule type '{kind}'")))
+        }
+    }
+
+    /// Checks if the rule matches a login identity. Service accounts and deactivated users never match,
+    /// and email domain rules only match addresses verified by the login system.
+    pub fn matches(&self, user: &ExternalSystemUser) -> bool {
+        if user.is_service_account() || user.deactivated {
+            return false;
+        }
+        if self.kind==KIND_EMAIL_DOMAIN {
+            let email = user.verified_email().unwrap_or_default().trim().to_lowercase();
+            email.rsplit_once('@').map(|(_,domain)|domain==self.value).unwrap_or(false)
+        } else if self.kind==KIND_SYSTEM {
+            user.system.as_str()==self.value
+        } else if self.kind==KIND_BESPOKE_DATA {
+            user.bespoke_data.pointer(&self.field).map(|v|value_matches(v,&self.value)).unwrap_or(false)
+        } else {
+            false
+        }
+    }
+}
+
+/// Strings match case-insensitively, and arrays match if any of their elements does
+fn value_matches(v: &Value, value: &str) -> bool {
+    match v {
+        Value::String(s) => s.eq_ignore_ascii_case(value),
+        Value::Number(n) => n.to_string()==value,
+        Value::Bool(b) => b.to_string()==value,
+        Value::Array(a) => a.iter().any(|v|value_matches(v,value)),
+        _ => false,
+    }
+}
+
+/// Number of users evaluated under one write lock by `apply_to_users`
+const BATCH_SIZE: usize = 100;
+
+/// Brings the rule-based grants of a user (or rather, their principal) in line with the rules:
+/// rights of matching rules are added, and rule-based rights that no longer match any rule are removed.
+/// Rights that were also granted manually are never touched.
+pub async fn apply_to_user(state: &Arc<AppState>, user_id: usize, rules: &[AutoGrantRule]) -> Result<(),RingError> {
+    let mut dal = state.dal.write().await;
+    let rules = applicable_rules(&dal,rules).await?;
+    apply_rules(&mut dal,user_id,&rules).await
+}
+
+/// Returns the rules on entities that do not require multiple approvals. A policy set after the rule was created disables it.
+async fn applicable_rules<'a>(dal: &DatabaseAbstractionLayer, rules: &'a [AutoGrantRule]) -> Result<Vec<&'a AutoGrantRule>,RingError> {
+    let mut ret = vec![];
+    for rule in rules {
+        if dal.get_required_approvals(rule.entity_id).await?<=1 {
+            ret.push(rule);
+        }
+    }
+    Ok(ret)
+}
+
+async fn apply_rules(dal: &mut DatabaseAbstractionLayer, user_id: usize, rules: &[&AutoGrantRule]) -> Result<(),RingError> {
+    let principal_id = dal.get_principal_id(user_id).await?;
+    if dal.is_user_deactivated(principal_id).await? {
+        return Ok(()); // Grants are ignored anyway; they are updated after reactivation
+    }
+    let identities = dal.get_linked_users(principal_id).await?;
+    let mut wanted: Vec<(usize,String,usize)> = vec![];
+    for rule in rules.iter().filter(|rule|identities.iter().any(|user|rule.matches(user))) {
+        for right in &rule.rights {
+            if !wanted.iter().any(|(entity_id,r,_)|*entity_id==rule.entity_id && r==right) {
+                wanted.push((rule.entity_id,right.to_owned(),rule.id));
+            }
+        }
+    }
+    let existing = dal.get_auto_grants(principal_id).await?;
+    for (entity_id,right,rule_id) in &existing {
+        if !wanted.iter().any(|(e,r,_)|e==entity_id && r==right) {
+            dal.remove_auto_granted_right(principal_id,*entity_id,right,*rule_id).await?;
+            tracing::info!("Removed '{right}' on entity #{entity_id} from user #{principal_id}, auto-grant rule #{rule_id} no longer applies");
+        }
+    }
+    for (entity_id,right,rule_id) in &wanted {
+        if dal.add_auto_granted_right(principal_id,*entity_id,right,*rule_id).await? {
+            tracing::info!("Granted '{right}' on entity #{entity_id} to user #{principal_id} via auto-grant rule #{rule_id}");
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates all rules for a user that just logged in
+pub async fn apply_at_login(state: &Arc<AppState>, user_id: usize) -> Result<(),RingError> {
+    let rules = state.dal.read().await.get_auto_grant_rules().await?;
+    apply_to_user(state,user_id,&rules).await
+}
+
+/// Evaluates the rules for several users, in batches that each hold the write lock once.
+/// A user that fails is logged and skipped.
+pub async fn apply_to_users(state: &Arc<AppState>, user_ids: &[usize], rules: &[AutoGrantRule]) -> Result<(),RingError> {
+    for batch in user_ids.chunks(BATCH_SIZE) {
+        let mut dal = state.dal.write().await;
+        let rules = applicable_rules(&dal,rules).await?;
+        for user_id in batch {
+            if let Err(e) = apply_rules(&mut dal,*user_id,&rules).await {
+                tracing::warn!("Could not apply auto-grant rules to user #{user_id}: {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Re-evaluates all rules for all users, e.g. after rules were changed
+pub async fn apply_to_all_users(state: &Arc<AppState>) -> Result<(),RingError> {
+    let rules = state.dal.read().await.get_auto_grant_rules().await?;
+    let mut user_ids = state.dal.read().await.get_users_with_auto_grants(None).await?;
+    if !rules.is_empty() {
+        user_ids.append(&mut state.dal.read().await.get_principal_user_ids().await?);
+    }
+    user_ids.sort();
+    user_ids.dedup();
+    apply_to_users(state,&user_ids,&rules).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn user(email: &str, email_verified: bool, bespoke_data: Value) -> ExternalSystemUser {
+        ExternalSystemUser {
+            id: Some(1),
+            system: ExternalSystem::ORCID,
+            name: "Some One".to_string(),
+            email: email.to_string(),
+            email_verified,
+            external_id: "0000-0002-1825-0097".to_string(),
+            bespoke_data,
+            principal_id: None,
+            deactivated: false,
+        }
+    }
+
+    fn rule(kind: &str, field: &str, value: &str) -> AutoGrantRule {
+        let (field,value) = AutoGrantRule::normalize(kind,field,value).unwrap();
+        AutoGrantRule {
+            id: 1,
+            entity_id: 1,
+            kind: kind.to_string(),
+            field,
+            value,
+            rights: vec!["read".to_string()],
+            created_by: 1,
+            created: String::new(),
+        }
+    }
+
+    #[test]
+    fn rules_are_normalized_on_creation() {
+        assert_eq!(AutoGrantRule::normalize(KIND_EMAIL_DOMAIN,"","  @Example.ORG ").unwrap(),(String::new(),"example.org".to_string()));
+        assert!(AutoGrantRule::normalize(KIND_EMAIL_DOMAIN,"","someone@example.org").is_err());
+        assert!(AutoGrantRule::normalize(KIND_EMAIL_DOMAIN,""," ").is_err());
+        assert_eq!(AutoGrantRule::normalize(KIND_SYSTEM,"","GitHub").unwrap(),(String::new(),"github".to_string()));
+        assert!(AutoGrantRule::normalize(KIND_SYSTEM,"","service").is_err());
+        assert_eq!(AutoGrantRule::normalize(KIND_BESPOKE_DATA," /affiliations ","Some Uni").unwrap(),("/affiliations".to_string(),"Some Uni".to_string()));
+        assert!(AutoGrantRule::normalize(KIND_BESPOKE_DATA,"affiliations","Some Uni").is_err());
+        assert!(AutoGrantRule::normalize("unknown","","x").is_err());
+    }
+
+    #[test]
+    fn email_domain_rules_need_a_verified_email() {
+        let rule = rule(KIND_EMAIL_DOMAIN,"","example.org");
+        assert!(rule.matches(&user("Some.One@Example.org",true,json!({}))));
+        assert!(!rule.matches(&user("some.one@example.org",false,json!({}))));
+        assert!(!rule.matches(&user("some.one@sub.example.org",true,json!({}))));
+        assert!(!rule.matches(&user("",true,json!({}))));
+    }
+
+    #[test]
+    fn rules_do_not_match_deactivated_users_or_service_accounts() {
+        let rule = rule(KIND_SYSTEM,"","orcid");
+        let mut user = user("",false,json!({}));
+        assert!(rule.matches(&user));
+        user.deactivated = true;
+        assert!(!rule.matches(&user));
+        user.deactivated = false;
+        user.system = ExternalSystem::SERVICE;
+        assert!(!rule.matches(&user));
+    }
+
+    #[test]
+    fn bespoke_data_rules_match_values_and_arrays() {
+        let rule = rule(KIND_BESPOKE_DATA,"/affiliations","some uni");
+        assert!(rule.matches(&user("",false,json!({"affiliations":["Other Uni","Some Uni"]}))));
+        assert!(!rule.matches(&user("",false,json!({"affiliations":["Other Uni"]}))));
+        assert!(!rule.matches(&user("",false,json!({}))));
+        assert!(value_matches(&json!(42),"42"));
+        assert!(value_matches(&json!(true),"true"));
+        assert!(!value_matches(&json!({"name":"some uni"}),"some uni"));
+    }
+}
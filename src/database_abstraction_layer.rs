@@ -1,13 +1,17 @@
 use std::collections::HashMap;
 use std::time::Duration;
 use mysql_async::{prelude::*, from_row};
-use mysql_async::{Conn, PoolOpts, PoolConstraints, OptsBuilder, Opts};
+use mysql_async::{Conn, PoolOpts, PoolConstraints, OptsBuilder, Opts, Transaction, TxOpts};
 use serde_json::Value;
 use crate::db_tables::{DbTableAccess, DbTableConnection, DbTableEntity};
 use crate::error::RingError;
 use crate::database_session_store::DatabaseSessionStore;
 use crate::entity::{Entity, EntityGroup};
-use crate::external_system::{ExternalSystemUser, ExternalSystem, ExternalAccessRequest};
+use crate::external_system::{ExternalSystemUser, ExternalSystem, ExternalAccessRequest, AccessApproval, SuperuserAction};
+use crate::api_token::ApiToken;
+use crate::idp::IdpCode;
+use crate::invitation::{Invitation, KIND_EMAIL, KIND_LINK, KIND_ORCID};
+use crate::auto_grant::AutoGrantRule;
 
 
 #[derive(Clone, Debug)]
@@ -23,11 +27,16 @@ pub struct DatabaseAbstractionLayer {
 }
 
 impl DatabaseAbstractionLayer {
+    /// `api_token.last_used` is updated at most this often
+    const API_TOKEN_LAST_USED_SEC: u64 = 300;
+    const INVITATION_SELECT: &'static str = "SELECT `id`,`kind`,`target`,`entity_ids`,`rights`,`created_by`,CAST(`created` AS CHAR),CAST(`expires` AS CHAR),`accepted_by`,CAST(`accepted` AS CHAR) FROM `invitation`";
+    const AUTO_GRANT_RULE_SELECT: &'static str = "SELECT `id`,`entity_id`,`kind`,`field`,`value`,`rights`,`created_by`,CAST(`created` AS CHAR) FROM `auto_grant_rule`";
+
     pub async fn new(config: &Value) -> Result<Self,RingError> {
         let db_pool = Self::create_pool(&config["database"]);
         let mut ret = Self {
             use_cached: config["use_cache"].as_bool().unwrap_or(false),
-            session_store: DatabaseSessionStore::new_with_pool(&db_pool).await?,
+            session_store: DatabaseSessionStore::new_with_pool(&db_pool,&config["sessions"]).await?,
             db_pool: db_pool.clone(),
             db_access: HashMap::new(), // Not used if use_cache=false
             db_connection: HashMap::new(), // Not used if use_cache=false
@@ -50,9 +59,9 @@ impl DatabaseAbstractionLayer {
 
     pub async fn load_entities(&self, entity_ids: &[usize]) -> Result<EntityGroup,RingError> {
         if self.use_cached {
-            return self.load_entities_cached(entity_ids);
+            self.load_entities_cached(entity_ids)
         } else {
-            return self.load_entities_db(entity_ids).await;
+            self.load_entities_db(entity_ids).await
         }
     }
 
@@ -72,23 +81,76 @@ impl DatabaseAbstractionLayer {
         }
     }
 
-    pub async fn add_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, rights: Vec<String>) -> Result<(),RingError> {
-        let existing_rights: Vec<(usize,String)> = self.get_user_rights_for_entities(user_id).await?
-            .into_iter()
-            .filter(|(entity_id,_right)| entity_ids.contains(entity_id))
+    /// Adds rights for a user. If an approver is given, rights on entities with an approval policy
+    /// are only added once enough admins have approved them.
+    /// Returns (entity_id,right) pairs that are still waiting for approvals.
+    pub async fn add_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, rights: Vec<String>, approver_id: Option<usize>) -> Result<Vec<(usize,String)>,RingError> {
+        let requested_rights: Vec<(usize,String)> = entity_ids.iter()
+            .flat_map(|entity_id| rights.iter().map(|right|(*entity_id,right.to_owned())).collect::<Vec<(usize,String)>>())
             .collect();
-        let add_rights: Vec<(usize,String)> = entity_ids.iter()
-            .map(|entity_id| rights.iter().map(|right|(*entity_id,right.to_owned())).collect::<Vec<(usize,String)>>() )
-            .flatten()
-            .filter(|x|!existing_rights.contains(x))
-            .collect();
-        for (entity_id,right) in &add_rights {
-            self.add_right(user_id,*entity_id,right).await?;
+        self.add_access_right_pairs(user_id,requested_rights,approver_id).await
+    }
+
+    /// Adds (entity_id,right) pairs for a user in one transaction, see `add_access_rights`
+    pub async fn add_access_right_pairs(&mut self, user_id: usize, requested_rights: Vec<(usize,String)>, approver_id: Option<usize>) -> Result<Vec<(usize,String)>,RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let pending = self.add_access_right_pairs_in(&mut tx,user_id,requested_rights,approver_id).await?;
+        tx.commit().await?;
+        self.reload_user_access(user_id).await?;
+        Ok(pending)
+    }
+
+    /// Adds rights for a (principal) user within a transaction. The cache is not updated.
+    async fn add_access_right_pairs_in(&self, tx: &mut Transaction<'_>, user_id: usize, requested_rights: Vec<(usize,String)>, approver_id: Option<usize>) -> Result<Vec<(usize,String)>,RingError> {
+        let existing_rights = self.get_user_grants(user_id).await?;
+        let (keep_rights,add_rights): (Vec<_>,Vec<_>) = requested_rights.into_iter()
+            .partition(|x|existing_rights.contains(x));
+        Self::mark_as_manual_grants(tx,user_id,&keep_rights).await?;
+        self.add_rights_with_approval(tx,user_id,&add_rights,approver_id).await
+    }
+
+    /// Rights that are granted manually, but were already held via a GitHub organisation or an auto-grant rule,
+    /// are kept when the organisation or rule no longer matches
+    async fn mark_as_manual_grants(tx: &mut Transaction<'_>, user_id: usize, rights: &[(usize,String)]) -> Result<(),RingError> {
+        let sql = "UPDATE `access` SET `github_org`=NULL,`auto_grant_rule_id`=NULL WHERE `user_id`=:user_id AND `entity_id`=:entity_id AND `right`=:right AND (`github_org` IS NOT NULL OR `auto_grant_rule_id` IS NOT NULL)";
+        for (entity_id,right) in rights {
+            tx.exec_drop(sql, params!{user_id,entity_id,right}).await?;
         }
         Ok(())
     }
 
-    pub async fn remove_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, rights: Vec<String>) -> Result<(),RingError> {
+    /// Reloads the cached rights and access requests of a (principal) user, after they were changed in a transaction
+    async fn reload_user_access(&mut self, user_id: usize) -> Result<(),RingError> {
+        if !self.use_cached {
+            return Ok(());
+        }
+        let mut conn = self.db_conn().await?;
+        let sql = "SELECT `id`,`user_id`,`entity_id`,`right` FROM `access` WHERE `user_id`=:user_id" ;
+        let access = conn.exec_iter(sql,params!{user_id}).await?
+            .map_and_drop(|row| DbTableAccess::from_row(&row) ).await?;
+        let sql = "SELECT `id`,`user_id`,`entity_id`,`note`,CAST(`created` AS CHAR),CAST(`escalated` AS CHAR) FROM `access_request` WHERE `user_id`=:user_id" ;
+        let requests = conn.exec_iter(sql,params!{user_id}).await?
+            .map_and_drop(|row| ExternalAccessRequest::from_row(&row) ).await?;
+        self.db_access.retain(|_id,entry| entry.user_id!=user_id);
+        self.db_access.extend(access.into_iter().map(|x|(x.id,x)));
+        self.db_access_request.retain(|_id,ar| ar.user_id!=user_id);
+        self.db_access_request.extend(requests.into_iter().map(|x|(x.id,x)));
+        Ok(())
+    }
+
+    /// Removes rights of a user on entities. Fails if that would leave an entity without an effective admin, unless `force` is set.
+    pub async fn remove_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, rights: Vec<String>, force: bool) -> Result<(),RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        if !force && rights.iter().any(|right|right=="admin") {
+            let admin_removals: Vec<usize> = self.get_user_grants(user_id).await?
+                .into_iter()
+                .filter(|(entity_id,right)|right=="admin" && entity_ids.contains(entity_id))
+                .map(|(entity_id,_right)|entity_id)
+                .collect();
+            self.check_admin_lockout(user_id,&admin_removals).await?;
+        }
         for entity_id in entity_ids {
             for right in &rights {
                 self.remove_right(user_id,entity_id,right).await?;
@@ -97,35 +159,75 @@ impl DatabaseAbstractionLayer {
         Ok(())
     }
 
+    /// Removes all direct rights of a user on an entity and all its descendants, and their pending approvals, in one transaction.
+    /// `checked_entity_ids` are the entities the caller has checked permissions on; fails if rights on other entities would be removed,
+    /// e.g. because rights or the entity tree changed since. Fails if that would leave an entity without an effective admin,
+    /// unless `force` is set. Returns the removed (entity_id,right) pairs.
+    pub async fn revoke_subtree_rights(&mut self, user_id: usize, entity_id: usize, checked_entity_ids: &[usize], force: bool) -> Result<Vec<(usize,String)>,RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        let subtree = self.get_entity_subtree(&[entity_id]).await?;
+        let mut removed: Vec<(usize,String)> = self.get_user_grants(user_id).await?
+            .into_iter()
+            .filter(|(entity_id,_right)|subtree.contains(entity_id))
+            .collect();
+        removed.sort();
+        if let Some((unchecked_id,_right)) = removed.iter().find(|(entity_id,_right)|!checked_entity_ids.contains(entity_id)) {
+            return Err(RingError::Conflict(format!("The rights of user #{user_id} on entity #{unchecked_id} changed, please try again")));
+        }
+        if !force {
+            let admin_removals: Vec<usize> = removed.iter()
+                .filter(|(_entity_id,right)|right=="admin")
+                .map(|(entity_id,_right)|*entity_id)
+                .collect();
+            self.check_admin_lockout(user_id,&admin_removals).await?;
+        }
+        let entity_ids_str = subtree.iter().map(|s|format!("{s}")).collect::<Vec<String>>().join(",");
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        for sql in [
+            format!("DELETE FROM `access` WHERE `user_id`=:user_id AND `entity_id` IN ({entity_ids_str})"),
+            format!("DELETE FROM `access_approval` WHERE `user_id`=:user_id AND `entity_id` IN ({entity_ids_str})"),
+        ] {
+            tx.exec_drop(sql, params!{user_id}).await?;
+        }
+        tx.commit().await?;
+        if self.use_cached {
+            self.db_access.retain(|_id,entry| entry.user_id!=user_id || !subtree.contains(&entry.entity_id));
+        }
+        Ok(removed)
+    }
+
     pub async fn request_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, note: &str) -> Result<(),RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
         for entity_id in entity_ids {
             self.request_right(user_id,entity_id,note).await?;
         }
         Ok(())
     }
 
+    /// Adds an access request. A repeated request only updates the note, so it keeps its ID, creation time and escalations.
     async fn request_right(&mut self, user_id: usize, entity_id: usize, note: &str) -> Result<(),RingError> {
-        let sql = "REPLACE INTO `access_request` (`entity_id`,`user_id`,`note`) VALUES (:entity_id,:user_id,:note)";
+        let sql = "INSERT INTO `access_request` (`entity_id`,`user_id`,`note`) VALUES (:entity_id,:user_id,:note) ON DUPLICATE KEY UPDATE `note`=:note";
         let mut conn = self.db_conn().await?;
         conn.exec_drop(sql, params!{entity_id,user_id,note}).await?;
         if self.use_cached {
-            let id = match conn.last_insert_id() {
-                Some(id) => id as usize,
-                None => return Err(RingError::String("Failed to create new access request".into())),
-            };
-            let request = ExternalAccessRequest {
-                id,
-                user_id,
-                entity_id,
-                note: note.to_owned(),
-            };
-            self.db_access_request.insert(id,request);
+            let sql = "SELECT `id`,`user_id`,`entity_id`,`note`,CAST(`created` AS CHAR),CAST(`escalated` AS CHAR) FROM `access_request` WHERE `entity_id`=:entity_id AND `user_id`=:user_id" ;
+            let request = conn.exec_iter(sql,params!{entity_id,user_id}).await?
+                .map_and_drop(|row| ExternalAccessRequest::from_row(&row) ).await?
+                .pop()
+                .ok_or_else(||RingError::String("Failed to create new access request".into()))?;
+            self.db_access_request.retain(|_id,ar| ar.user_id!=user_id || ar.entity_id!=entity_id);
+            self.db_access_request.insert(request.id,request);
         }
         Ok(())
     }
 
-    pub async fn set_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, rights: Vec<String>) -> Result<(),RingError> {
-        let existing_rights: Vec<(usize,String)> = self.get_user_rights_for_entities(user_id).await?
+    /// Sets the rights of a user on entities, removing all other rights.
+    /// Fails if that would leave an entity without an effective admin, unless `force` is set.
+    /// Returns (entity_id,right) pairs that are still waiting for approvals, see `add_access_rights`.
+    pub async fn set_access_rights(&mut self, user_id: usize, entity_ids: Vec<usize>, rights: Vec<String>, approver_id: Option<usize>, force: bool) -> Result<Vec<(usize,String)>,RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        let existing_rights: Vec<(usize,String)> = self.get_user_grants(user_id).await?
             .into_iter()
             .filter(|(entity_id,_right)| entity_ids.contains(entity_id))
             .collect();
@@ -134,22 +236,141 @@ impl DatabaseAbstractionLayer {
             .cloned()
             .collect();
         let new_rights: Vec<(usize,String)> = entity_ids.iter()
-            .map(|entity_id| rights.iter().map(|right|(*entity_id,right.to_owned())).collect::<Vec<(usize,String)>>() )
-            .flatten()
+            .flat_map(|entity_id| rights.iter().map(|right|(*entity_id,right.to_owned())).collect::<Vec<(usize,String)>>())
             .collect();
-        let add_rights: Vec<_> = new_rights.into_iter()
-            .filter(|x|!existing_rights.contains(x))
+        let (keep_rights,add_rights): (Vec<_>,Vec<_>) = new_rights.into_iter()
             .filter(|x|!remove_rights.contains(x)) // Paranoia
-            .collect();
+            .partition(|x|existing_rights.contains(x));
+        if !force {
+            let admin_removals: Vec<usize> = remove_rights.iter()
+                .filter(|(_entity_id,right)|right=="admin")
+                .map(|(entity_id,_right)|*entity_id)
+                .collect();
+            self.check_admin_lockout(user_id,&admin_removals).await?;
+        }
         for (entity_id,right) in &remove_rights {
             self.remove_right(user_id,*entity_id,right).await?;
         }
-        for (entity_id,right) in &add_rights {
-            self.add_right(user_id,*entity_id,right).await?;
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        Self::mark_as_manual_grants(&mut tx,user_id,&keep_rights).await?;
+        let pending = self.add_rights_with_approval(&mut tx,user_id,&add_rights,approver_id).await?;
+        tx.commit().await?;
+        self.reload_user_access(user_id).await?;
+        Ok(pending)
+    }
+
+    /// Checks that removing the admin right of a (principal) user on these entities leaves every one of them,
+    /// and all their descendants, with at least one effective admin (direct or inherited, not deactivated)
+    pub async fn check_admin_lockout(&self, user_id: usize, admin_removals: &[usize]) -> Result<(),RingError> {
+        for entity_id in self.get_entity_subtree(admin_removals).await? {
+            let ancestors = self.get_entity_ancestors(entity_id).await?;
+            let mut entity_ids = ancestors.to_owned();
+            entity_ids.push(entity_id);
+            let mut admin_grants: Vec<(usize,usize)> = self.get_all_direct_access_for_entities(&entity_ids).await?
+                .into_iter()
+                .filter(|(_admin_id,_entity_id,right)|right=="admin")
+                .map(|(admin_id,admin_entity_id,_right)|(admin_id,admin_entity_id))
+                .collect();
+            let mut admin_ids: Vec<usize> = admin_grants.iter().map(|(admin_id,_entity_id)|*admin_id).collect();
+            admin_ids.sort();
+            admin_ids.dedup();
+            let active_admin_ids = self.active_users(admin_ids).await?;
+            admin_grants.retain(|(admin_id,_entity_id)|active_admin_ids.contains(admin_id));
+            if !has_remaining_admin(entity_id,&ancestors,user_id,admin_removals,&admin_grants) {
+                return Err(RingError::Conflict(format!("This would leave entity #{entity_id} without an admin")));
+            }
         }
         Ok(())
     }
 
+    /// Adds new rights within a transaction, once they have enough approvals. The cache is not updated.
+    async fn add_rights_with_approval(&self, tx: &mut Transaction<'_>, user_id: usize, add_rights: &[(usize,String)], approver_id: Option<usize>) -> Result<Vec<(usize,String)>,RingError> {
+        let mut pending = vec![];
+        for (entity_id,right) in add_rights {
+            if let Some(approver_id) = approver_id {
+                if !self.approve_right(tx,approver_id,user_id,*entity_id,right).await? {
+                    pending.push((*entity_id,right.to_owned()));
+                    continue;
+                }
+            }
+            Self::add_right(tx,user_id,*entity_id,right).await?;
+        }
+        Ok(pending)
+    }
+
+    /// Returns the number of distinct admins that need to approve a new right on an entity
+    pub async fn get_required_approvals(&self, entity_id: usize) -> Result<usize,RingError> {
+        let sql = "SELECT `required_approvals` FROM `approval_policy` WHERE `entity_id`=:entity_id";
+        let ret: Option<usize> = self.db_conn().await?.exec_first(sql, params!{entity_id}).await?;
+        Ok(ret.unwrap_or(1).max(1))
+    }
+
+    /// Sets the required approvals of an entity, and discards pending approvals for lowering it
+    pub async fn set_required_approvals(&mut self, entity_id: usize, required_approvals: usize) -> Result<(),RingError> {
+        let mut conn = self.db_conn().await?;
+        let sql = "REPLACE INTO `approval_policy` (`entity_id`,`required_approvals`) VALUES (:entity_id,:required_approvals)";
+        conn.exec_drop(sql, params!{entity_id,required_approvals}).await?;
+        let sql = "DELETE FROM `approval_policy_change` WHERE `entity_id`=:entity_id";
+        conn.exec_drop(sql, params!{entity_id}).await?;
+        Ok(())
+    }
+
+    /// Records the approval of an admin for lowering the required approvals of an entity.
+    /// Returns true if as many current admins as the current policy requires have approved the new value.
+    pub async fn approve_policy_change(&mut self, approver_id: usize, entity_id: usize, required_approvals: usize) -> Result<bool,RingError> {
+        let current = self.get_required_approvals(entity_id).await?;
+        if required_approvals>=current {
+            return Ok(true);
+        }
+        let mut conn = self.db_conn().await?;
+        let sql = "INSERT IGNORE INTO `approval_policy_change` (`entity_id`,`required_approvals`,`approver_id`) VALUES (:entity_id,:required_approvals,:approver_id)";
+        conn.exec_drop(sql, params!{entity_id,required_approvals,approver_id}).await?;
+        let sql = "SELECT DISTINCT `approver_id` FROM `approval_policy_change` WHERE `entity_id`=:entity_id AND `required_approvals`=:required_approvals";
+        let approver_ids: Vec<usize> = conn.exec(sql, params!{entity_id,required_approvals}).await?;
+        Ok(self.count_current_admins(entity_id,&approver_ids).await?>=current)
+    }
+
+    /// Counts how many of these users are currently effective admins of an entity (direct or inherited, not deactivated)
+    async fn count_current_admins(&self, entity_id: usize, user_ids: &[usize]) -> Result<usize,RingError> {
+        let admin_ids: Vec<usize> = self.get_all_rights_for_entity(entity_id).await?
+            .into_iter()
+            .filter(|(_user_id,right)|right=="admin")
+            .map(|(user_id,_right)|user_id)
+            .collect();
+        Ok(user_ids.iter().filter(|user_id|admin_ids.contains(user_id)).count())
+    }
+
+    /// Returns all partial approvals for rights on the given entities
+    pub async fn get_access_approvals(&self, entity_ids: &[usize]) -> Result<Vec<AccessApproval>,RingError> {
+        if entity_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let entity_ids_str = entity_ids.iter().map(|s|format!("{s}")).collect::<Vec<String>>().join(",");
+        let sql = format!("SELECT `id`,`entity_id`,`user_id`,`right`,`approver_id`,CAST(`timestamp` AS CHAR) FROM `access_approval` WHERE `entity_id` IN ({entity_ids_str})");
+        Ok(self.db_conn().await?.exec_iter(sql,()).await?.map_and_drop(|row|AccessApproval::from_row(&row)).await?)
+    }
+
+    /// Records the approval of a right by an admin.
+    /// Returns true if enough distinct admins have approved the right for it to be added.
+    /// Approvals by users who are no longer admins of the entity do not count.
+    async fn approve_right(&self, tx: &mut Transaction<'_>, approver_id: usize, user_id: usize, entity_id: usize, right: &str) -> Result<bool,RingError> {
+        let required_approvals = self.get_required_approvals(entity_id).await?;
+        if required_approvals<=1 {
+            return Ok(true);
+        }
+        let sql = "INSERT IGNORE INTO `access_approval` (`entity_id`,`user_id`,`right`,`approver_id`) VALUES (:entity_id,:user_id,:right,:approver_id)";
+        tx.exec_drop(sql, params!{entity_id,user_id,right,approver_id}).await?;
+        let sql = "SELECT DISTINCT `approver_id` FROM `access_approval` WHERE `entity_id`=:entity_id AND `user_id`=:user_id AND `right`=:right";
+        let approver_ids: Vec<usize> = tx.exec(sql, params!{entity_id,user_id,right}).await?;
+        if self.count_current_admins(entity_id,&approver_ids).await?<required_approvals {
+            return Ok(false);
+        }
+        let sql = "DELETE FROM `access_approval` WHERE `entity_id`=:entity_id AND `user_id`=:user_id AND `right`=:right";
+        tx.exec_drop(sql, params!{entity_id,user_id,right}).await?;
+        Ok(true)
+    }
+
 
     /// Returns (user_id,entity_id,right)
     pub async fn get_all_direct_access_for_entities(&self, entity_ids: &[usize]) -> Result<Vec<(usize,usize,String)>,RingError> {
@@ -162,7 +383,22 @@ impl DatabaseAbstractionLayer {
 
 
 
-    pub async fn get_all_rights_for_entity(&self, entity_id: usize) -> Result<Vec<(usize,String)>,RingError> {
+    /// Returns the IDs of the given entities and all their children, grandchildren etc.
+    pub async fn get_entity_subtree(&self, entity_ids: &[usize]) -> Result<Vec<usize>,RingError> {
+        let mut ret = entity_ids.to_vec();
+        let mut todo = entity_ids.to_vec();
+        while !todo.is_empty() {
+            todo = self.load_entity_children(&todo).await?.iter().map(|(_parent,child)|*child).collect();
+            todo.retain(|entity_id|!ret.contains(entity_id));
+            todo.sort();
+            todo.dedup();
+            ret.append(&mut todo.clone());
+        }
+        Ok(ret)
+    }
+
+    /// Returns the IDs of all parents, grandparents etc. of an entity (not including the entity itself)
+    pub async fn get_entity_ancestors(&self, entity_id: usize) -> Result<Vec<usize>,RingError> {
         let mut all_parents = vec![entity_id];
         let mut todo = vec![entity_id];
         while !todo.is_empty() {
@@ -172,15 +408,38 @@ impl DatabaseAbstractionLayer {
             all_parents.sort();
             all_parents.dedup();
         }
+        all_parents.retain(|id|*id!=entity_id);
+        Ok(all_parents)
+    }
+
+    /// Returns (user_id,right) for all direct and inherited rights on an entity. Rights of deactivated users are left out.
+    pub async fn get_all_rights_for_entity(&self, entity_id: usize) -> Result<Vec<(usize,String)>,RingError> {
+        let mut all_parents = self.get_entity_ancestors(entity_id).await?;
+        all_parents.push(entity_id);
         let mut access: Vec<(usize,String)> = self.get_all_direct_access_for_entities(&all_parents).await?
             .into_iter()
             .map(|(user_id,_entity_id,right)|(user_id,right.to_string()))
             .collect();
         access.sort();
         access.dedup();
+        let mut user_ids: Vec<usize> = access.iter().map(|(user_id,_right)|*user_id).collect();
+        user_ids.dedup();
+        let active_user_ids = self.active_users(user_ids).await?;
+        access.retain(|(user_id,_right)|active_user_ids.contains(user_id));
         Ok(access)
     }
 
+    /// Returns the users that are not deactivated
+    pub async fn active_users(&self, user_ids: Vec<usize>) -> Result<Vec<usize>,RingError> {
+        let mut ret = vec![];
+        for user_id in user_ids {
+            if !self.is_user_deactivated(user_id).await? {
+                ret.push(user_id);
+            }
+        }
+        Ok(ret)
+    }
+
     pub async fn search_user_name(&self, query: &str) -> Result<Vec<usize>,RingError> {
         if self.use_cached {
             let query = query.to_lowercase();
@@ -216,9 +475,9 @@ impl DatabaseAbstractionLayer {
     // ________________ DB PRIVATE
 
     async fn get_user_db(&self, user_id: usize) -> Result<ExternalSystemUser,RingError> {
-        let sql = format!("SELECT `id`,`system`,`name`,`external_id`,`email`,`bespoke_data` FROM `user` WHERE `id`={user_id}");
+        let sql = format!("SELECT `id`,`system`,`name`,`external_id`,`email`,`bespoke_data`,`email_verified`,`principal_id`,`deactivated` FROM `user` WHERE `id`={user_id}");
         let res: Vec<ExternalSystemUser> = self.db_conn().await?.exec_iter(sql,()).await?.map_and_drop(|row|ExternalSystemUser::from_row(&row)).await?;
-        res.get(0).map(|x|x.to_owned()).ok_or_else(||RingError::String("No such user".into()))
+        res.first().map(|x|x.to_owned()).ok_or_else(||RingError::NotFound("No such user".into()))
     }
 
     async fn load_entities_db(&self, entity_ids: &[usize]) -> Result<EntityGroup,RingError> {
@@ -274,7 +533,7 @@ impl DatabaseAbstractionLayer {
     fn get_user_cached(&self, user_id: usize) -> Result<ExternalSystemUser,RingError> {
         match self.db_user.get(&user_id) {
             Some(user) => Ok(user.to_owned()),
-            None => Err(format!("No user with ID {user_id}").into()),
+            None => Err(RingError::NotFound(format!("No user with ID {user_id}"))),
         }
     }
 
@@ -282,7 +541,7 @@ impl DatabaseAbstractionLayer {
         let v: Vec<Entity> = entity_ids
             .iter()
             .filter_map(|id|self.db_entity.get(id))
-            .map(|e|Entity::from_table_entity(e))
+            .map(Entity::from_table_entity)
             .collect();
         Ok(EntityGroup::from_vec(v))
     }
@@ -343,35 +602,118 @@ impl DatabaseAbstractionLayer {
         Ok(())
     }
 
-    pub async fn add_user(&mut self, system: &str, external_id: &str, name: &str, email: &str, bespoke_data: &str) -> Result<Option<u64>,RingError> {
-        let sql = r#"INSERT INTO `user` (`system`,`external_id`,`name`,`bespoke_data`) 
-            VALUES (:system,:external_id,:name,:bespoke_data) 
-            ON DUPLICATE KEY UPDATE `bespoke_data`=:bespoke_data"# ;
+    pub async fn add_user(&mut self, system: &str, external_id: &str, name: &str, email: &str, email_verified: bool, bespoke_data: &str) -> Result<Option<u64>,RingError> {
+        let sql = r#"INSERT INTO `user` (`system`,`external_id`,`name`,`email`,`email_verified`,`bespoke_data`) 
+            VALUES (:system,:external_id,:name,:email,:email_verified,:bespoke_data) 
+            ON DUPLICATE KEY UPDATE `email`=:email,`email_verified`=:email_verified,`bespoke_data`=:bespoke_data"# ;
         let mut conn = self.db_conn().await?;
-        conn.exec_drop(sql, params!{system,external_id,name,bespoke_data}).await?;
+        conn.exec_drop(sql, params!{system,external_id,name,email,email_verified,bespoke_data}).await?;
         let user_id = conn.last_insert_id();
-        match user_id {
-            Some(user_id) => {
-                let user = ExternalSystemUser{
-                    id: Some(user_id),
-                    system: ExternalSystem::from_str(system),
-                    name: name.to_string(),
-                    external_id: external_id.to_string(),
-                    email: email.to_string(),
-                    bespoke_data: serde_json::from_str(&bespoke_data).unwrap_or(Value::Null)
-                };
-                self.db_user.insert(user_id as usize,user);
-            }
-            None => {},
+        if let Some(user_id) = user_id {
+            let user = ExternalSystemUser{
+                id: Some(user_id),
+                system: ExternalSystem::from_name(system),
+                name: name.to_string(),
+                external_id: external_id.to_string(),
+                email: email.to_string(),
+                email_verified,
+                bespoke_data: serde_json::from_str(bespoke_data).unwrap_or(Value::Null),
+                principal_id: self.db_user.get(&(user_id as usize)).and_then(|u|u.principal_id),
+                deactivated: self.db_user.get(&(user_id as usize)).map(|u|u.deactivated).unwrap_or(false),
+            };
+            self.db_user.insert(user_id as usize,user);
         }
         Ok(user_id)
 
     }  
 
+    /// Returns the ID of the principal user that a (possibly linked) user ID resolves to
+    pub async fn get_principal_id(&self, user_id: usize) -> Result<usize,RingError> {
+        Ok(self.get_user(user_id).await?.principal_id.unwrap_or(user_id))
+    }
+
+    /// Returns all identities of a principal user, including the principal itself
+    pub async fn get_linked_users(&self, principal_id: usize) -> Result<Vec<ExternalSystemUser>,RingError> {
+        if self.use_cached {
+            Ok(self.db_user.values()
+                .filter(|user|user.principal()==Some(principal_id))
+                .cloned()
+                .collect())
+        } else {
+            let sql = "SELECT `id`,`system`,`name`,`external_id`,`email`,`bespoke_data`,`email_verified`,`principal_id`,`deactivated` FROM `user` WHERE `id`=:principal_id OR `principal_id`=:principal_id";
+            Ok(self.db_conn().await?.exec_iter(sql,params!{principal_id}).await?.map_and_drop(|row|ExternalSystemUser::from_row(&row)).await?)
+        }
+    }
+
+    /// Makes `user_id` (and all identities linked to it) an identity of `principal_id`.
+    /// All rights, requests and approvals of `user_id` are moved to the principal, as is the superuser flag.
+    pub async fn link_users(&mut self, principal_id: usize, user_id: usize) -> Result<(),RingError> {
+        let principal_id = self.get_principal_id(principal_id).await?;
+        let user_id = self.get_principal_id(user_id).await?;
+        if principal_id==user_id {
+            return Err(RingError::Conflict("These users are already linked".into()));
+        }
+        let principal = self.get_user(principal_id).await?;
+        let user = self.get_user(user_id).await?;
+        // The client credentials of a service account would otherwise act with the rights of a person
+        if principal.is_service_account() || user.is_service_account() {
+            return Err(RingError::Validation("Service accounts can not be linked to other users".into()));
+        }
+        // Linking would otherwise silently reactivate or deactivate one of them
+        if principal.deactivated!=user.deactivated {
+            return Err(RingError::Conflict("Only users that are both active or both deactivated can be linked".into()));
+        }
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let params = params!{principal_id,user_id};
+        for sql in [
+            "UPDATE IGNORE `access` SET `user_id`=:principal_id WHERE `user_id`=:user_id",
+            "DELETE FROM `access` WHERE `user_id`=:user_id",
+            "UPDATE IGNORE `access_request` SET `user_id`=:principal_id WHERE `user_id`=:user_id",
+            "DELETE FROM `access_request_escalation` WHERE `request_id` IN (SELECT `id` FROM `access_request` WHERE `user_id`=:user_id)",
+            "DELETE FROM `access_request` WHERE `user_id`=:user_id",
+            "UPDATE IGNORE `access_approval` SET `user_id`=:principal_id WHERE `user_id`=:user_id",
+            "UPDATE IGNORE `access_approval` SET `approver_id`=:principal_id WHERE `approver_id`=:user_id",
+            "DELETE FROM `access_approval` WHERE `user_id`=:user_id OR `approver_id`=:user_id",
+            "UPDATE IGNORE `approval_policy_change` SET `approver_id`=:principal_id WHERE `approver_id`=:user_id",
+            "DELETE FROM `approval_policy_change` WHERE `approver_id`=:user_id",
+            "DELETE FROM `idp_consent` WHERE `user_id`=:user_id",
+            "UPDATE IGNORE `superuser` SET `user_id`=:principal_id WHERE `user_id`=:user_id",
+            "DELETE FROM `superuser` WHERE `user_id`=:user_id",
+            "UPDATE `user` SET `principal_id`=:principal_id WHERE `id`=:user_id OR `principal_id`=:user_id",
+        ] {
+            tx.exec_drop(sql, params.clone()).await?;
+        }
+        tx.commit().await?;
+        if self.use_cached {
+            self.init_from_db().await?;
+        }
+        Ok(())
+    }
+
+    /// Checks if a user (or rather, their principal user) is deactivated
+    pub async fn is_user_deactivated(&self, user_id: usize) -> Result<bool,RingError> {
+        let principal_id = self.get_principal_id(user_id).await?;
+        Ok(self.get_user(principal_id).await?.deactivated)
+    }
+
+    /// Deactivates or reactivates a user, including all their linked identities. Grants are not changed.
+    pub async fn set_user_deactivated(&mut self, user_id: usize, deactivated: bool) -> Result<(),RingError> {
+        let principal_id = self.get_principal_id(user_id).await?;
+        let sql = "UPDATE `user` SET `deactivated`=:deactivated WHERE `id`=:principal_id OR `principal_id`=:principal_id" ;
+        self.db_conn().await?.exec_drop(sql, params!{deactivated,principal_id}).await?;
+        if self.use_cached {
+            self.db_user.values_mut()
+                .filter(|user|user.principal()==Some(principal_id))
+                .for_each(|user|user.deactivated=deactivated);
+        }
+        Ok(())
+    }
+
     pub async fn get_entities_with_user_access(&self, user_id: usize, special_right: Option<String>) -> Result<EntityGroup,RingError> {
         let mut res: Vec<(usize,String)> = self.get_user_rights_for_entities(user_id).await?;
         if let Some(right) = special_right {
-            res = res.into_iter().filter(|(_id,r)|*r==right).collect()
+            res.retain(|(_id,r)|*r==right)
         }
         
         let mut entity_ids: Vec<usize> = res
@@ -406,16 +748,13 @@ impl DatabaseAbstractionLayer {
             for (parent_id,child_id) in parent_child.into_iter() {
                 let parent = entities.get(parent_id);
                 let new_entity = new_entities.get(child_id);
-                match (parent,new_entity) {
-                    (Some(parent),Some(new_entity)) => {
-                        let mut rights = parent.rights.clone();
-                        let child = entities.get_create_mut(new_entity.to_owned());
-                        child.rights.append(&mut rights);
-                        child.rights.sort();
-                        child.rights.dedup();
-                        last_ids.push(child.id);
-                    },
-                    _ => {},
+                if let (Some(parent),Some(new_entity)) = (parent,new_entity) {
+                    let mut rights = parent.rights.clone();
+                    let child = entities.get_create_mut(new_entity.to_owned());
+                    child.rights.append(&mut rights);
+                    child.rights.sort();
+                    child.rights.dedup();
+                    last_ids.push(child.id);
                 }
             }
 
@@ -437,7 +776,7 @@ impl DatabaseAbstractionLayer {
             .with_constraints(PoolConstraints::new(min_connections, max_connections).expect("pool_opts error"))
             .with_inactive_connection_ttl(Duration::from_secs(keep_sec));
         let wd_url = url;
-        let wd_opts = Opts::from_url(wd_url).expect(format!("Can not build options from db_wd URL {}",wd_url).as_str());
+        let wd_opts = Opts::from_url(wd_url).unwrap_or_else(|_| panic!("Can not build options from db_wd URL {}",wd_url));
         mysql_async::Pool::new(OptsBuilder::from_opts(wd_opts).pool_opts(pool_opts.clone()))
     }
 
@@ -456,10 +795,10 @@ impl DatabaseAbstractionLayer {
             .exec_iter("SELECT `id`,`name`,`external_id` FROM `entity`",()).await?
             .map_and_drop(|row| DbTableEntity::from_row(&row) ).await?.into_iter().map(|x|(x.id,x)).collect();
         self.db_user = conn
-            .exec_iter("SELECT `id`,`system`,`name`,`external_id`,`email`,`bespoke_data` FROM `user`",()).await?
+            .exec_iter("SELECT `id`,`system`,`name`,`external_id`,`email`,`bespoke_data`,`email_verified`,`principal_id`,`deactivated` FROM `user`",()).await?
             .map_and_drop(|row| ExternalSystemUser::from_row(&row) ).await?.into_iter().map(|x|(x.id.unwrap() as usize,x)).collect();
         self.db_access_request = conn
-            .exec_iter("SELECT `id`,`user_id`,`entity_id`,`note` FROM `access_request`",()).await?
+            .exec_iter("SELECT `id`,`user_id`,`entity_id`,`note`,CAST(`created` AS CHAR),CAST(`escalated` AS CHAR) FROM `access_request`",()).await?
             .map_and_drop(|row| ExternalAccessRequest::from_row(&row) ).await?.into_iter().map(|x|(x.id,x)).collect();
         Ok(())
     }
@@ -472,7 +811,7 @@ impl DatabaseAbstractionLayer {
                 .cloned()
                 .collect()
         } else {
-            let sql = "SELECT `id`,`user_id`,`entity_id`,`note` FROM `access_request` WHERE `entity_id`=:entity_id" ;
+            let sql = "SELECT `id`,`user_id`,`entity_id`,`note`,CAST(`created` AS CHAR),CAST(`escalated` AS CHAR) FROM `access_request` WHERE `entity_id`=:entity_id" ;
             self.db_conn().await?
                 .exec_iter(sql,params!{entity_id}).await?
                 .map_and_drop(|row| ExternalAccessRequest::from_row(&row) ).await?.into_iter().collect()
@@ -480,6 +819,50 @@ impl DatabaseAbstractionLayer {
         Ok(ret)
     }
 
+    /// Returns pending access requests on entities the user is a direct admin of,
+    /// and requests that were escalated to such entities from their descendants
+    pub async fn get_access_requests_for_admin(&self, user_id: usize) -> Result<Vec<ExternalAccessRequest>,RingError> {
+        let admin_entity_ids: Vec<usize> = self.get_user_rights_for_entities(user_id).await?
+            .into_iter()
+            .filter(|(_entity_id,right)|right=="admin")
+            .map(|(entity_id,_right)|entity_id)
+            .collect();
+        if admin_entity_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let entity_ids_str = admin_entity_ids.iter().map(|s|format!("{s}")).collect::<Vec<String>>().join(",");
+        let sql = format!("SELECT `id`,`user_id`,`entity_id`,`note`,CAST(`created` AS CHAR),CAST(`escalated` AS CHAR) FROM `access_request`
+            WHERE `entity_id` IN ({entity_ids_str})
+            OR `id` IN (SELECT `request_id` FROM `access_request_escalation` WHERE `ancestor_id` IN ({entity_ids_str}))");
+        Ok(self.db_conn().await?.exec_iter(sql,()).await?.map_and_drop(|row|ExternalAccessRequest::from_row(&row)).await?)
+    }
+
+    /// Returns access requests that have been pending for longer than `hours`, and have not been escalated yet
+    pub async fn get_access_requests_to_escalate(&self, hours: u64) -> Result<Vec<ExternalAccessRequest>,RingError> {
+        let sql = "SELECT `id`,`user_id`,`entity_id`,`note`,CAST(`created` AS CHAR),CAST(`escalated` AS CHAR) FROM `access_request`
+            WHERE `escalated` IS NULL AND `created` < NOW() - INTERVAL :hours HOUR" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{hours}).await?.map_and_drop(|row|ExternalAccessRequest::from_row(&row)).await?)
+    }
+
+    /// Records that an access request was escalated to the given ancestor entities
+    pub async fn escalate_access_request(&mut self, request_id: usize, ancestor_ids: &[usize]) -> Result<(),RingError> {
+        let mut conn = self.db_conn().await?;
+        let sql = "INSERT IGNORE INTO `access_request_escalation` (`request_id`,`ancestor_id`) VALUES (:request_id,:ancestor_id)" ;
+        for ancestor_id in ancestor_ids {
+            conn.exec_drop(sql, params!{request_id,ancestor_id}).await?;
+        }
+        let sql = "UPDATE `access_request` SET `escalated`=NOW() WHERE `id`=:request_id" ;
+        conn.exec_drop(sql, params!{request_id}).await?;
+        if self.use_cached {
+            let sql = "SELECT CAST(`escalated` AS CHAR) FROM `access_request` WHERE `id`=:request_id" ;
+            let escalated: Option<String> = conn.exec_first(sql, params!{request_id}).await?;
+            if let Some(request) = self.db_access_request.get_mut(&request_id) {
+                request.escalated = escalated;
+            }
+        }
+        Ok(())
+    }
+
     async fn remove_right(&mut self, user_id: usize, entity_id: usize, right: &str) -> Result<(),RingError> {
         // Delete from database
         let sql = "DELETE FROM `access` WHERE `user_id`=:user_id AND `entity_id`=:entity_id AND `right`=:right";
@@ -498,28 +881,35 @@ impl DatabaseAbstractionLayer {
         Ok(())
     }
 
-    async fn add_right(&mut self, user_id: usize, entity_id: usize, right: &str) -> Result<(),RingError> {
-        let mut conn = self.db_conn().await?;
-
-        // Add access
+    /// Adds a right within a transaction, and removes the matching access request. The cache is not updated.
+    async fn add_right(tx: &mut Transaction<'_>, user_id: usize, entity_id: usize, right: &str) -> Result<(),RingError> {
         let sql = "INSERT IGNORE INTO `access` (`user_id`,`entity_id`,`right`) VALUES (:user_id,:entity_id,:right)";
-        conn.exec_drop(sql, params!{user_id,entity_id,right}).await?;
-        let access_id_opt = conn.last_insert_id();
+        tx.exec_drop(sql, params!{user_id,entity_id,right}).await?;
+        let sql = "DELETE FROM `access_request_escalation` WHERE `request_id` IN (SELECT `id` FROM `access_request` WHERE `user_id`=:user_id AND `entity_id`=:entity_id)" ;
+        tx.exec_drop(sql, params!{user_id,entity_id}).await?;
+        let sql = "DELETE FROM `access_request` WHERE `user_id`=:user_id AND `entity_id`=:entity_id" ;
+        tx.exec_drop(sql, params!{user_id,entity_id}).await?;
+        Ok(())
+    }
 
-        // Remove request, if exists
+    /// Removes a pending access request without granting anything
+    pub async fn deny_access_requests(&mut self, user_id: usize, entity_ids: Vec<usize>) -> Result<(),RingError> {
+        for entity_id in entity_ids {
+            self.remove_access_request(user_id,entity_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes an access request, and its escalations
+    async fn remove_access_request(&mut self, user_id: usize, entity_id: usize) -> Result<(),RingError> {
+        let mut conn = self.db_conn().await?;
+        let sql = "DELETE FROM `access_request_escalation` WHERE `request_id` IN (SELECT `id` FROM `access_request` WHERE `user_id`=:user_id AND `entity_id`=:entity_id)" ;
+        conn.exec_drop(sql, params!{user_id,entity_id}).await?;
         let sql = "DELETE FROM `access_request` WHERE `user_id`=:user_id AND `entity_id`=:entity_id" ;
         conn.exec_drop(sql, params!{user_id,entity_id}).await?;
-
         if self.use_cached {
-            // Add to cache
-            if let Some(id) = access_id_opt {
-                let id = id as usize;
-                self.db_access.insert(id,DbTableAccess{ id: id, user_id, entity_id, right: right.to_string() });
-            }
-            // Remove request from cache
             self.db_access_request.retain(|_id,ar| ar.user_id!=user_id || ar.entity_id!=entity_id);
         }
-
         Ok(())
     }
 
@@ -545,13 +935,491 @@ impl DatabaseAbstractionLayer {
         if self.use_cached {
             if let Some(id) = conn.last_insert_id() {
                 let id = id as usize;
-                self.db_connection.insert(id,DbTableConnection { id: id, parent_id, child_id});
+                self.db_connection.insert(id,DbTableConnection { id, parent_id, child_id});
             }
         }        
 
         Ok(child_id)
     }
 
+    /// Returns (entity_id,right,org) of all rights a user holds via a GitHub organisation
+    pub async fn get_github_org_grants(&self, user_id: usize) -> Result<Vec<(usize,String,String)>,RingError> {
+        let sql = "SELECT `entity_id`,`right`,`github_org` FROM `access` WHERE `user_id`=:user_id AND `github_org` IS NOT NULL" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{user_id}).await?.map_and_drop(from_row::<(usize,String,String)>).await?)
+    }
+
+    /// Adds a right on behalf of a GitHub organisation in `systems.github.org_grants`. Returns false if the user already had that right.
+    pub async fn add_github_org_right(&mut self, user_id: usize, entity_id: usize, right: &str, org: &str) -> Result<bool,RingError> {
+        let sql = "INSERT IGNORE INTO `access` (`user_id`,`entity_id`,`right`,`github_org`) VALUES (:user_id,:entity_id,:right,:org)";
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{user_id,entity_id,right,org}).await?;
+        if conn.affected_rows()==0 {
+            return Ok(false);
+        }
+        if self.use_cached {
+            if let Some(id) = conn.last_insert_id() {
+                let id = id as usize;
+                self.db_access.insert(id,DbTableAccess{ id, user_id, entity_id, right: right.to_string() });
+            }
+        }
+        self.remove_access_request(user_id,entity_id).await?;
+        Ok(true)
+    }
+
+    /// Removes a right that was added for a GitHub organisation; manually granted rights are kept
+    pub async fn remove_github_org_right(&mut self, user_id: usize, entity_id: usize, right: &str, org: &str) -> Result<(),RingError> {
+        let sql = "DELETE FROM `access` WHERE `user_id`=:user_id AND `entity_id`=:entity_id AND `right`=:right AND `github_org`=:org";
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{user_id,entity_id,right,org}).await?;
+        if self.use_cached && conn.affected_rows()>0 {
+            self.db_access.retain(|_id,entry| entry.user_id!=user_id || entry.entity_id!=entity_id || entry.right!=right);
+        }
+        Ok(())
+    }
+
+    /// Adds a parent to an entity. Fails if either entity does not exist, or if that would create a cycle.
+    pub async fn add_entity_parent(&mut self, entity_id: usize, parent_id: usize) -> Result<(),RingError> {
+        if entity_id==parent_id {
+            return Err(RingError::Validation(format!("Entity #{entity_id} can not be its own parent")));
+        }
+        let entities = self.load_entities(&[entity_id,parent_id]).await?;
+        if let Some(id) = [entity_id,parent_id].into_iter().find(|id|entities.get(*id).is_none()) {
+            return Err(RingError::NotFound(format!("No entity with ID {id}")));
+        }
+        if self.get_entity_ancestors(parent_id).await?.contains(&entity_id) {
+            return Err(RingError::Conflict(format!("Entity #{entity_id} is an ancestor of #{parent_id}")));
+        }
+        if self.load_entity_parents(&[entity_id]).await?.iter().any(|(parent,_child)|*parent==parent_id) {
+            return Ok(());
+        }
+        let sql = "INSERT INTO `connection` (`parent_id`,`child_id`) VALUES (:parent_id,:child_id)";
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{parent_id,"child_id" => entity_id}).await?;
+
+        // Add to cache
+        if self.use_cached {
+            if let Some(id) = conn.last_insert_id() {
+                let id = id as usize;
+                self.db_connection.insert(id,DbTableConnection { id, parent_id, child_id: entity_id });
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a parent from an entity. Returns false if there was no such connection.
+    pub async fn remove_entity_parent(&mut self, entity_id: usize, parent_id: usize) -> Result<bool,RingError> {
+        let sql = "DELETE FROM `connection` WHERE `parent_id`=:parent_id AND `child_id`=:child_id";
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{parent_id,"child_id" => entity_id}).await?;
+        if self.use_cached {
+            self.db_connection.retain(|_id,c| c.parent_id!=parent_id || c.child_id!=entity_id);
+        }
+        Ok(conn.affected_rows()>0)
+    }
+
+    /// Checks the superuser flag of a (principal) user. See also `AppState::is_superuser`.
+    pub async fn has_superuser_flag(&self, user_id: usize) -> Result<bool,RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        let sql = "SELECT `user_id` FROM `superuser` WHERE `user_id`=:user_id" ;
+        Ok(self.db_conn().await?.exec_first::<usize,_,_>(sql,params!{user_id}).await?.is_some())
+    }
+
+    pub async fn set_superuser_flag(&mut self, user_id: usize, is_superuser: bool, granted_by: usize) -> Result<(),RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        let sql = match is_superuser {
+            true => "INSERT IGNORE INTO `superuser` (`user_id`,`granted_by`) VALUES (:user_id,:granted_by)",
+            false => "DELETE FROM `superuser` WHERE `user_id`=:user_id",
+        };
+        self.db_conn().await?.exec_drop(sql, params!{user_id,granted_by}).await?;
+        Ok(())
+    }
+
+    /// Returns the IDs of all users with the superuser flag (not those from the config file)
+    pub async fn get_superuser_ids(&self) -> Result<Vec<usize>,RingError> {
+        let sql = "SELECT `user_id` FROM `superuser` ORDER BY `user_id`" ;
+        Ok(self.db_conn().await?.exec_iter(sql,()).await?.map_and_drop(from_row::<usize>).await?)
+    }
+
+    pub async fn log_superuser_action(&self, user_id: usize, action: &str, details: &str) -> Result<(),RingError> {
+        let sql = "INSERT INTO `superuser_log` (`user_id`,`action`,`details`) VALUES (:user_id,:action,:details)" ;
+        self.db_conn().await?.exec_drop(sql, params!{user_id,action,details}).await?;
+        Ok(())
+    }
+
+    /// Returns the latest superuser actions, newest first
+    pub async fn get_superuser_log(&self, limit: usize) -> Result<Vec<SuperuserAction>,RingError> {
+        let sql = "SELECT `id`,`user_id`,`action`,`details`,CAST(`created` AS CHAR) FROM `superuser_log` ORDER BY `id` DESC LIMIT :limit" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{limit}).await?.map_and_drop(|row|SuperuserAction::from_row(&row)).await?)
+    }
+
+    /// Stores a new API token hash for a user, valid for `valid_sec` seconds. Returns the token ID.
+    pub async fn add_api_token(&mut self, user_id: usize, name: &str, token_hash: &str, rights: &[String], entity_ids: &[usize], valid_sec: u64) -> Result<usize,RingError> {
+        let rights = rights.join(",");
+        let entity_ids = entity_ids.iter().map(|id|id.to_string()).collect::<Vec<String>>().join(",");
+        let sql = "INSERT INTO `api_token` (`user_id`,`name`,`token_hash`,`rights`,`entity_ids`,`expires`) VALUES (:user_id,:name,:token_hash,:rights,:entity_ids,NOW() + INTERVAL :valid_sec SECOND)" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{user_id,name,token_hash,rights,entity_ids,valid_sec}).await?;
+        conn.last_insert_id()
+            .map(|id|id as usize)
+            .ok_or_else(||RingError::String("Failed to create API token".into()))
+    }
+
+    /// Returns all API tokens of a user, including expired ones
+    pub async fn get_api_tokens(&self, user_id: usize) -> Result<Vec<ApiToken>,RingError> {
+        let sql = "SELECT `id`,`user_id`,`name`,`rights`,`entity_ids`,CAST(`created` AS CHAR),CAST(`expires` AS CHAR),CAST(`last_used` AS CHAR) FROM `api_token` WHERE `user_id`=:user_id ORDER BY `id`" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{user_id}).await?.map_and_drop(|row|ApiToken::from_row(&row)).await?)
+    }
+
+    /// Returns the unexpired API token with that hash, and records its use.
+    /// `last_used` is only written if it is older than `Self::API_TOKEN_LAST_USED_SEC`, to avoid a write on every request.
+    pub async fn get_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>,RingError> {
+        self.find_api_token(token_hash,true).await
+    }
+
+    /// Returns the unexpired API token with that hash, without recording its use (for introspection by a third party)
+    pub async fn peek_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>,RingError> {
+        self.find_api_token(token_hash,false).await
+    }
+
+    async fn find_api_token(&self, token_hash: &str, record_use: bool) -> Result<Option<ApiToken>,RingError> {
+        let sql = "SELECT `id`,`user_id`,`name`,`rights`,`entity_ids`,CAST(`created` AS CHAR),CAST(`expires` AS CHAR),CAST(`last_used` AS CHAR),
+            (`last_used` IS NULL OR `last_used`<NOW() - INTERVAL :interval SECOND) AS `stale`
+            FROM `api_token` WHERE `token_hash`=:token_hash AND `expires`>NOW()" ;
+        let mut conn = self.db_conn().await?;
+        let token = conn.exec_iter(sql,params!{token_hash,"interval" => Self::API_TOKEN_LAST_USED_SEC}).await?
+            .map_and_drop(|row|(ApiToken::from_row(&row),row.get::<bool,_>(8).unwrap_or(true))).await?
+            .pop();
+        let (token,stale) = match token {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        if stale && record_use {
+            let sql = "UPDATE `api_token` SET `last_used`=NOW() WHERE `id`=:id" ;
+            conn.exec_drop(sql, params!{"id" => token.id}).await?;
+        }
+        Ok(Some(token))
+    }
+
+    /// Deletes an API token of a user. Returns false if there was no such token.
+    pub async fn revoke_api_token(&mut self, user_id: usize, token_id: usize) -> Result<bool,RingError> {
+        let sql = "DELETE FROM `api_token` WHERE `id`=:token_id AND `user_id`=:user_id" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{token_id,user_id}).await?;
+        Ok(conn.affected_rows()>0)
+    }
+
+    /// Deletes expired API tokens. Returns the number of deleted tokens.
+    pub async fn purge_expired_api_tokens(&self) -> Result<u64,RingError> {
+        let sql = "DELETE FROM `api_token` WHERE `expires`<=NOW()" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, ()).await?;
+        Ok(conn.affected_rows())
+    }
+
+    /// Creates a service account owned by an entity, in one transaction. Returns the new user ID.
+    pub async fn add_service_account(&mut self, entity_id: usize, name: &str, client_id: &str, secret_hash: &str, created_by: usize) -> Result<usize,RingError> {
+        let system = ExternalSystem::SERVICE.as_str();
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let sql = "INSERT INTO `user` (`system`,`external_id`,`name`,`email`,`email_verified`,`bespoke_data`) VALUES (:system,:client_id,:name,'',0,'null')" ;
+        tx.exec_drop(sql, params!{system,client_id,name}).await?;
+        let user_id = tx.last_insert_id()
+            .ok_or_else(||RingError::String("Failed to create service account".into()))? as usize;
+        let sql = "INSERT INTO `service_account` (`user_id`,`entity_id`,`secret_hash`,`created_by`) VALUES (:user_id,:entity_id,:secret_hash,:created_by)" ;
+        tx.exec_drop(sql, params!{user_id,entity_id,secret_hash,created_by}).await?;
+        tx.commit().await?;
+        if self.use_cached {
+            let user = ExternalSystemUser {
+                id: Some(user_id as u64),
+                system: ExternalSystem::SERVICE,
+                name: name.to_string(),
+                external_id: client_id.to_string(),
+                email: String::new(),
+                email_verified: false,
+                bespoke_data: Value::Null,
+                principal_id: None,
+                deactivated: false,
+            };
+            self.db_user.insert(user_id,user);
+        }
+        Ok(user_id)
+    }
+
+    /// Returns the user IDs of all service accounts owned by an entity
+    pub async fn get_service_accounts(&self, entity_id: usize) -> Result<Vec<usize>,RingError> {
+        let sql = "SELECT `user_id` FROM `service_account` WHERE `entity_id`=:entity_id" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{entity_id}).await?.map_and_drop(from_row::<usize>).await?)
+    }
+
+    /// Returns the ID of the entity owning a service account, or None if the user is not a service account
+    pub async fn get_service_account_entity(&self, user_id: usize) -> Result<Option<usize>,RingError> {
+        let sql = "SELECT `entity_id` FROM `service_account` WHERE `user_id`=:user_id" ;
+        Ok(self.db_conn().await?.exec_first(sql,params!{user_id}).await?)
+    }
+
+    /// Replaces the client secret of a service account, and revokes the API tokens issued with the old one
+    pub async fn set_service_account_secret(&mut self, user_id: usize, secret_hash: &str) -> Result<(),RingError> {
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let sql = "UPDATE `service_account` SET `secret_hash`=:secret_hash WHERE `user_id`=:user_id" ;
+        tx.exec_drop(sql, params!{user_id,secret_hash}).await?;
+        let sql = "DELETE FROM `api_token` WHERE `user_id`=:user_id" ;
+        tx.exec_drop(sql, params!{user_id}).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Deactivates or reactivates a service account. Deactivation also revokes its API tokens.
+    pub async fn set_service_account_deactivated(&mut self, user_id: usize, deactivated: bool) -> Result<(),RingError> {
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let sql = "UPDATE `user` SET `deactivated`=:deactivated WHERE `id`=:user_id" ;
+        tx.exec_drop(sql, params!{user_id,deactivated}).await?;
+        if deactivated {
+            let sql = "DELETE FROM `api_token` WHERE `user_id`=:user_id" ;
+            tx.exec_drop(sql, params!{user_id}).await?;
+        }
+        tx.commit().await?;
+        if let (true,Some(user)) = (self.use_cached,self.db_user.get_mut(&user_id)) {
+            user.deactivated = deactivated;
+        }
+        Ok(())
+    }
+
+    /// Deletes a service account with its rights, requests and API tokens, in one transaction.
+    /// Fails if that would leave an entity without an effective admin.
+    pub async fn remove_service_account(&mut self, user_id: usize) -> Result<(),RingError> {
+        let admin_removals: Vec<usize> = self.get_user_grants(user_id).await?
+            .into_iter()
+            .filter(|(_entity_id,right)|right=="admin")
+            .map(|(entity_id,_right)|entity_id)
+            .collect();
+        self.check_admin_lockout(user_id,&admin_removals).await?;
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        for sql in [
+            "DELETE FROM `access` WHERE `user_id`=:user_id",
+            "DELETE FROM `access_approval` WHERE `user_id`=:user_id OR `approver_id`=:user_id",
+            "DELETE FROM `approval_policy_change` WHERE `approver_id`=:user_id",
+            "DELETE FROM `access_request_escalation` WHERE `request_id` IN (SELECT `id` FROM `access_request` WHERE `user_id`=:user_id)",
+            "DELETE FROM `access_request` WHERE `user_id`=:user_id",
+            "DELETE FROM `api_token` WHERE `user_id`=:user_id",
+            "DELETE FROM `service_account` WHERE `user_id`=:user_id",
+            "DELETE FROM `user` WHERE `id`=:user_id",
+        ] {
+            tx.exec_drop(sql, params!{user_id}).await?;
+        }
+        tx.commit().await?;
+        if self.use_cached {
+            self.db_access.retain(|_id,entry| entry.user_id!=user_id);
+            self.db_access_request.retain(|_id,request| request.user_id!=user_id);
+            self.db_user.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    /// Returns the user ID of the service account with these client credentials, if any
+    pub async fn authenticate_service_account(&self, client_id: &str, secret_hash: &str) -> Result<Option<usize>,RingError> {
+        let system = ExternalSystem::SERVICE.as_str();
+        let sql = "SELECT `user`.`id` FROM `user`,`service_account` WHERE `user`.`id`=`service_account`.`user_id` AND `user`.`system`=:system AND `user`.`external_id`=:client_id AND `secret_hash`=:secret_hash" ;
+        Ok(self.db_conn().await?.exec_first(sql,params!{system,client_id,secret_hash}).await?)
+    }
+
+    /// Returns the (space-separated) scopes a user has consented to for an IdP client
+    pub async fn get_idp_consent(&self, user_id: usize, client_id: &str) -> Result<String,RingError> {
+        let sql = "SELECT `scope` FROM `idp_consent` WHERE `user_id`=:user_id AND `client_id`=:client_id" ;
+        let scope: Option<String> = self.db_conn().await?.exec_first(sql,params!{user_id,client_id}).await?;
+        Ok(scope.unwrap_or_default())
+    }
+
+    /// Sets the (space-separated) scopes a user has consented to for an IdP client
+    pub async fn set_idp_consent(&mut self, user_id: usize, client_id: &str, scope: &str) -> Result<(),RingError> {
+        let sql = "INSERT INTO `idp_consent` (`user_id`,`client_id`,`scope`) VALUES (:user_id,:client_id,:scope) ON DUPLICATE KEY UPDATE `scope`=:scope" ;
+        self.db_conn().await?.exec_drop(sql, params!{user_id,client_id,scope}).await?;
+        Ok(())
+    }
+
+    /// Creates an invitation, valid for `valid_sec` seconds. Returns the invitation ID.
+    pub async fn add_invitation(&mut self, kind: &str, target: &str, entity_ids: &[usize], rights: &[String], created_by: usize, valid_sec: u64) -> Result<usize,RingError> {
+        let entity_ids = entity_ids.iter().map(|id|id.to_string()).collect::<Vec<String>>().join(",");
+        let rights = rights.join(",");
+        let sql = "INSERT INTO `invitation` (`kind`,`target`,`entity_ids`,`rights`,`created_by`,`expires`) VALUES (:kind,:target,:entity_ids,:rights,:created_by,NOW() + INTERVAL :valid_sec SECOND)" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{kind,target,entity_ids,rights,created_by,valid_sec}).await?;
+        conn.last_insert_id()
+            .map(|id|id as usize)
+            .ok_or_else(||RingError::String("Failed to create invitation".into()))
+    }
+
+    pub async fn get_invitation(&self, invitation_id: usize) -> Result<Option<Invitation>,RingError> {
+        let sql = format!("{} WHERE `id`=:invitation_id",Self::INVITATION_SELECT);
+        Ok(self.db_conn().await?.exec_iter(sql,params!{invitation_id}).await?.map_and_drop(|row|Invitation::from_row(&row)).await?.pop())
+    }
+
+    /// Returns the open invitations that include an entity
+    pub async fn get_entity_invitations(&self, entity_id: usize) -> Result<Vec<Invitation>,RingError> {
+        let sql = format!("{} WHERE FIND_IN_SET(:entity_id,`entity_ids`) AND `accepted_by` IS NULL AND `expires`>NOW() ORDER BY `id`",Self::INVITATION_SELECT);
+        Ok(self.db_conn().await?.exec_iter(sql,params!{entity_id}).await?.map_and_drop(|row|Invitation::from_row(&row)).await?)
+    }
+
+    /// Returns open ORCID and email invitations for an ORCID iD or email address (either can be empty)
+    pub async fn get_matching_invitations(&self, orcid_id: &str, email: &str) -> Result<Vec<Invitation>,RingError> {
+        let sql = format!("{} WHERE `accepted_by` IS NULL AND `expires`>NOW() AND ((`kind`=:kind_orcid AND `target`=:orcid_id AND :orcid_id!='') OR (`kind`=:kind_email AND `target`=:email AND :email!=''))",Self::INVITATION_SELECT);
+        let params = params!{orcid_id,email,"kind_orcid" => KIND_ORCID,"kind_email" => KIND_EMAIL};
+        Ok(self.db_conn().await?.exec_iter(sql,params).await?.map_and_drop(|row|Invitation::from_row(&row)).await?)
+    }
+
+    /// Returns the open invitation for an invite link token hash
+    pub async fn get_link_invitation(&self, token_hash: &str) -> Result<Option<Invitation>,RingError> {
+        let sql = format!("{} WHERE `kind`=:kind AND `target`=:token_hash AND `accepted_by` IS NULL AND `expires`>NOW()",Self::INVITATION_SELECT);
+        Ok(self.db_conn().await?.exec_iter(sql,params!{token_hash,"kind" => KIND_LINK}).await?.map_and_drop(|row|Invitation::from_row(&row)).await?.pop())
+    }
+
+    /// Marks an invitation as accepted by a user, and grants its rights with the inviter as approver, in one transaction.
+    /// Returns the (entity_id,right) pairs still waiting for approvals, or None if it was already accepted (by anyone).
+    pub async fn accept_invitation(&mut self, invitation: &Invitation, user_id: usize) -> Result<Option<Vec<(usize,String)>>,RingError> {
+        let principal_id = self.get_principal_id(user_id).await?;
+        let mut conn = self.db_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let sql = "UPDATE `invitation` SET `accepted_by`=:user_id,`accepted`=NOW() WHERE `id`=:invitation_id AND `accepted_by` IS NULL" ;
+        tx.exec_drop(sql, params!{"invitation_id" => invitation.id,user_id}).await?;
+        if tx.affected_rows()==0 {
+            return Ok(None); // Rolled back on drop
+        }
+        let requested_rights: Vec<(usize,String)> = invitation.entity_ids.iter()
+            .flat_map(|entity_id| invitation.rights.iter().map(|right|(*entity_id,right.to_owned())).collect::<Vec<(usize,String)>>())
+            .collect();
+        let pending = self.add_access_right_pairs_in(&mut tx,principal_id,requested_rights,Some(invitation.created_by)).await?;
+        tx.commit().await?;
+        self.reload_user_access(principal_id).await?;
+        Ok(Some(pending))
+    }
+
+    /// Deletes an open invitation. Returns false if there was no such invitation.
+    pub async fn revoke_invitation(&mut self, invitation_id: usize) -> Result<bool,RingError> {
+        let sql = "DELETE FROM `invitation` WHERE `id`=:invitation_id AND `accepted_by` IS NULL" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{invitation_id}).await?;
+        Ok(conn.affected_rows()>0)
+    }
+
+    /// Creates an auto-grant rule. Returns the rule ID.
+    pub async fn add_auto_grant_rule(&mut self, entity_id: usize, kind: &str, field: &str, value: &str, rights: &[String], created_by: usize) -> Result<usize,RingError> {
+        let rights = rights.join(",");
+        let sql = "INSERT INTO `auto_grant_rule` (`entity_id`,`kind`,`field`,`value`,`rights`,`created_by`) VALUES (:entity_id,:kind,:field,:value,:rights,:created_by)" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{entity_id,kind,field,value,rights,created_by}).await?;
+        conn.last_insert_id()
+            .map(|id|id as usize)
+            .ok_or_else(||RingError::String("Failed to create auto-grant rule".into()))
+    }
+
+    pub async fn get_auto_grant_rule(&self, rule_id: usize) -> Result<Option<AutoGrantRule>,RingError> {
+        let sql = format!("{} WHERE `id`=:rule_id",Self::AUTO_GRANT_RULE_SELECT);
+        Ok(self.db_conn().await?.exec_iter(sql,params!{rule_id}).await?.map_and_drop(|row|AutoGrantRule::from_row(&row)).await?.pop())
+    }
+
+    pub async fn get_auto_grant_rules(&self) -> Result<Vec<AutoGrantRule>,RingError> {
+        let sql = format!("{} ORDER BY `id`",Self::AUTO_GRANT_RULE_SELECT);
+        Ok(self.db_conn().await?.exec_iter(sql,()).await?.map_and_drop(|row|AutoGrantRule::from_row(&row)).await?)
+    }
+
+    pub async fn get_entity_auto_grant_rules(&self, entity_id: usize) -> Result<Vec<AutoGrantRule>,RingError> {
+        let sql = format!("{} WHERE `entity_id`=:entity_id ORDER BY `id`",Self::AUTO_GRANT_RULE_SELECT);
+        Ok(self.db_conn().await?.exec_iter(sql,params!{entity_id}).await?.map_and_drop(|row|AutoGrantRule::from_row(&row)).await?)
+    }
+
+    /// Deletes an auto-grant rule. Its grants stay until the users are re-evaluated, see `auto_grant::apply_to_user`.
+    pub async fn remove_auto_grant_rule(&mut self, rule_id: usize) -> Result<bool,RingError> {
+        let sql = "DELETE FROM `auto_grant_rule` WHERE `id`=:rule_id" ;
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{rule_id}).await?;
+        Ok(conn.affected_rows()>0)
+    }
+
+    /// Returns the rule-based rights of a (principal) user, as (entity_id,right,rule_id)
+    pub async fn get_auto_grants(&self, user_id: usize) -> Result<Vec<(usize,String,usize)>,RingError> {
+        let sql = "SELECT `entity_id`,`right`,`auto_grant_rule_id` FROM `access` WHERE `user_id`=:user_id AND `auto_grant_rule_id` IS NOT NULL" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{user_id}).await?.map_and_drop(from_row::<(usize,String,usize)>).await?)
+    }
+
+    /// Returns the users that hold rights from an auto-grant rule, or from any rule
+    pub async fn get_users_with_auto_grants(&self, rule_id: Option<usize>) -> Result<Vec<usize>,RingError> {
+        let sql = "SELECT DISTINCT `user_id` FROM `access` WHERE `auto_grant_rule_id`=:rule_id OR (:rule_id IS NULL AND `auto_grant_rule_id` IS NOT NULL)" ;
+        Ok(self.db_conn().await?.exec_iter(sql,params!{rule_id}).await?.map_and_drop(from_row::<usize>).await?)
+    }
+
+    /// Returns the IDs of all active principal users, excluding service accounts
+    pub async fn get_principal_user_ids(&self) -> Result<Vec<usize>,RingError> {
+        if self.use_cached {
+            Ok(self.db_user.iter()
+                .filter(|(_id,user)|user.principal_id.is_none() && !user.deactivated && !user.is_service_account())
+                .map(|(id,_user)|*id)
+                .collect())
+        } else {
+            let sql = "SELECT `id` FROM `user` WHERE `principal_id` IS NULL AND `deactivated`=0 AND `system`!=:system" ;
+            Ok(self.db_conn().await?.exec_iter(sql,params!{"system" => ExternalSystem::SERVICE.as_str()}).await?.map_and_drop(from_row::<usize>).await?)
+        }
+    }
+
+    /// Adds a right on behalf of an auto-grant rule. Returns false if the user already had that right.
+    pub async fn add_auto_granted_right(&mut self, user_id: usize, entity_id: usize, right: &str, rule_id: usize) -> Result<bool,RingError> {
+        let sql = "INSERT IGNORE INTO `access` (`user_id`,`entity_id`,`right`,`auto_grant_rule_id`) VALUES (:user_id,:entity_id,:right,:rule_id)";
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{user_id,entity_id,right,rule_id}).await?;
+        if conn.affected_rows()==0 {
+            return Ok(false);
+        }
+        if self.use_cached {
+            if let Some(id) = conn.last_insert_id() {
+                let id = id as usize;
+                self.db_access.insert(id,DbTableAccess{ id, user_id, entity_id, right: right.to_string() });
+            }
+        }
+        self.remove_access_request(user_id,entity_id).await?;
+        Ok(true)
+    }
+
+    /// Removes a right that was added by an auto-grant rule; manually granted rights are kept
+    pub async fn remove_auto_granted_right(&mut self, user_id: usize, entity_id: usize, right: &str, rule_id: usize) -> Result<(),RingError> {
+        let sql = "DELETE FROM `access` WHERE `user_id`=:user_id AND `entity_id`=:entity_id AND `right`=:right AND `auto_grant_rule_id`=:rule_id";
+        let mut conn = self.db_conn().await?;
+        conn.exec_drop(sql, params!{user_id,entity_id,right,rule_id}).await?;
+        if self.use_cached && conn.affected_rows()>0 {
+            self.db_access.retain(|_id,entry| entry.user_id!=user_id || entry.entity_id!=entity_id || entry.right!=right);
+        }
+        Ok(())
+    }
+
+    /// Stores a one-time IdP authorization code hash, valid for `valid_sec` seconds
+    pub async fn add_idp_code(&mut self, code_hash: &str, code: &IdpCode, valid_sec: u64) -> Result<(),RingError> {
+        let sql = "INSERT INTO `idp_code` (`code_hash`,`client_id`,`user_id`,`redirect_uri`,`scope`,`nonce`,`code_challenge`,`expires`) VALUES (:code_hash,:client_id,:user_id,:redirect_uri,:scope,:nonce,:code_challenge,NOW() + INTERVAL :valid_sec SECOND)" ;
+        self.db_conn().await?.exec_drop(sql, params!{
+            code_hash,
+            "client_id" => &code.client_id,
+            "user_id" => code.user_id,
+            "redirect_uri" => &code.redirect_uri,
+            "scope" => &code.scope,
+            "nonce" => &code.nonce,
+            "code_challenge" => &code.code_challenge,
+            valid_sec,
+        }).await?;
+        Ok(())
+    }
+
+    /// Returns the unexpired IdP authorization code with that hash, and deletes it, so it can only be used once.
+    /// Expired codes are removed as well.
+    pub async fn take_idp_code(&mut self, code_hash: &str) -> Result<Option<IdpCode>,RingError> {
+        let sql = "SELECT `client_id`,`user_id`,`redirect_uri`,`scope`,`nonce`,`code_challenge` FROM `idp_code` WHERE `code_hash`=:code_hash AND `expires`>NOW()" ;
+        let mut conn = self.db_conn().await?;
+        let code = conn.exec_iter(sql,params!{code_hash}).await?.map_and_drop(|row|IdpCode::from_row(&row)).await?.pop();
+        let sql = "DELETE FROM `idp_code` WHERE `code_hash`=:code_hash" ;
+        conn.exec_drop(sql, params!{code_hash}).await?;
+        let redeemed = conn.affected_rows()>0; // Only one concurrent request can delete the code
+        conn.query_drop("DELETE FROM `idp_code` WHERE `expires`<NOW()").await?;
+        Ok(code.filter(|_|redeemed))
+    }
+
     /// Returns Vec<(parent,child)>
     async fn load_entity_parents(&self, entity_ids: &[usize]) -> Result<Vec<(usize,usize)>,RingError> {
         if self.use_cached {
@@ -561,12 +1429,66 @@ impl DatabaseAbstractionLayer {
         }
     }
 
+    /// Returns the direct rights of a user, for evaluation; those of deactivated users are ignored
     async fn get_user_rights_for_entities(&self, user_id: usize) -> Result<Vec<(usize,String)>,RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        if self.is_user_deactivated(user_id).await? {
+            return Ok(vec![]); // Grants are kept, but ignored
+        }
+        self.get_user_grants(user_id).await
+    }
+
+    /// Returns the direct rights of a user (or rather, their principal) as stored, even if the user is deactivated
+    pub async fn get_direct_grants(&self, user_id: usize) -> Result<Vec<(usize,String)>,RingError> {
+        let user_id = self.get_principal_id(user_id).await?;
+        self.get_user_grants(user_id).await
+    }
+
+    /// Returns the direct rights of a (principal) user as stored, even if the user is deactivated
+    async fn get_user_grants(&self, user_id: usize) -> Result<Vec<(usize,String)>,RingError> {
         if self.use_cached {
             self.get_user_rights_for_entities_cached(user_id)
         } else {
             self.get_user_rights_for_entities_db(user_id).await
         }
     }
+}
+
+/// Checks if an entity keeps an admin when a user loses their admin right on `admin_removals`.
+/// Admin rights on the entity itself and on its ancestors count; `admin_grants` are (admin_id,entity_id) pairs of active admins.
+fn has_remaining_admin(entity_id: usize, ancestors: &[usize], user_id: usize, admin_removals: &[usize], admin_grants: &[(usize,usize)]) -> bool {
+    admin_grants.iter()
+        .filter(|(_admin_id,admin_entity_id)|*admin_entity_id==entity_id || ancestors.contains(admin_entity_id))
+        .any(|(admin_id,admin_entity_id)|*admin_id!=user_id || !admin_removals.contains(admin_entity_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_keeps_admin_only_if_another_admin_is_left() {
+        assert!(!has_remaining_admin(1,&[],10,&[1],&[(10,1)]));
+        assert!(has_remaining_admin(1,&[],10,&[1],&[(10,1),(11,1)]));
+    }
+
+    #[test]
+    fn child_counts_its_own_admins() {
+        // Entity 2 is a child of 1; user 11 is a direct admin of 2
+        assert!(has_remaining_admin(2,&[1],10,&[1],&[(10,1),(11,2)]));
+        assert!(!has_remaining_admin(2,&[1],10,&[2],&[(10,2)]));
+    }
+
+    #[test]
+    fn child_counts_inherited_admins() {
+        // Entity 3 is a child of 2, which is a child of 1
+        assert!(has_remaining_admin(3,&[1,2],10,&[3],&[(10,3),(11,1)]));
+        assert!(!has_remaining_admin(3,&[1,2],10,&[2],&[(10,2)]));
+        assert!(has_remaining_admin(3,&[1,2],10,&[2],&[(10,1),(10,2)]));
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn grants_on_other_entities_do_not_count() {
+        assert!(!has_remaining_admin(2,&[1],10,&[2],&[(10,2),(11,4)]));
+    }
+}
@@ -1,47 +1,118 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use mysql_async::{prelude::*, Conn};
 use async_session::{Session, SessionStore};
 use async_trait::async_trait;
-use serde_json::json;
+use serde::Serialize;
+use serde_json::{json, Value};
 use tokio::sync::Mutex;
 
 use crate::{db_tables::DbTableSession, error::RingError};
 
+/// Minimum time between updates of the last use of a session, to avoid a DB write on every request
+const LAST_USED_GRANULARITY_SEC: i64 = 60;
+
+/// A session in the in-process cache, with the time it was read from or written to the database
+#[derive(Debug, Clone)]
+struct CachedSession {
+    session: DbTableSession,
+    cached_at: i64,
+}
+
+/// Metadata of a stored session, for users to review where they are logged in
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    #[serde(skip)]
+    pub id_string: String,
+    pub user_id: Option<usize>,
+    pub user_agent: String,
+    pub created: String,
+    pub last_used: String,
+}
+
+impl SessionInfo {
+    fn from_row(row: &mysql_async::Row) -> Self {
+        Self {
+            id: row.get(0).unwrap(),
+            id_string: row.get(1).unwrap(),
+            user_id: row.get(2).unwrap(),
+            user_agent: row.get(3).unwrap(),
+            created: row.get(4).unwrap(),
+            last_used: row.get(5).unwrap(),
+        }
+    }
+}
+
+/// Session store backed by the `session` table. Sessions are cached in-process for a short time only,
+/// so several server instances can share the table.
 #[derive(Debug, Clone)]
 pub struct DatabaseSessionStore {
     pub pool: mysql_async::Pool,
-    cache: Arc<Mutex<HashMap<String,DbTableSession>>>,
+    cache: Arc<Mutex<HashMap<String,CachedSession>>>,
+    cache_ttl: Duration,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
 }
 
 #[async_trait]
 impl SessionStore for DatabaseSessionStore {
     async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
         let id_string = Session::id_from_cookie_value(&cookie_value)?;
-        let ret = match self.cache.lock().await.get(&id_string) {
-            Some(s) => serde_json::from_str(&s.json)?,
-            None => None
+        let now = Self::now();
+        let idle_timeout = self.idle_timeout.as_secs() as i64;
+
+        // Use the cached session if it is fresh; otherwise, the database has the authoritative version
+        let cached = self.cache.lock().await.get(&id_string)
+            .filter(|c|now-c.cached_at <= self.cache_ttl.as_secs() as i64 && now-c.session.last_used <= idle_timeout)
+            .map(|c|c.session.to_owned());
+        let mut entry = match cached {
+            Some(entry) => entry,
+            None => match self.load_session_from_db(&id_string).await? {
+                Some(entry) => entry,
+                None => {
+                    self.cache.lock().await.remove(&id_string);
+                    return Ok(None);
+                }
+            },
         };
-        Ok(ret)
+
+        let session: Session = serde_json::from_str(&entry.json)?;
+        let idle_sec = now-entry.last_used;
+        if session.is_expired() || idle_sec > idle_timeout {
+            self.destroy_session(session).await?;
+            return Ok(None);
+        }
+        if idle_sec > LAST_USED_GRANULARITY_SEC {
+            entry.last_used = now;
+            let sql = "UPDATE `session` SET `last_used`=NOW() WHERE `id_string`=:id_string" ;
+            self.db_conn().await.exec_drop(sql, params!{"id_string" => &id_string}).await?;
+        }
+        self.cache_session(entry).await;
+        Ok(Some(session))
     }
 
-    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+    async fn store_session(&self, mut session: Session) -> async_session::Result<Option<String>> {
+        if session.expiry().is_none() {
+            session.expire_in(self.absolute_timeout);
+        }
         let id_string = session.id().to_string();
         let json = json!(session).to_string();
-        let sql = "INSERT INTO `session` (`id_string`,`json`) VALUES (:id_string,:json) ON DUPLICATE KEY UPDATE `json`=:json" ;
+        let expires = session.expiry().map(|e|e.timestamp()).unwrap_or_default();
+        // Metadata columns, from the session data
+        let user_id = session.get::<Value>("user").and_then(|user|user["id"].as_u64());
+        let user_agent: String = session.get::<String>("user_agent").unwrap_or_default().chars().take(255).collect();
+        let sql = "INSERT INTO `session` (`id_string`,`json`,`expires`,`user_id`,`user_agent`) VALUES (:id_string,:json,FROM_UNIXTIME(:expires),:user_id,:user_agent)
+            ON DUPLICATE KEY UPDATE `json`=:json,`last_used`=NOW(),`user_id`=:user_id,`user_agent`=:user_agent" ;
         let mut conn = self.db_conn().await;
-        conn.exec_drop(sql, params!{id_string,json}).await?;
-        match conn.last_insert_id() {
-            Some(id) => {
-                let id_string = session.id().to_string();
-                let json = json!(session).to_string();
-                let mut cache = self.cache.lock().await;
-                cache.remove(&id_string);
-                let s = DbTableSession {id: id as usize,id_string,json};
-                cache.insert(s.id_string.to_owned(), s);
-            }
-            None => {}
-        }
+        conn.exec_drop(sql, params!{id_string,json,expires,user_id,user_agent}).await?;
+        let id_string = session.id().to_string();
+        let json = json!(session).to_string();
+        let id = match conn.last_insert_id() {
+            Some(id) if id>0 => id as usize,
+            _ => self.cache.lock().await.get(&id_string).map(|c|c.session.id).unwrap_or_default(),
+        };
+        self.cache_session(DbTableSession {id,id_string,json,last_used: Self::now()}).await;
         session.reset_data_changed();
         Ok(session.into_cookie_value())
     }
@@ -64,19 +135,84 @@ impl SessionStore for DatabaseSessionStore {
 }
 
 impl DatabaseSessionStore {
-    /// Create a new instance of DatabaseSessionStore
-    pub async fn new_with_pool(pool: &mysql_async::Pool) -> Result<Self,RingError> {
+    /// Create a new instance of DatabaseSessionStore, with timeouts from the `sessions` config object
+    pub async fn new_with_pool(pool: &mysql_async::Pool, config: &Value) -> Result<Self,RingError> {
         let ret = Self {
             pool: pool.clone(),
             cache: Arc::new(Mutex::new(HashMap::new())),
+            cache_ttl: Duration::from_secs(config["cache_ttl_sec"].as_u64().unwrap_or(60)),
+            idle_timeout: Duration::from_secs(config["idle_timeout_sec"].as_u64().unwrap_or(7*24*3600)),
+            absolute_timeout: Duration::from_secs(config["absolute_timeout_sec"].as_u64().unwrap_or(30*24*3600)),
         };
-        *ret.cache.lock().await = ret.db_conn().await
-            .exec_iter("SELECT `id`,`id_string`,`json` FROM `session`",()).await?
-            .map_and_drop(|row| DbTableSession::from_row(&row) ).await?
-            .into_iter().map(|s|(s.id_string.to_owned(),s)).collect();
         Ok(ret)
     }
 
+    async fn load_session_from_db(&self, id_string: &str) -> Result<Option<DbTableSession>,mysql_async::Error> {
+        let sql = "SELECT `id`,`id_string`,`json`,UNIX_TIMESTAMP(`last_used`) FROM `session` WHERE `id_string`=:id_string" ;
+        Ok(self.db_conn().await
+            .exec_iter(sql,params!{id_string}).await?
+            .map_and_drop(|row| DbTableSession::from_row(&row) ).await?
+            .pop())
+    }
+
+    async fn cache_session(&self, session: DbTableSession) {
+        let cached = CachedSession { session, cached_at: Self::now() };
+        self.cache.lock().await.insert(cached.session.id_string.to_owned(), cached);
+    }
+
+    /// Returns the sessions of the given users
+    pub async fn get_user_sessions(&self, user_ids: &[usize]) -> Result<Vec<SessionInfo>,RingError> {
+        if user_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let user_ids_str = user_ids.iter().map(|id|id.to_string()).collect::<Vec<String>>().join(",");
+        let sql = format!("SELECT `id`,`id_string`,`user_id`,`user_agent`,CAST(`created` AS CHAR),CAST(`last_used` AS CHAR) FROM `session` WHERE `user_id` IN ({user_ids_str}) ORDER BY `last_used` DESC");
+        Ok(self.db_conn().await.exec_iter(sql,()).await?.map_and_drop(|row|SessionInfo::from_row(&row)).await?)
+    }
+
+    /// Destroys sessions of the given users; all of them, or only the one with `session_id`.
+    /// Returns the number of destroyed sessions. Other server instances drop them from their cache within `cache_ttl`.
+    pub async fn destroy_user_sessions(&self, user_ids: &[usize], session_id: Option<usize>) -> Result<usize,RingError> {
+        let sessions: Vec<SessionInfo> = self.get_user_sessions(user_ids).await?
+            .into_iter()
+            .filter(|s|session_id.is_none() || session_id==Some(s.id))
+            .collect();
+        let mut conn = self.db_conn().await;
+        for session in &sessions {
+            let sql = "DELETE FROM `session` WHERE `id`=:id" ;
+            conn.exec_drop(sql, params!{"id" => session.id}).await?;
+        }
+        let mut cache = self.cache.lock().await;
+        for session in &sessions {
+            cache.remove(&session.id_string);
+        }
+        Ok(sessions.len())
+    }
+
+    /// Maximum lifetime of a session, used for the cookie Max-Age
+    pub fn absolute_timeout(&self) -> Duration {
+        self.absolute_timeout
+    }
+
+    /// Removes expired and idle sessions from the database and the cache. Returns the number of removed sessions.
+    pub async fn purge_expired(&self) -> Result<usize,RingError> {
+        let idle_sec = self.idle_timeout.as_secs();
+        let sql = "DELETE FROM `session` WHERE `expires`<NOW() OR `last_used`<NOW() - INTERVAL :idle_sec SECOND" ;
+        let mut conn = self.db_conn().await;
+        conn.exec_drop(sql, params!{idle_sec}).await?;
+        let removed = conn.affected_rows() as usize;
+
+        // Stale cache entries would be reloaded from the database anyway
+        let now = Self::now();
+        let cache_ttl = self.cache_ttl.as_secs() as i64;
+        self.cache.lock().await.retain(|_id_string,c| now-c.cached_at <= cache_ttl && now-c.session.last_used <= idle_sec as i64);
+        Ok(removed)
+    }
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.as_secs() as i64).unwrap_or_default()
+    }
+
     async fn db_conn(&self) -> Conn {
         self.pool.get_conn().await.unwrap()
     }
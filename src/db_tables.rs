@@ -60,6 +60,7 @@ pub struct DbTableSession {
     pub id: usize,
     pub id_string: String,
     pub json: String,
+    pub last_used: i64, // UNIX timestamp
 }
 
 impl DbTableSession {
@@ -68,6 +69,7 @@ impl DbTableSession {
             id: row.get(0).unwrap(),
             id_string: row.get(1).unwrap(),
             json: row.get(2).unwrap(),
+            last_used: row.get::<Option<i64>,_>(3).unwrap().unwrap_or_default(),
         }
     }
 }
@@ -2,21 +2,106 @@
 // use wikibase::mediawiki::media_wiki_error::MediaWikiError;
 
 use std::sync::Arc;
+use axum::{Json, http::StatusCode, response::{IntoResponse, Response}};
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use serde_json::json;
 
 #[derive(Clone, Debug)]
 pub enum RingError { // Lava etc
-    String(String),
+    String(String), // Backend error
+    NotFound(String),
+    Unauthenticated(String),
+    Forbidden(String),
+    Conflict(String),
+    Validation(String),
+    Upstream(String), // An external service, e.g. a login provider, failed or sent an unusable response
     MySQL(Arc<mysql_async::Error>),
     IO(Arc<std::io::Error>),
     Serde(Arc<serde_json::Error>),
 }
 
+/// The kind of an error, which determines the HTTP status code and the machine-readable error code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    Unauthenticated,
+    Forbidden,
+    Conflict,
+    Validation,
+    Upstream,
+    Backend,
+}
+
+impl ErrorKind {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::Validation => StatusCode::BAD_REQUEST,
+            Self::Upstream => StatusCode::BAD_GATEWAY,
+            Self::Backend => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable error code for clients, returned as `error` in JSON responses
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Unauthenticated => "unauthenticated",
+            Self::Forbidden => "forbidden",
+            Self::Conflict => "conflict",
+            Self::Validation => "validation",
+            Self::Upstream => "upstream",
+            Self::Backend => "backend",
+        }
+    }
+}
+
+impl RingError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotFound(_) => ErrorKind::NotFound,
+            Self::Unauthenticated(_) => ErrorKind::Unauthenticated,
+            Self::Forbidden(_) => ErrorKind::Forbidden,
+            Self::Conflict(_) => ErrorKind::Conflict,
+            Self::Validation(_) => ErrorKind::Validation,
+            Self::Upstream(_) => ErrorKind::Upstream,
+            Self::String(_) | Self::MySQL(_) | Self::IO(_) | Self::Serde(_) => ErrorKind::Backend,
+        }
+    }
+
+    /// The status code and JSON body for this error. `status` holds the message, as in successful responses.
+    pub fn json_response(&self) -> (StatusCode, Json<serde_json::Value>) {
+        let kind = self.kind();
+        match kind {
+            ErrorKind::Backend => tracing::error!("{self}"),
+            ErrorKind::Upstream => tracing::warn!("{self}"),
+            _ => {},
+        }
+        (kind.status_code(), Json(json!({"status":self.to_string(),"error":kind.as_str()})))
+    }
+}
+
+impl IntoResponse for RingError {
+    fn into_response(self) -> Response {
+        self.json_response().into_response()
+    }
+}
+
 impl std::error::Error for RingError {}
 
 impl std::fmt::Display for RingError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::String(s) => f.write_str(s),
+            Self::NotFound(s) => f.write_str(s),
+            Self::Unauthenticated(s) => f.write_str(s),
+            Self::Forbidden(s) => f.write_str(s),
+            Self::Conflict(s) => f.write_str(s),
+            Self::Validation(s) => f.write_str(s),
+            Self::Upstream(s) => f.write_str(s),
             Self::MySQL(e) => f.write_str(&e.to_string()),
             Self::IO(e) => f.write_str(&e.to_string()),
             Self::Serde(e) => f.write_str(&e.to_string()),
@@ -32,6 +117,10 @@ impl From<&str> for RingError {
     fn from(e: &str) -> Self {Self::String(e.to_string())}
 }
 
+impl From<reqwest::Error> for RingError {  
+    fn from(e: reqwest::Error) -> Self {Self::Upstream(e.to_string())}
+}
+
 impl From<mysql_async::Error> for RingError {  
     fn from(e: mysql_async::Error) -> Self {Self::MySQL(Arc::new(e))}
 }
@@ -43,3 +132,46 @@ impl From<std::io::Error> for RingError {
 impl From<serde_json::Error> for RingError {  
     fn from(e: serde_json::Error) -> Self {Self::Serde(Arc::new(e))}
 }
+
+impl RingError {
+    /// Maps a rejected request to a validation error; rejections that axum reports as server errors stay backend errors
+    fn from_rejection(status: StatusCode, message: String) -> Self {
+        match status.is_server_error() {
+            true => Self::String(message),
+            false => Self::Validation(message),
+        }
+    }
+}
+
+impl From<JsonRejection> for RingError {
+    fn from(e: JsonRejection) -> Self {Self::from_rejection(e.status(),e.body_text())}
+}
+
+impl From<PathRejection> for RingError {
+    fn from(e: PathRejection) -> Self {Self::from_rejection(e.status(),e.body_text())}
+}
+
+impl From<QueryRejection> for RingError {
+    fn from(e: QueryRejection) -> Self {Self::from_rejection(e.status(),e.body_text())}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_have_status_codes_and_stable_codes() {
+        let (status,json) = RingError::Forbidden("No admin rights".into()).json_response();
+        assert_eq!(status,StatusCode::FORBIDDEN);
+        assert_eq!(json.0,json!({"status":"No admin rights","error":"forbidden"}));
+        assert_eq!(RingError::Upstream("GitHub failed".into()).json_response().0,StatusCode::BAD_GATEWAY);
+        assert_eq!(RingError::String("Backend".into()).kind(),ErrorKind::Backend);
+        assert_eq!(RingError::from("Backend").json_response().1.0["error"],"backend");
+    }
+
+    #[test]
+    fn rejections_are_validation_errors_unless_they_are_server_errors() {
+        assert_eq!(RingError::from_rejection(StatusCode::UNPROCESSABLE_ENTITY,"Bad body".into()).kind(),ErrorKind::Validation);
+        assert_eq!(RingError::from_rejection(StatusCode::INTERNAL_SERVER_ERROR,"Missing extension".into()).kind(),ErrorKind::Backend);
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use async_session::{Session, SessionStore};
 use axum::TypedHeader;
 use serde_json::{Value, json};
@@ -6,13 +6,19 @@ use serde::{Serialize,Deserialize};
 use crate::error::RingError;
 use crate::app_state::AppState;
 use crate::entity::EntityGroup;
+use crate::invitation;
 
 pub static COOKIE_NAME: &str = "SESSION";
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
 pub enum ExternalSystem {
     ORCID,
     GOOGLE,
+    WIKIMEDIA,
+    GITHUB,
+    OIDC(String), // Generic OpenID Connect provider, by its key in the `systems` config
+    SERVICE, // Service account, not tied to a human login
     Unknown,
 }
 
@@ -21,6 +27,10 @@ impl ExternalSystem {
         match self {
             Self::ORCID => "orcid",
             Self::GOOGLE => "google",
+            Self::WIKIMEDIA => "wikimedia",
+            Self::GITHUB => "github",
+            Self::OIDC(key) => key,
+            Self::SERVICE => "service",
             Self::Unknown => "",
         }
     }
@@ -29,15 +39,52 @@ impl ExternalSystem {
         match self {
             Self::ORCID => format!("https://orcid.org/{external_id}"),
             Self::GOOGLE => String::new(),
+            Self::WIKIMEDIA => String::new(), // See AppState::external_url
+            Self::GITHUB => format!("https://github.com/{external_id}"),
+            Self::OIDC(_) => String::new(), // See OidcProvider::as_url
+            Self::SERVICE => String::new(),
             Self::Unknown => String::new(),
         }
     }
 
-    pub fn from_str(s: &str) -> Self {
+    pub fn from_name(s: &str) -> Self {
         match s.to_lowercase().as_str() {
             "orcid" => Self::ORCID,
             "google" => Self::GOOGLE,
-            _ => Self::Unknown,
+            "wikimedia" => Self::WIKIMEDIA,
+            "github" => Self::GITHUB,
+            "service" => Self::SERVICE,
+            "" => Self::Unknown,
+            _ => Self::OIDC(s.to_string()),
+        }
+    }
+}
+
+// Serialized as "ORCID"/"GOOGLE"/"WIKIMEDIA"/"GITHUB"/"SERVICE"/"Unknown" for compatibility with existing sessions, OIDC providers by key
+impl From<String> for ExternalSystem {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "ORCID" => Self::ORCID,
+            "GOOGLE" => Self::GOOGLE,
+            "WIKIMEDIA" => Self::WIKIMEDIA,
+            "GITHUB" => Self::GITHUB,
+            "SERVICE" => Self::SERVICE,
+            "Unknown" | "" => Self::Unknown,
+            _ => Self::OIDC(s),
+        }
+    }
+}
+
+impl From<ExternalSystem> for String {
+    fn from(system: ExternalSystem) -> Self {
+        match system {
+            ExternalSystem::ORCID => "ORCID".to_string(),
+            ExternalSystem::GOOGLE => "GOOGLE".to_string(),
+            ExternalSystem::WIKIMEDIA => "WIKIMEDIA".to_string(),
+            ExternalSystem::GITHUB => "GITHUB".to_string(),
+            ExternalSystem::OIDC(key) => key,
+            ExternalSystem::SERVICE => "SERVICE".to_string(),
+            ExternalSystem::Unknown => "Unknown".to_string(),
         }
     }
 }
@@ -49,8 +96,14 @@ pub struct ExternalSystemUser {
     pub system: ExternalSystem,
     pub name: String,
     pub email: String,
+    #[serde(default)]
+    pub email_verified: bool, // Set if the login system has verified the email address
     pub external_id: String,
     pub bespoke_data: Value,
+    #[serde(default)]
+    pub principal_id: Option<usize>, // Set if this is a linked identity of another user
+    #[serde(default)]
+    pub deactivated: bool,
 }
 
 impl ExternalSystemUser {
@@ -60,13 +113,28 @@ impl ExternalSystemUser {
         let name = &self.name;
         let email = &self.email;
         let bespoke_data = self.bespoke_data.to_string();
-        self.id = state.dal.write().await.add_user(system,external_id,name,email,&bespoke_data).await?;
-        self.id.ok_or_else(||format!("User {system}:{external_id} was not added to database").into())
+        self.id = state.dal.write().await.add_user(system,external_id,name,email,self.email_verified,&bespoke_data).await?;
+        let user_id = self.id.ok_or_else(||RingError::String(format!("User {system}:{external_id} was not added to database")))?;
+
+        // Pending invitations for this ORCID iD or email address
+        if let Err(e) = invitation::apply_to_user(&state,self).await {
+            tracing::warn!("Could not apply invitations for user #{user_id}: {e}");
+        }
+        Ok(user_id)
     }
 
     pub fn strip_private_data(&mut self) {
         self.bespoke_data = Value::Null;
         self.email = String::new();
+        self.email_verified = false;
+    }
+
+    /// Returns the email address, if the login system has verified it
+    pub fn verified_email(&self) -> Option<&str> {
+        match self.email_verified && !self.email.is_empty() {
+            true => Some(&self.email),
+            false => None,
+        }
     }
 
     pub fn from_row(row: &mysql_async::Row) -> Self {
@@ -74,37 +142,68 @@ impl ExternalSystemUser {
         let json: String = row.get(5).unwrap();
         Self {
             id: row.get(0),
-            system: ExternalSystem::from_str(&system),
+            system: ExternalSystem::from_name(&system),
             name: row.get(2).unwrap(),
             external_id: row.get(3).unwrap(),
             email: row.get(4).unwrap(),
+            email_verified: row.get(6).unwrap(),
             bespoke_data: serde_json::from_str(&json).unwrap_or(Value::Null),
+            principal_id: row.get(7).unwrap(),
+            deactivated: row.get(8).unwrap(),
         }
     }
 
+    /// Returns the ID of the user that rights are evaluated for
+    pub fn principal(&self) -> Option<usize> {
+        self.principal_id.or(self.id.map(|id|id as usize))
+    }
+
+    /// Returns the GitHub organisations the user was a member of at login
+    pub fn github_orgs(&self) -> Vec<String> {
+        match self.system {
+            ExternalSystem::GITHUB => self.bespoke_data["orgs"].as_array()
+                .map(|orgs|orgs.iter().filter_map(|org|org.as_str()).map(|org|org.to_lowercase()).collect())
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    pub fn is_service_account(&self) -> bool {
+        self.system==ExternalSystem::SERVICE
+    }
+
     pub fn external_url(&self) -> String {
-        self.system.as_url(&self.external_id)
+        self.system.as_url(&self.external_name())
     }
 
-    pub async fn set_cookie(&self, state: Arc<AppState>) -> Result<String,RingError> {
+    /// Returns the user name in the external system, which can differ from the stable external ID
+    pub fn external_name(&self) -> String {
+        let name = match self.system {
+            ExternalSystem::WIKIMEDIA => self.bespoke_data["username"].as_str(),
+            ExternalSystem::GITHUB => self.bespoke_data["login"].as_str(),
+            _ => None,
+        };
+        name.unwrap_or(&self.external_id).to_string()
+    }
+
+    pub async fn set_cookie(&self, state: Arc<AppState>, user_agent: &str) -> Result<String,RingError> {
         // Create a new session filled with user data
         let mut session = Session::new();
-        session.insert("user", &self)?;
+        session.insert("user", self)?;
+        session.insert("user_agent", user_agent)?;
 
         // Store session and get corresponding cookie
-        let cookie = state.dal.read().await.session_store.store_session(session)
+        let session_store = &state.dal.read().await.session_store;
+        let cookie = session_store.store_session(session)
             .await
             .map_err(|e|e.to_string())?
-            .ok_or_else(||format!("Session store error"))?;
+            .ok_or_else(||"Session store error".to_string())?;
 
-        // Build the cookie
-        let cookie = format!("{}={}; SameSite=Lax; Path=/", COOKIE_NAME, cookie);
-        Ok(cookie)
+        Ok(session_cookie(&cookie,session_store.absolute_timeout()))
     }
 
     pub async fn from_cookies(app: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>) -> Option<Self> {
-        let cookie = cookies.to_owned()?.get(COOKIE_NAME)?.to_string();
-        let session = app.dal.read().await.session_store.load_session(cookie).await.ok()??;
+        let session = session_from_cookies(app, cookies).await?;
         let j = json!(session).get("data").cloned()?.get("user")?.to_owned();
         let user: Value = serde_json::from_str(j.as_str()?).ok()?;
         let s = serde_json::to_string(&user).ok()?;
@@ -113,6 +212,7 @@ impl ExternalSystemUser {
     }
 
     pub async fn get_entities_with_access(&self, app: &Arc<AppState>) -> Result<EntityGroup,RingError> {
+        // Resolves linked identities to their principal in the DAL
         match self.id {
             Some(user_id) => app.dal.read().await.get_entities_with_user_access(user_id as usize,None).await,
             None => Ok(EntityGroup::empty()),
@@ -122,6 +222,16 @@ impl ExternalSystemUser {
 
 }
 
+/// Builds the session cookie header value. The cookie is not readable by scripts, and only sent via HTTPS.
+pub fn session_cookie(cookie_value: &str, max_age: Duration) -> String {
+    format!("{}={}; Max-Age={}; Secure; HttpOnly; SameSite=Lax; Path=/", COOKIE_NAME, cookie_value, max_age.as_secs())
+}
+
+/// Loads the session belonging to the session cookie, if any
+pub async fn session_from_cookies(app: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>) -> Option<Session> {
+    let cookie = cookies.to_owned()?.get(COOKIE_NAME)?.to_string();
+    app.dal.read().await.session_store.load_session(cookie).await.ok()?
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ExternalAccessRequest {
@@ -129,6 +239,8 @@ pub struct ExternalAccessRequest {
     pub user_id: usize,
     pub entity_id: usize,
     pub note: String,
+    pub created: String,
+    pub escalated: Option<String>,
 }
 
 impl ExternalAccessRequest {
@@ -138,6 +250,90 @@ impl ExternalAccessRequest {
             user_id: row.get(1).unwrap(),
             entity_id: row.get(2).unwrap(),
             note: row.get(3).unwrap(),
+            created: row.get(4).unwrap(),
+            escalated: row.get(5).unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessApproval {
+    pub id: usize,
+    pub entity_id: usize,
+    pub user_id: usize,
+    pub right: String,
+    pub approver_id: usize,
+    pub timestamp: String,
+}
+
+impl AccessApproval {
+    pub fn from_row(row: &mysql_async::Row) -> Self {
+        Self {
+            id: row.get(0).unwrap(),
+            entity_id: row.get(1).unwrap(),
+            user_id: row.get(2).unwrap(),
+            right: row.get(3).unwrap(),
+            approver_id: row.get(4).unwrap(),
+            timestamp: row.get(5).unwrap(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SuperuserAction {
+    pub id: usize,
+    pub user_id: usize,
+    pub action: String,
+    pub details: String,
+    pub timestamp: String,
+}
+
+impl SuperuserAction {
+    pub fn from_row(row: &mysql_async::Row) -> Self {
+        Self {
+            id: row.get(0).unwrap(),
+            user_id: row.get(1).unwrap(),
+            action: row.get(2).unwrap(),
+            details: row.get(3).unwrap(),
+            timestamp: row.get(4).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(system: &str, external_id: &str, bespoke_data: Value) -> ExternalSystemUser {
+        serde_json::from_value(json!({"id":1,"system":system,"name":"Some One","email":"","external_id":external_id,"bespoke_data":bespoke_data})).unwrap()
+    }
+
+    #[test]
+    fn github_users_are_named_by_login() {
+        let user = user("GITHUB","12345",json!({"login":"someone","orgs":["Some-Org","other"]}));
+        assert_eq!(user.external_name(),"someone");
+        assert_eq!(user.github_orgs(),vec!["some-org".to_string(),"other".to_string()]);
+    }
+
+    #[test]
+    fn only_github_users_have_github_orgs() {
+        let user = user("ORCID","0000-0002-1825-0097",json!({"orgs":["some-org"]}));
+        assert!(user.github_orgs().is_empty());
+        assert_eq!(user.external_name(),"0000-0002-1825-0097");
+    }
+
+    #[test]
+    fn session_cookies_are_hardened() {
+        let cookie = session_cookie("abc",Duration::from_secs(3600));
+        assert!(cookie.starts_with(&format!("{COOKIE_NAME}=abc;")));
+        for attribute in ["Max-Age=3600","Secure","HttpOnly","SameSite=Lax","Path=/"] {
+            assert!(cookie.split("; ").any(|a|a==attribute),"{attribute} missing in {cookie}");
+        }
+    }
+
+    #[test]
+    fn wikimedia_users_are_named_by_username() {
+        assert_eq!(user("WIKIMEDIA","987",json!({"username":"Some One"})).external_name(),"Some One");
+        assert_eq!(user("WIKIMEDIA","987",json!({})).external_name(),"987");
+    }
+}
@@ -0,0 +1,82 @@
+use axum::{async_trait, body::HttpBody, BoxError};
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::{Request, StatusCode, request::Parts};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use crate::error::RingError;
+
+/// Like `axum::extract::Path`, but rejects with the JSON error shape of `RingError`
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T,S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = RingError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self,Self::Rejection> {
+        let axum::extract::Path(value) = axum::extract::Path::<T>::from_request_parts(parts,state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Like `axum::extract::Query`, but rejects with the JSON error shape of `RingError`
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T,S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = RingError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self,Self::Rejection> {
+        let axum::extract::Query(value) = axum::extract::Query::<T>::from_request_parts(parts,state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Like `axum::Json` as a request body, but rejects with the JSON error shape of `RingError`
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T,S,B> FromRequest<S,B> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = RingError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self,Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req,state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Like `axum::Form`, for the OAuth endpoints: a malformed body is rejected with an OAuth `invalid_request` error
+pub struct OAuthForm<T>(pub T);
+
+#[async_trait]
+impl<T,S,B> FromRequest<S,B> for OAuthForm<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = (StatusCode, axum::Json<Value>);
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self,Self::Rejection> {
+        match axum::Form::<T>::from_request(req,state).await {
+            Ok(axum::Form(value)) => Ok(Self(value)),
+            Err(e) => Err((StatusCode::BAD_REQUEST, axum::Json(json!({"error":"invalid_request","error_description":e.body_text()})))),
+        }
+    }
+}
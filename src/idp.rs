@@ -0,0 +1,360 @@
+use std::{collections::HashMap, fs, time::{SystemTime, UNIX_EPOCH}};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rsa::{RsaPrivateKey, RsaPublicKey, PublicKeyParts};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use crate::api_token::ApiToken;
+use crate::database_abstraction_layer::DatabaseAbstractionLayer;
+use crate::error::RingError;
+
+pub static SCOPE_RIGHTS: &str = "rights";
+pub static JWT_TYPE_ACCESS_TOKEN: &str = "at+jwt";
+pub static JWT_TYPE_ASSERTION: &str = "sauron-assertion+jwt";
+/// Scopes a client may request unless `allowed_scopes` is configured; `rights` has to be allowed explicitly
+static DEFAULT_SCOPES: [&str;3] = ["openid","profile","email"];
+
+/// A client application that may log users in via sauron, configured under `idp.clients`
+#[derive(Clone, Debug)]
+pub struct IdpClient {
+    pub client_id: String,
+    pub name: String,
+    client_secret: String,
+    pub redirect_uris: Vec<String>,
+    pub entity_ids: Vec<usize>, // Entities whose rights are included in the tokens
+    pub allowed_scopes: Vec<String>,
+    pub trusted: bool, // Users are not asked to consent to trusted clients
+    pub introspect: bool, // May introspect tokens issued to other clients, and API tokens
+}
+
+impl IdpClient {
+    fn from_config(client_id: &str, config: &Value) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            name: config["name"].as_str().unwrap_or(client_id).to_string(),
+            client_secret: config["client_secret"].as_str().unwrap_or_default().to_string(),
+            redirect_uris: config["redirect_uris"].as_array()
+                .map(|a|a.iter().filter_map(|s|s.as_str()).map(|s|s.to_string()).collect())
+                .unwrap_or_default(),
+            entity_ids: config["entity_ids"].as_array()
+                .map(|a|a.iter().filter_map(|id|id.as_u64()).map(|id|id as usize).collect())
+                .unwrap_or_default(),
+            allowed_scopes: config["allowed_scopes"].as_array()
+                .map(|a|a.iter().filter_map(|s|s.as_str()).map(|s|s.to_string()).collect())
+                .unwrap_or_else(||DEFAULT_SCOPES.iter().map(|s|s.to_string()).collect()),
+            trusted: config["trusted"].as_bool().unwrap_or(false),
+            introspect: config["introspect"].as_bool().unwrap_or(false),
+        }
+    }
+
+    /// Checks that the client may request all of these (space-separated) scopes
+    pub fn allows_scope(&self, scope: &str) -> bool {
+        scope_covers(&self.allowed_scopes.join(" "),scope)
+    }
+
+    pub fn check_secret(&self, client_secret: &str) -> bool {
+        !self.client_secret.is_empty() && ApiToken::hash(&self.client_secret)==ApiToken::hash(client_secret)
+    }
+}
+
+/// Checks if the (space-separated) `granted` scopes include all `requested` ones
+pub fn scope_covers(granted: &str, requested: &str) -> bool {
+    let granted: Vec<&str> = granted.split(' ').collect();
+    requested.split(' ').filter(|s|!s.is_empty()).all(|s|granted.contains(&s))
+}
+
+/// An authorization request of a client that is waiting for the consent of the user. Stored in the session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingConsent {
+    pub token: String,
+    pub query: String, // The query string of the authorization request, to continue with after consent
+    pub client_id: String,
+    pub scope: String,
+    pub expires: u64,
+}
+
+/// A one-time authorization code, issued by the authorization endpoint and redeemed at the token endpoint
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdpCode {
+    pub client_id: String,
+    pub user_id: usize,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+}
+
+impl IdpCode {
+    pub fn from_row(row: &mysql_async::Row) -> Self {
+        Self {
+            client_id: row.get(0).unwrap(),
+            user_id: row.get(1).unwrap(),
+            redirect_uri: row.get(2).unwrap(),
+            scope: row.get(3).unwrap(),
+            nonce: row.get(4).unwrap(),
+            code_challenge: row.get(5).unwrap(),
+        }
+    }
+
+    /// Checks a PKCE code verifier against the stored S256 challenge, if any
+    pub fn check_verifier(&self, code_verifier: Option<&String>) -> bool {
+        match (&self.code_challenge,code_verifier) {
+            (None,_) => true,
+            (Some(challenge),Some(verifier)) => URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))==*challenge,
+            (Some(_),None) => false,
+        }
+    }
+}
+
+/// Sauron as an OpenID Connect identity provider, configured in the `idp` config object.
+/// Tokens are RS256-signed JWTs.
+#[derive(Clone, Debug)]
+pub struct Idp {
+    pub issuer: String,
+    key_id: String,
+    private_key: RsaPrivateKey,
+    public_key: RsaPublicKey,
+    clients: HashMap<String,IdpClient>,
+    pub id_token_ttl: u64,
+    pub access_token_ttl: u64,
+    pub code_ttl: u64,
+    pub assertion_ttl: u64,
+}
+
+impl Idp {
+    /// Returns `None` if the identity provider is not configured
+    pub fn from_config(config: &Value, default_issuer: &str) -> Result<Option<Self>,RingError> {
+        let key_file = match config["signing_key"].as_str() {
+            Some(key_file) => key_file,
+            None => return Ok(None),
+        };
+        let pem = fs::read_to_string(key_file)?;
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_|RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|e|RingError::String(format!("Can not read IdP signing key {key_file}: {e}")))?;
+        let clients = config["clients"].as_object()
+            .map(|clients|clients.iter().map(|(id,c)|(id.to_owned(),IdpClient::from_config(id,c))).collect())
+            .unwrap_or_default();
+        Ok(Some(Self {
+            issuer: config["issuer"].as_str().unwrap_or(default_issuer).trim_end_matches('/').to_string(),
+            key_id: config["key_id"].as_str().unwrap_or("sauron").to_string(),
+            public_key: RsaPublicKey::from(&private_key),
+            private_key,
+            clients,
+            id_token_ttl: config["id_token_ttl_sec"].as_u64().unwrap_or(3600),
+            access_token_ttl: config["access_token_ttl_sec"].as_u64().unwrap_or(3600),
+            code_ttl: config["code_ttl_sec"].as_u64().unwrap_or(60),
+            assertion_ttl: config["assertion_ttl_sec"].as_u64().unwrap_or(300),
+        }))
+    }
+
+    pub fn client(&self, client_id: &str) -> Option<&IdpClient> {
+        self.clients.get(client_id)
+    }
+
+    /// Returns the client if the client credentials are valid
+    pub fn authenticate_client(&self, client_id: &str, client_secret: &str) -> Option<&IdpClient> {
+        self.client(client_id).filter(|client|client.check_secret(client_secret))
+    }
+
+    pub fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.as_secs()).unwrap_or_default()
+    }
+
+    /// Creates a signed JWT with the given type and claims
+    pub fn sign(&self, typ: &str, claims: &Value) -> Result<String,RingError> {
+        let header = json!({"alg":"RS256","typ":typ,"kid":self.key_id});
+        let payload = format!("{}.{}",URL_SAFE_NO_PAD.encode(header.to_string()),URL_SAFE_NO_PAD.encode(claims.to_string()));
+        let signing_key = SigningKey::<Sha256>::new_with_prefix(self.private_key.to_owned());
+        let signature = signing_key.try_sign(payload.as_bytes()).map_err(|e|RingError::String(e.to_string()))?;
+        Ok(format!("{payload}.{}",URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+    }
+
+    /// Verifies a JWT issued by this IdP, with the given type. Returns the claims if the token is valid and not expired.
+    pub fn verify(&self, typ: &str, token: &str) -> Result<Value,RingError> {
+        let invalid = ||RingError::Unauthenticated("Invalid token".into());
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len()!=3 {
+            return Err(invalid());
+        }
+        let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[0]).map_err(|_|invalid())?)?;
+        if header["alg"].as_str()!=Some("RS256") || header["typ"].as_str()!=Some(typ) {
+            return Err(invalid());
+        }
+        let signature = Signature::try_from(URL_SAFE_NO_PAD.decode(parts[2]).map_err(|_|invalid())?.as_slice()).map_err(|_|invalid())?;
+        let verifying_key = VerifyingKey::<Sha256>::new_with_prefix(self.public_key.to_owned());
+        verifying_key.verify(format!("{}.{}",parts[0],parts[1]).as_bytes(), &signature).map_err(|_|invalid())?;
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).map_err(|_|invalid())?)?;
+        if claims["iss"].as_str()!=Some(&self.issuer) || claims["exp"].as_u64().unwrap_or(0) < Self::now() {
+            return Err(RingError::Unauthenticated("Token expired or from another issuer".into()));
+        }
+        Ok(claims)
+    }
+
+    /// The public signing key as a JSON Web Key Set
+    pub fn jwks(&self) -> Value {
+        json!({"keys":[{
+            "kty":"RSA",
+            "use":"sig",
+            "alg":"RS256",
+            "kid":self.key_id,
+            "n":URL_SAFE_NO_PAD.encode(self.public_key.n().to_bytes_be()),
+            "e":URL_SAFE_NO_PAD.encode(self.public_key.e().to_bytes_be()),
+        }]})
+    }
+
+    /// The public signing key in PEM (SPKI) format, for verifiers that do not handle JWKS
+    pub fn public_key_pem(&self) -> Result<String,RingError> {
+        self.public_key.to_public_key_pem(LineEnding::LF).map_err(|e|RingError::String(e.to_string()))
+    }
+
+    /// The key ID, as used in the `kid` header of issued tokens
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The OpenID Connect discovery document
+    pub fn discovery(&self) -> Value {
+        let issuer = &self.issuer;
+        json!({
+            "issuer":issuer,
+            "authorization_endpoint":format!("{issuer}/oidc/authorize"),
+            "token_endpoint":format!("{issuer}/oidc/token"),
+            "userinfo_endpoint":format!("{issuer}/oidc/userinfo"),
+            "jwks_uri":format!("{issuer}/oidc/jwks"),
+            "introspection_endpoint":format!("{issuer}/oauth/introspect"),
+            "response_types_supported":["code"],
+            "grant_types_supported":["authorization_code"],
+            "subject_types_supported":["public"],
+            "id_token_signing_alg_values_supported":["RS256"],
+            "scopes_supported":["openid","profile","email",SCOPE_RIGHTS],
+            "token_endpoint_auth_methods_supported":["client_secret_basic","client_secret_post"],
+            "code_challenge_methods_supported":["S256"],
+            "claims_supported":["sub","name","email","email_verified",SCOPE_RIGHTS],
+        })
+    }
+}
+
+/// Returns the effective rights of a user on the given entities, as a JSON object (entity ID => rights)
+pub async fn rights_claim(dal: &DatabaseAbstractionLayer, user_id: usize, entity_ids: &[usize]) -> Result<Value,RingError> {
+    let allowed_entities = dal.get_all_user_rights_for_entities(user_id,None).await?;
+    let rights: HashMap<String,Vec<String>> = entity_ids.iter()
+        .filter_map(|entity_id|allowed_entities.get(*entity_id))
+        .map(|entity|(entity.id.to_string(),entity.rights.to_owned()))
+        .collect();
+    Ok(json!(rights))
+}
+
+/// Returns the claims about a user that the (space-separated) scopes grant a client access to
+pub async fn user_claims(dal: &DatabaseAbstractionLayer, user_id: usize, scope: &str, client: &IdpClient) -> Result<Value,RingError> {
+    let scopes: Vec<&str> = scope.split(' ').collect();
+    let user = dal.get_user(user_id).await?;
+    let mut claims = json!({"sub":user_id.to_string()});
+    if scopes.contains(&"profile") {
+        claims["name"] = json!(user.name);
+    }
+    // Only emails the login system verified are passed on, as relying parties may match accounts on them
+    if scopes.contains(&"email") {
+        if let Some(email) = user.verified_email() {
+            claims["email"] = json!(email);
+            claims["email_verified"] = json!(true);
+        }
+    }
+    if scopes.contains(&SCOPE_RIGHTS) {
+        claims[SCOPE_RIGHTS] = rights_claim(dal,user_id,&client.entity_ids).await?;
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_idp() -> Idp {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(),1024).unwrap();
+        Idp {
+            issuer: "https://sauron.example.org".to_string(),
+            key_id: "test".to_string(),
+            public_key: RsaPublicKey::from(&private_key),
+            private_key,
+            clients: HashMap::new(),
+            id_token_ttl: 3600,
+            access_token_ttl: 3600,
+            code_ttl: 60,
+            assertion_ttl: 300,
+        }
+    }
+
+    fn claims(idp: &Idp) -> Value {
+        json!({"iss":idp.issuer,"sub":"1","exp":Idp::now()+60})
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let idp = test_idp();
+        let token = idp.sign(JWT_TYPE_ACCESS_TOKEN,&claims(&idp)).unwrap();
+        assert_eq!(idp.verify(JWT_TYPE_ACCESS_TOKEN,&token).unwrap()["sub"],"1");
+        // A token signed by another key
+        let other = test_idp().sign(JWT_TYPE_ACCESS_TOKEN,&claims(&idp)).unwrap();
+        assert!(idp.verify(JWT_TYPE_ACCESS_TOKEN,&other).is_err());
+    }
+
+    #[test]
+    fn tokens_of_another_type_are_rejected() {
+        let idp = test_idp();
+        let id_token = idp.sign("JWT",&claims(&idp)).unwrap();
+        assert!(idp.verify(JWT_TYPE_ACCESS_TOKEN,&id_token).is_err());
+        let assertion = idp.sign(JWT_TYPE_ASSERTION,&claims(&idp)).unwrap();
+        assert!(idp.verify(JWT_TYPE_ACCESS_TOKEN,&assertion).is_err());
+        let access_token = idp.sign(JWT_TYPE_ACCESS_TOKEN,&claims(&idp)).unwrap();
+        assert!(idp.verify(JWT_TYPE_ASSERTION,&access_token).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let idp = test_idp();
+        let mut claims = claims(&idp);
+        claims["exp"] = json!(Idp::now()-1);
+        let token = idp.sign(JWT_TYPE_ACCESS_TOKEN,&claims).unwrap();
+        assert!(idp.verify(JWT_TYPE_ACCESS_TOKEN,&token).is_err());
+    }
+
+    #[test]
+    fn tokens_of_another_issuer_are_rejected() {
+        let idp = test_idp();
+        let mut claims = claims(&idp);
+        claims["iss"] = json!("https://other.example.org");
+        let token = idp.sign(JWT_TYPE_ACCESS_TOKEN,&claims).unwrap();
+        assert!(idp.verify(JWT_TYPE_ACCESS_TOKEN,&token).is_err());
+    }
+
+    #[test]
+    fn pkce_verifier_must_match_the_challenge() {
+        // From RFC 7636, appendix B
+        let code = IdpCode {
+            client_id: "client".to_string(),
+            user_id: 1,
+            redirect_uri: "https://client.example.org/callback".to_string(),
+            scope: "openid".to_string(),
+            nonce: None,
+            code_challenge: Some("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".to_string()),
+        };
+        assert!(code.check_verifier(Some(&"dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string())));
+        assert!(!code.check_verifier(Some(&"another_verifier".to_string())));
+        assert!(!code.check_verifier(None));
+        let code = IdpCode { code_challenge: None, ..code };
+        assert!(code.check_verifier(None));
+    }
+
+    #[test]
+    fn scopes_must_all_be_granted() {
+        assert!(scope_covers("openid profile email","openid email"));
+        assert!(scope_covers("openid profile","openid  profile"));
+        assert!(!scope_covers("openid profile","openid rights"));
+        assert!(!scope_covers("openid","openidx"));
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use crate::api_token::{ApiToken, random_string};
+use crate::app_state::AppState;
+use crate::error::RingError;
+use crate::external_system::{ExternalSystem, ExternalSystemUser};
+
+pub static KIND_ORCID: &str = "orcid";
+pub static KIND_EMAIL: &str = "email";
+pub static KIND_LINK: &str = "link";
+
+/// A pending grant of rights for someone who may not have logged in yet.
+/// It is materialised into `access` when a matching user logs in, or follows the invite link.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: usize,
+    pub kind: String,
+    pub target: String, // ORCID iD, email address, or hash of the link token
+    pub entity_ids: Vec<usize>,
+    pub rights: Vec<String>,
+    pub created_by: usize,
+    pub created: String,
+    pub expires: String,
+    pub accepted_by: Option<usize>,
+    pub accepted: Option<String>,
+}
+
+impl Invitation {
+    pub fn from_row(row: &mysql_async::Row) -> Self {
+        let entity_ids: String = row.get(3).unwrap();
+        let rights: String = row.get(4).unwrap();
+        Self {
+            id: row.get(0).unwrap(),
+            kind: row.get(1).unwrap(),
+            target: row.get(2).unwrap(),
+            entity_ids: entity_ids.split(',').filter_map(|s|s.parse::<usize>().ok()).collect(),
+            rights: rights.split(',').filter(|s|!s.is_empty()).map(|s|s.to_string()).collect(),
+            created_by: row.get(5).unwrap(),
+            created: row.get(6).unwrap(),
+            expires: row.get(7).unwrap(),
+            accepted_by: row.get(8).unwrap(),
+            accepted: row.get(9).unwrap(),
+        }
+    }
+
+    /// Generates a new invite link token, and returns it together with its hash
+    pub fn generate_link_token() -> (String,String) {
+        let token = random_string("inv_",24);
+        let hash = ApiToken::hash(&token);
+        (token,hash)
+    }
+
+    /// Normalises and checks the target of an ORCID or email invitation
+    pub fn normalize_target(kind: &str, target: &str) -> Result<String,RingError> {
+        let target = target.trim();
+        let valid = if kind==KIND_ORCID {
+            is_orcid_id(target)
+        } else if kind==KIND_EMAIL {
+            target.contains('@') && !target.contains(char::is_whitespace)
+        } else {
+            false
+        };
+        match valid {
+            true if kind==KIND_EMAIL => Ok(target.to_lowercase()),
+            true => Ok(target.to_uppercase()),
+            false => Err(RingError::Validation(format!("Invalid {kind} invitation target '{target}'"))),
+        }
+    }
+}
+
+/// Checks the format of an ORCID iD, e.g. 0000-0002-1825-0097
+fn is_orcid_id(s: &str) -> bool {
+    let parts: Vec<&str> = s.split('-').collect();
+    parts.len()==4 && parts.iter().enumerate().all(|(num,part)|{
+        part.len()==4 && part.chars().enumerate().all(|(pos,c)|c.is_ascii_digit() || (num==3 && pos==3 && (c=='X' || c=='x')))
+    })
+}
+
+/// Grants the rights of an invitation to a user, unless it was accepted already.
+/// The inviting admin counts as the approver, and must still be an admin of all the invitation's entities (or a superuser).
+/// Returns false if the invitation was already used.
+pub async fn accept(state: &Arc<AppState>, invitation: &Invitation, user_id: usize) -> Result<bool,RingError> {
+    check_inviter(state,invitation).await?;
+    let pending = match state.dal.write().await.accept_invitation(invitation,user_id).await? {
+        Some(pending) => pending,
+        None => return Ok(false),
+    };
+    tracing::info!("User #{user_id} accepted invitation #{} ({} rights still need approval)",invitation.id,pending.len());
+    Ok(true)
+}
+
+/// Checks that the inviting admin is still active, and still has admin rights on all entities of the invitation
+async fn check_inviter(state: &Arc<AppState>, invitation: &Invitation) -> Result<(),RingError> {
+    let inviter_id = invitation.created_by;
+    let denied = || RingError::Forbidden(format!("The admin who created invitation #{} can no longer grant these rights",invitation.id));
+    if state.dal.read().await.is_user_deactivated(inviter_id).await? {
+        return Err(denied());
+    }
+    let admin_entities = state.dal.read().await.get_all_user_rights_for_entities(inviter_id,Some("admin".into())).await?;
+    if invitation.entity_ids.iter().all(|entity_id|admin_entities.has(*entity_id)) || state.is_superuser(inviter_id).await? {
+        return Ok(());
+    }
+    Err(denied())
+}
+
+/// Materialises all pending ORCID and email invitations matching a user that just logged in.
+/// Email invitations only match if the login system has verified the address. An invitation that fails is logged and skipped.
+pub async fn apply_to_user(state: &Arc<AppState>, user: &ExternalSystemUser) -> Result<(),RingError> {
+    let user_id = match user.id {
+        Some(id) => id as usize,
+        None => return Ok(()),
+    };
+    let orcid_id = match user.system {
+        ExternalSystem::ORCID => user.external_id.to_uppercase(),
+        _ => String::new(),
+    };
+    let email = user.verified_email().map(|email|email.trim().to_lowercase()).unwrap_or_default();
+    let invitations = state.dal.read().await.get_matching_invitations(&orcid_id,&email).await?;
+    if invitations.is_empty() || state.dal.read().await.is_user_deactivated(user_id).await? {
+        return Ok(());
+    }
+    for invitation in &invitations {
+        if let Err(e) = accept(state,invitation,user_id).await {
+            tracing::warn!("Could not apply invitation #{} to user #{user_id}: {e}",invitation.id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orcid_ids_have_four_blocks_of_digits() {
+        assert!(is_orcid_id("0000-0002-1825-0097"));
+        assert!(is_orcid_id("0000-0002-1694-233X"));
+        assert!(is_orcid_id("0000-0002-1694-233x"));
+        assert!(!is_orcid_id("0000-0002-1694-23X3"));
+        assert!(!is_orcid_id("0000-0002-1825"));
+        assert!(!is_orcid_id("0000-0002-1825-0097-0000"));
+        assert!(!is_orcid_id("0000-0002-1825-009"));
+        assert!(!is_orcid_id("https://orcid.org/0000-0002-1825-0097"));
+        assert!(!is_orcid_id("000a-0002-1825-0097"));
+    }
+
+    #[test]
+    fn targets_are_normalized() {
+        assert_eq!(Invitation::normalize_target(KIND_ORCID," 0000-0002-1694-233x ").unwrap(),"0000-0002-1694-233X");
+        assert_eq!(Invitation::normalize_target(KIND_EMAIL,"Some.One@Example.org").unwrap(),"some.one@example.org");
+        assert!(Invitation::normalize_target(KIND_EMAIL,"some one@example.org").is_err());
+        assert!(Invitation::normalize_target(KIND_EMAIL,"someone").is_err());
+        assert!(Invitation::normalize_target(KIND_LINK,"anything").is_err());
+    }
+}
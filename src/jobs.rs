@@ -0,0 +1,75 @@
+use std::{sync::Arc, time::Duration};
+use crate::app_state::AppState;
+use crate::auto_grant;
+use crate::error::RingError;
+use crate::notification::notify_access_escalated;
+
+/// Starts all configured periodic background jobs
+pub fn start(state: Arc<AppState>) {
+    if let Some(after_hours) = state.config["escalation"]["after_hours"].as_u64() {
+        let interval = state.config["escalation"]["check_interval_sec"].as_u64().unwrap_or(3600);
+        spawn_periodic(state.clone(), interval, move |state| async move {
+            escalate_access_requests(&state, after_hours).await
+        });
+    }
+    let interval = state.config["sessions"]["purge_interval_sec"].as_u64().unwrap_or(3600);
+    spawn_periodic(state.clone(), interval, |state| async move {
+        purge_sessions(&state).await
+    });
+    let interval = state.config["api_tokens"]["purge_interval_sec"].as_u64().unwrap_or(3600);
+    spawn_periodic(state.clone(), interval, |state| async move {
+        purge_api_tokens(&state).await
+    });
+    let interval = state.config["auto_grant"]["check_interval_sec"].as_u64().unwrap_or(3600);
+    spawn_periodic(state.clone(), interval, |state| async move {
+        auto_grant::apply_to_all_users(&state).await
+    });
+}
+
+fn spawn_periodic<F, Fut>(state: Arc<AppState>, interval_sec: u64, job: F)
+where
+    F: Fn(Arc<AppState>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<(),RingError>> + Send,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_sec.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = job(state.clone()).await {
+                tracing::error!("Periodic job failed: {e}");
+            }
+        }
+    });
+}
+
+/// Escalates access requests that have been pending for too long to the admins of all ancestor entities
+async fn escalate_access_requests(state: &Arc<AppState>, after_hours: u64) -> Result<(),RingError> {
+    let requests = state.dal.read().await.get_access_requests_to_escalate(after_hours).await?;
+    for request in requests {
+        let ancestor_ids = state.dal.read().await.get_entity_ancestors(request.entity_id).await?;
+        state.dal.write().await.escalate_access_request(request.id,&ancestor_ids).await?;
+        tracing::info!("Escalated access request #{} on entity #{} to {ancestor_ids:?}",request.id,request.entity_id);
+        if let Err(e) = notify_access_escalated(state,&request,&ancestor_ids).await {
+            tracing::warn!("Could not send escalation notification: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Removes expired and idle sessions
+async fn purge_sessions(state: &Arc<AppState>) -> Result<(),RingError> {
+    let removed = state.dal.read().await.session_store.purge_expired().await?;
+    if removed>0 {
+        tracing::info!("Purged {removed} expired sessions");
+    }
+    Ok(())
+}
+
+/// Removes expired API tokens, including those issued to service accounts
+async fn purge_api_tokens(state: &Arc<AppState>) -> Result<(),RingError> {
+    let removed = state.dal.read().await.purge_expired_api_tokens().await?;
+    if removed>0 {
+        tracing::info!("Purged {removed} expired API tokens");
+    }
+    Ok(())
+}
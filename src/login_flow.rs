@@ -0,0 +1,251 @@
+use std::{collections::HashMap, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use async_session::{Session, SessionStore};
+use axum::TypedHeader;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{header::SET_COOKIE, HeaderMap, HeaderValue};
+use oauth2::{CsrfToken, PkceCodeChallenge};
+use serde::{Serialize, Deserialize};
+use crate::app_state::AppState;
+use crate::error::RingError;
+use crate::external_system::{ExternalSystemUser, session_from_cookies};
+
+/// The pending login is kept in its own cookie, so anonymous login attempts do not create sessions
+static LOGIN_COOKIE: &str = "LOGIN";
+static LINK_KEY: &str = "link_principal";
+/// Logins that are not completed within this time are rejected
+static LOGIN_TIMEOUT_SEC: u64 = 600;
+/// A link request has to be followed by a login right away
+static LINK_TIMEOUT_SEC: u64 = 60;
+
+/// An OAuth login that was started, but not completed yet. Stored in the login cookie, which is only sent to the callbacks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub system: String,
+    pub state: String,
+    pub pkce_verifier: Option<String>, // None if PKCE is disabled for this system
+    pub return_to: String,
+    pub link_principal: Option<usize>, // Link the new identity to this user, instead of logging in
+    pub started: u64,
+}
+
+impl PendingLogin {
+    /// Returns the PKCE parameter to add to the token request, if any
+    pub fn token_params(&self) -> Vec<(&str,&str)> {
+        match &self.pkce_verifier {
+            Some(verifier) => vec![("code_verifier",verifier.as_str())],
+            None => vec![],
+        }
+    }
+}
+
+/// A request to link the next login to the logged-in user, see `request_link`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LinkRequest {
+    principal_id: usize,
+    expires: u64,
+}
+
+/// Parameters to add to the authorization URL of the external system
+#[derive(Clone, Debug)]
+pub struct LoginFlow {
+    pub state: String,
+    pub pkce_challenge: Option<String>,
+}
+
+impl LoginFlow {
+    /// Returns the OAuth parameters to add to an authorization URL
+    pub fn params(&self) -> Vec<(&str,&str)> {
+        let mut ret = vec![("state",self.state.as_str())];
+        if let Some(challenge) = &self.pkce_challenge {
+            ret.push(("code_challenge",challenge.as_str()));
+            ret.push(("code_challenge_method","S256"));
+        }
+        ret
+    }
+}
+
+/// Starts a login via an external system: stores state and PKCE verifier (if `pkce` is set) in the login cookie.
+/// Returns the headers to set that cookie.
+pub async fn begin(app: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>, system: &str, return_to: Option<&String>, pkce: bool) -> Result<(HeaderMap,LoginFlow),RingError> {
+    // A link request only applies to the login that directly follows it
+    let mut link_principal = None;
+    if let Some(mut session) = session_from_cookies(app, cookies).await {
+        if let Some(link) = session.get::<LinkRequest>(LINK_KEY) {
+            link_principal = Some(link.principal_id).filter(|_|link.expires>now());
+            session.remove(LINK_KEY);
+            app.dal.read().await.session_store.store_session(session).await
+                .map_err(|e|RingError::String(e.to_string()))?;
+        }
+    }
+    let (pkce_challenge,pkce_verifier) = match pkce {
+        true => {
+            let (challenge,verifier) = PkceCodeChallenge::new_random_sha256();
+            (Some(challenge.as_str().to_string()),Some(verifier.secret().to_owned()))
+        }
+        false => (None,None),
+    };
+    let pending = PendingLogin {
+        system: system.to_string(),
+        state: CsrfToken::new_random().secret().to_owned(),
+        pkce_verifier,
+        return_to: return_to.and_then(|url|safe_return_to(url)).unwrap_or_else(||"/".to_string()),
+        link_principal,
+        started: now(),
+    };
+    let value = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&pending)?);
+    let mut headers = HeaderMap::new();
+    headers.insert(SET_COOKIE, login_cookie(&value,LOGIN_TIMEOUT_SEC)?);
+    let flow = LoginFlow {
+        state: pending.state,
+        pkce_challenge,
+    };
+    Ok((headers,flow))
+}
+
+/// Completes a login via an external system: checks the `state` parameter against the login cookie.
+/// Returns the pending login, with the PKCE verifier for the token request. The cookie is removed by `clear_login_cookie`.
+pub async fn finish(app: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>, system: &str, params: &HashMap<String,String>) -> Result<PendingLogin,RingError> {
+    let value = cookies.as_ref()
+        .and_then(|TypedHeader(cookies)|cookies.get(LOGIN_COOKIE))
+        .ok_or_else(||RingError::Validation("No login in progress".into()))?;
+    let pending = check_pending(value,system,params,now())?;
+    if pending.link_principal.is_some() {
+        let current_user_id = ExternalSystemUser::from_cookies(app,cookies).await.and_then(|user|user.id);
+        let current_principal_id = match current_user_id {
+            Some(user_id) => Some(app.dal.read().await.get_principal_id(user_id as usize).await?),
+            None => None,
+        };
+        check_link(&pending,current_principal_id)?;
+    }
+    Ok(pending)
+}
+
+/// Decodes the login cookie, and checks it against the system and `state` parameter of the callback, and the login timeout
+fn check_pending(value: &str, system: &str, params: &HashMap<String,String>, now: u64) -> Result<PendingLogin,RingError> {
+    let mut pending: PendingLogin = URL_SAFE_NO_PAD.decode(value).ok()
+        .and_then(|json|serde_json::from_slice(&json).ok())
+        .ok_or_else(||RingError::Validation("Invalid login cookie".into()))?;
+    if pending.system!=system || params.get("state")!=Some(&pending.state) {
+        return Err(RingError::Validation("OAuth state mismatch".into()));
+    }
+    if pending.started+LOGIN_TIMEOUT_SEC<now {
+        return Err(RingError::Validation("The login has timed out, please try again".into()));
+    }
+    // The cookie is not signed, so it is only trusted as far as it can be checked
+    pending.return_to = safe_return_to(&pending.return_to).unwrap_or_else(||"/".to_string());
+    Ok(pending)
+}
+
+/// A login that links a new identity has to be completed by the user who requested the link
+fn check_link(pending: &PendingLogin, current_principal_id: Option<usize>) -> Result<(),RingError> {
+    match pending.link_principal {
+        Some(principal_id) if current_principal_id!=Some(principal_id) => Err(RingError::Validation("Identities can only be linked to the logged-in user".into())),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the login cookie, so a login attempt can only be completed once; added to all callback responses, including failures
+pub fn clear_login_cookie(headers: &mut HeaderMap) {
+    if let Ok(val) = login_cookie("",0) {
+        headers.append(SET_COOKIE, val);
+    }
+}
+
+fn login_cookie(value: &str, max_age_sec: u64) -> Result<HeaderValue,RingError> {
+    format!("{LOGIN_COOKIE}={value}; Max-Age={max_age_sec}; Secure; HttpOnly; SameSite=Lax; Path=/redirect")
+        .parse()
+        .map_err(|_e|RingError::String("Invalid login cookie".into()))
+}
+
+/// Marks the next login in this session as linking another identity to `principal_id`, instead of logging in
+pub async fn request_link(app: &Arc<AppState>, mut session: Session, principal_id: usize) -> Result<(),RingError> {
+    session.insert(LINK_KEY, LinkRequest { principal_id, expires: now()+LINK_TIMEOUT_SEC })?;
+    app.dal.read().await.session_store.store_session(session).await
+        .map_err(|e|RingError::String(e.to_string()))?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.as_secs()).unwrap_or_default()
+}
+
+/// Only allows local paths as post-login return URLs, to prevent open redirects
+pub fn safe_return_to(url: &str) -> Option<String> {
+    let is_safe = url.starts_with('/')
+        && !url.starts_with("//")
+        && !url.starts_with("/\\")
+        && !url.chars().any(|c|c.is_control());
+    match is_safe {
+        true => Some(url.to_string()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(link_principal: Option<usize>) -> PendingLogin {
+        PendingLogin {
+            system: "orcid".to_string(),
+            state: "the_state".to_string(),
+            pkce_verifier: None,
+            return_to: "/entity/1".to_string(),
+            link_principal,
+            started: 1000,
+        }
+    }
+
+    fn cookie_value(pending: &PendingLogin) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(pending).unwrap())
+    }
+
+    fn state_params(state: &str) -> HashMap<String,String> {
+        HashMap::from([("state".to_string(),state.to_string())])
+    }
+
+    #[test]
+    fn return_to_must_be_a_local_path() {
+        assert_eq!(safe_return_to("/entity/1?x=2").as_deref(),Some("/entity/1?x=2"));
+        assert_eq!(safe_return_to("//evil.example.org"),None);
+        assert_eq!(safe_return_to("/\\evil.example.org"),None);
+        assert_eq!(safe_return_to("https://evil.example.org/"),None);
+        assert_eq!(safe_return_to("javascript:alert(1)"),None);
+        assert_eq!(safe_return_to("/\t/evil.example.org"),None);
+        assert_eq!(safe_return_to("/x\r\nSet-Cookie: a=b"),None);
+        assert_eq!(safe_return_to(""),None);
+    }
+
+    #[test]
+    fn callback_must_match_the_pending_login() {
+        let value = cookie_value(&pending(None));
+        assert!(check_pending(&value,"orcid",&state_params("the_state"),1000).is_ok());
+        assert!(check_pending(&value,"orcid",&state_params("another_state"),1000).is_err());
+        assert!(check_pending(&value,"orcid",&HashMap::new(),1000).is_err());
+        assert!(check_pending(&value,"github",&state_params("the_state"),1000).is_err());
+        assert!(check_pending("not a cookie","orcid",&state_params("the_state"),1000).is_err());
+    }
+
+    #[test]
+    fn pending_logins_time_out() {
+        let value = cookie_value(&pending(None));
+        assert!(check_pending(&value,"orcid",&state_params("the_state"),1000+LOGIN_TIMEOUT_SEC).is_ok());
+        assert!(check_pending(&value,"orcid",&state_params("the_state"),1001+LOGIN_TIMEOUT_SEC).is_err());
+    }
+
+    #[test]
+    fn return_to_from_the_cookie_is_checked_again() {
+        let mut login = pending(None);
+        login.return_to = "//evil.example.org".to_string();
+        let pending = check_pending(&cookie_value(&login),"orcid",&state_params("the_state"),1000).unwrap();
+        assert_eq!(pending.return_to,"/");
+    }
+
+    #[test]
+    fn links_are_only_completed_by_the_requesting_user() {
+        assert!(check_link(&pending(None),None).is_ok());
+        assert!(check_link(&pending(Some(5)),Some(5)).is_ok());
+        assert!(check_link(&pending(Some(5)),Some(6)).is_err());
+        assert!(check_link(&pending(Some(5)),None).is_err());
+    }
+}
@@ -2,89 +2,1047 @@ use std::{net::SocketAddr, sync::Arc, collections::HashMap, path::PathBuf, env};
 use async_session::SessionStore;
 use axum_server::tls_rustls::RustlsConfig;
 use entity::{Entity, EntityGroup};
+use serde::Deserialize;
 use serde_json::{Value, json};
 use google_oauth::AsyncClient;
 use axum::{
-    routing::get,
+    routing::{get, post, put, patch, delete},
     Router,
     http::StatusCode,
-    extract::{State,Query, Path}, response::{Redirect, IntoResponse}, TypedHeader, Json,
+    extract::{State, RawQuery}, response::{Redirect, IntoResponse, Response}, TypedHeader, Json,
+    middleware,
 };
-use http::{header::{ACCEPT, CONTENT_TYPE, SET_COOKIE}, HeaderMap};
+use http::{header::{ACCEPT, LINK, SET_COOKIE, USER_AGENT}, HeaderMap, HeaderValue};
 use tower_http::{services::ServeDir, trace::TraceLayer, compression::CompressionLayer};
 use crate::error::RingError;
 use crate::app_state::AppState;
 use crate::external_system::*;
+use crate::notification::{notify_access_requested, notify_access_decided, notify_invited};
+use crate::api_token::{ApiToken, Credentials, random_string};
+use crate::extract::{JsonBody, OAuthForm, Path, Query};
 
 pub mod error;
+pub mod extract;
 pub mod db_tables;
 pub mod app_state;
 pub mod database_session_store;
 pub mod database_abstraction_layer;
 pub mod external_system;
 pub mod entity;
+pub mod notification;
+pub mod jobs;
+pub mod oidc;
+pub mod api_token;
+pub mod login_flow;
+pub mod idp;
+pub mod invitation;
+pub mod auto_grant;
 
 
-async fn redirect_to_orcid(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+type LoginRedirect = Result<(HeaderMap,Redirect),RingError>;
+
+static IDP_CONSENT_KEY: &str = "idp_consent";
+
+/// Starts a login flow for an external system; see `login_flow::begin`. PKCE is used unless `systems.<system>.pkce` is false.
+async fn begin_login(state: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>, system: &str, params: &HashMap<String, String>) -> Result<(HeaderMap,login_flow::LoginFlow),RingError> {
+    let pkce = state.config["systems"][system]["pkce"].as_bool().unwrap_or(true);
+    login_flow::begin(state,cookies,system,params.get("return_to"),pkce).await
+}
+
+/// Checks the state of a login flow for an external system; see `login_flow::finish`
+async fn finish_login(state: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>, system: &str, params: &HashMap<String, String>) -> Result<login_flow::PendingLogin,RingError> {
+    login_flow::finish(state,cookies,system,params).await
+        .map_err(|e| {
+            tracing::warn!("Rejected {system} login: {e}");
+            e
+        })
+}
+
+async fn redirect_to_orcid(State(state): State<Arc<AppState>>, Query(params): Query<HashMap<String, String>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> LoginRedirect {
     let redirect_url = format!("{}/redirect/orcid",state.get_redirect_server());
     let client_id = match state.config["systems"]["orcid"]["client_id"].as_str() {
         Some(id) => id,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        None => return Err(RingError::NotFound("Login via ORCID is not configured".into())),
     };
-    let url = format!("https://orcid.org/oauth/authorize?client_id={client_id}&response_type=code&scope=/authenticate&redirect_uri={redirect_url}");
-    Ok(Redirect::to(&url))
+    let (headers,flow) = begin_login(&state,&cookies,"orcid",&params).await?;
+    let mut url_params = vec![
+        ("client_id",client_id),
+        ("response_type","code"),
+        ("scope","/authenticate"),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    url_params.append(&mut flow.params());
+    let url = reqwest::Url::parse_with_params("https://orcid.org/oauth/authorize", &url_params)
+        .map_err(|e|RingError::String(e.to_string()))?;
+    Ok((headers,Redirect::to(url.as_str())))
 }
 
-async fn redirect_to_google(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+async fn redirect_to_google(State(state): State<Arc<AppState>>, Query(params): Query<HashMap<String, String>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> LoginRedirect {
     let redirect_url = format!("{}/redirect/google",state.get_redirect_server());
     let client_id = match state.config["systems"]["google"]["client_id"].as_str() {
         Some(id) => id,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        None => return Err(RingError::NotFound("Login via Google is not configured".into())),
+    };
+    let (headers,flow) = begin_login(&state,&cookies,"google",&params).await?;
+    let mut url_params = vec![
+        ("client_id",client_id),
+        ("response_type","code"),
+        ("scope","https://www.googleapis.com/auth/userinfo.profile openid email"),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    url_params.append(&mut flow.params());
+    let url = reqwest::Url::parse_with_params("https://accounts.google.com/o/oauth2/v2/auth", &url_params)
+        .map_err(|e|RingError::String(e.to_string()))?;
+    Ok((headers,Redirect::to(url.as_str())))
+}
+
+/// Returns a string setting of a login system from the config file
+fn system_config<'a>(state: &'a AppState, system: &str, key: &str) -> Result<&'a str,RingError> {
+    state.config["systems"][system][key].as_str()
+        .ok_or_else(||RingError::String(format!("'systems.{system}.{key}' is missing in the config file")))
+}
+
+async fn redirect_to_wikimedia(State(state): State<Arc<AppState>>, Query(params): Query<HashMap<String, String>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> LoginRedirect {
+    let redirect_url = format!("{}/redirect/wikimedia",state.get_redirect_server());
+    let client_id = match state.config["systems"]["wikimedia"]["client_id"].as_str() {
+        Some(id) => id,
+        None => return Err(RingError::NotFound("Login via Wikimedia is not configured".into())),
+    };
+    let (headers,flow) = begin_login(&state,&cookies,"wikimedia",&params).await?;
+    let url = format!("{}/w/rest.php/oauth2/authorize",state.wikimedia_base_url());
+    let mut url_params = vec![
+        ("client_id",client_id),
+        ("response_type","code"),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    url_params.append(&mut flow.params());
+    let url = reqwest::Url::parse_with_params(&url, &url_params).map_err(|e|RingError::String(e.to_string()))?;
+    Ok((headers,Redirect::to(url.as_str())))
+}
+
+async fn redirect_to_github(State(state): State<Arc<AppState>>, Query(params): Query<HashMap<String, String>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> LoginRedirect {
+    let redirect_url = format!("{}/redirect/github",state.get_redirect_server());
+    let client_id = match state.config["systems"]["github"]["client_id"].as_str() {
+        Some(id) => id,
+        None => return Err(RingError::NotFound("Login via GitHub is not configured".into())),
     };
-    let scope = "https://www.googleapis.com/auth/userinfo.profile openid email";
-    let url = format!("https://accounts.google.com/o/oauth2/v2/auth?client_id={client_id}&response_type=code&scope={scope}&redirect_uri={redirect_url}");
+    let (headers,flow) = begin_login(&state,&cookies,"github",&params).await?;
+    let mut url_params = vec![
+        ("client_id",client_id),
+        ("scope","read:user user:email read:org"),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    url_params.append(&mut flow.params());
+    let url = reqwest::Url::parse_with_params("https://github.com/login/oauth/authorize", &url_params)
+        .map_err(|e|RingError::String(e.to_string()))?;
+    Ok((headers,Redirect::to(url.as_str())))
+}
+
+async fn redirect_to_oidc(State(state): State<Arc<AppState>>, Path(key): Path<String>, Query(params): Query<HashMap<String, String>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> LoginRedirect {
+    let provider = state.oidc_providers.get(&key).ok_or_else(||RingError::NotFound(format!("No login system '{key}'")))?;
+    let redirect_url = format!("{}/redirect/oidc/{key}",state.get_redirect_server());
+    let (headers,flow) = begin_login(&state,&cookies,&key,&params).await?;
+    let url = provider.authorize_url(&redirect_url,&flow).await?;
+    Ok((headers,Redirect::to(&url)))
+}
+
+/// Lists the configured login systems
+async fn auth_systems(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let j = json!({"status":"OK","systems":login_systems(&state)});
+    (StatusCode::OK, Json(j))
+}
+
+fn login_systems(state: &AppState) -> Vec<Value> {
+    let mut systems = vec![];
+    if state.config["systems"]["orcid"]["client_id"].is_string() {
+        systems.push(json!({"key":"orcid","label":"ORCID","url":"/redirect_to/orcid"}));
+    }
+    if state.config["systems"]["google"]["client_id"].is_string() {
+        systems.push(json!({"key":"google","label":"Google","url":"/redirect_to/google"}));
+    }
+    if state.config["systems"]["wikimedia"]["client_id"].is_string() {
+        systems.push(json!({"key":"wikimedia","label":"Wikimedia","url":"/redirect_to/wikimedia"}));
+    }
+    if state.config["systems"]["github"]["client_id"].is_string() {
+        systems.push(json!({"key":"github","label":"GitHub","url":"/redirect_to/github"}));
+    }
+    let mut providers: Vec<_> = state.oidc_providers.values().collect();
+    providers.sort_by(|a,b|a.key.cmp(&b.key));
+    for provider in providers {
+        systems.push(json!({"key":provider.key,"label":provider.label,"url":format!("/redirect_to/oidc/{}",provider.key)}));
+    }
+    systems
+}
+
+/// Starts linking another external identity to the logged-in user, by logging in via that system
+async fn user_link(State(state): State<Arc<AppState>>, Path(key): Path<String>, cookies: Option<TypedHeader<headers::Cookie>>,) -> Result<Redirect,RingError> {
+    let credentials = Credentials { cookies: cookies.clone(), bearer: None };
+    let principal_id = get_current_user_id(&state,&credentials).await?;
+    let url = login_systems(&state)
+        .into_iter()
+        .find(|system|system["key"].as_str()==Some(&key))
+        .and_then(|system|system["url"].as_str().map(|s|s.to_string()))
+        .ok_or_else(||RingError::NotFound(format!("No login system '{key}'")))?;
+    let session = session_from_cookies(&state,&cookies).await.ok_or_else(||RingError::Unauthenticated("No session".into()))?;
+    login_flow::request_link(&state,session,principal_id).await?;
     Ok(Redirect::to(&url))
 }
 
+async fn user_identities(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    let principal_id = match get_current_user_id(&state,&credentials).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let users = match state.dal.read().await.get_linked_users(principal_id).await {
+        Ok(users) => users,
+        Err(e) => return e.json_response()
+    };
+    let identities: Vec<Value> = users.into_iter().map(|user|state.public_user_json(user)).collect();
+    let j = json!({"status":"OK","principal_id":principal_id,"identities":identities});
+    (StatusCode::OK, Json(j))
+}
 
-async fn auth_info(State(state): State<Arc<AppState>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let user = ExternalSystemUser::from_cookies(&state, &cookies).await;
-    let j = json!({"status":"OK","user":user});
+async fn user_tokens(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    let current_user_id = match get_current_user_id(&state,&credentials).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let tokens = match state.dal.read().await.get_api_tokens(current_user_id).await {
+        Ok(tokens) => tokens,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","tokens":tokens});
     (StatusCode::OK, Json(j))
 }
 
-async fn user_logout(State(state): State<Arc<AppState>>, cookies: TypedHeader<headers::Cookie>,) -> impl IntoResponse {
-    let cookie = cookies.get(COOKIE_NAME).unwrap().to_string();
-    if let Some(session) = state.dal.read().await.session_store.load_session(cookie).await.unwrap() {
-        state.dal.read().await.session_store.destroy_session(session).await.unwrap();
+/// Creates a personal API token, optionally restricted via `rights` and `entity_ids` (subtrees) query parameters.
+/// The token is only returned here, and only its hash is stored.
+async fn create_user_token(State(state): State<Arc<AppState>>, Path((name,days)): Path<(String,u64)>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    if credentials.bearer.is_some() {
+        return RingError::Forbidden("API tokens can only be created from a logged-in session".into()).json_response()
+    }
+    let current_user_id = match get_current_user_id(&state,&credentials).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let max_days = state.config["api_tokens"]["max_days"].as_u64().unwrap_or(365);
+    if days==0 || days>max_days {
+        return RingError::Validation(format!("API tokens must expire within 1 to {max_days} days")).json_response()
+    }
+    let rights = parse_rights_string(params.get("rights").map(|s|s.as_str()).unwrap_or_default());
+    let entity_ids = match parse_token_entity_ids(params.get("entity_ids").map(|s|s.as_str()).unwrap_or_default()) {
+        Ok(entity_ids) => entity_ids,
+        Err(e) => return e.json_response()
+    };
+    if let Err(e) = ApiToken::validate_scope(&rights,&entity_ids) {
+        return e.json_response()
+    }
+    let (token,token_hash) = ApiToken::generate();
+    let token_id = match state.dal.write().await.add_api_token(current_user_id,&name,&token_hash,&rights,&entity_ids,days*24*3600).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","id":token_id,"token":token});
+    (StatusCode::OK, Json(j))
+}
+
+async fn revoke_user_token(State(state): State<Arc<AppState>>, Path(token_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    let (current_user_id,token) = match get_current_user(&state,&credentials).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    // A restricted token must not be able to remove the other tokens of the user
+    if !is_unrestricted_token(&token) {
+        return RingError::Forbidden("Revoking API tokens requires a logged-in session or an unrestricted API token".into()).json_response()
+    }
+    match state.dal.write().await.revoke_api_token(current_user_id,token_id).await {
+        Ok(true) => {},
+        Ok(false) => return RingError::NotFound("No such API token".into()).json_response(),
+        Err(e) => return e.json_response(),
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// Parses the comma-separated entity IDs an API token is restricted to; invalid IDs are rejected rather than ignored
+fn parse_token_entity_ids(entity_ids: &str) -> Result<Vec<usize>,RingError> {
+    entity_ids.split(',')
+        .map(|id|id.trim())
+        .filter(|id|!id.is_empty())
+        .map(|id|id.parse::<usize>().map_err(|_|RingError::Validation(format!("Invalid entity ID '{id}'"))))
+        .collect()
+}
+
+/// Creates a service account owned by an entity. The client secret is only returned here.
+async fn create_service_account(State(state): State<Arc<AppState>>, Path((entity_id,name)): Path<(usize,String)>, credentials: Credentials,) -> impl IntoResponse {
+    let action = format!("create service account '{name}'");
+    let (current_user_id,_entity_ids) = match user_rights_prep(&state,entity_id.to_string(),&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let client_id = random_string("svc_",8);
+    let client_secret = random_string("",32);
+    let user_id = match state.dal.write().await.add_service_account(entity_id,&name,&client_id,&ApiToken::hash(&client_secret),current_user_id).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","user_id":user_id,"client_id":client_id,"client_secret":client_secret});
+    (StatusCode::OK, Json(j))
+}
+
+async fn list_service_accounts(State(state): State<Arc<AppState>>, Path(entity_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    if let Err(e) = user_rights_prep(&state,entity_id.to_string(),&credentials,"list service accounts").await {
+        return e.json_response()
+    }
+    let user_ids = match state.dal.read().await.get_service_accounts(entity_id).await {
+        Ok(ids) => ids,
+        Err(e) => return e.json_response()
+    };
+    let mut users = vec![];
+    for user_id in user_ids {
+        let user = match state.dal.read().await.get_user(user_id).await {
+            Ok(user) => user,
+            Err(e) => return e.json_response(),
+        };
+        users.push(state.public_user_json(user));
+    }
+    let j = json!({"status":"OK","service_accounts":users});
+    (StatusCode::OK, Json(j))
+}
+
+/// Checks that the current user has admin rights on the entity owning a service account. Returns the current user ID.
+async fn check_service_account_admin(state: &Arc<AppState>, user_id: usize, credentials: &Credentials, action: &str) -> Result<usize,RingError> {
+    let entity_id = state.dal.read().await.get_service_account_entity(user_id).await?
+        .ok_or_else(||RingError::NotFound("No such service account".into()))?;
+    let action = format!("{action} of service account #{user_id}");
+    let (current_user_id,_entity_ids) = user_rights_prep(state,entity_id.to_string(),credentials,&action).await?;
+    Ok(current_user_id)
+}
+
+/// Replaces the client secret of a service account, revoking its API tokens; requires admin rights on the owning entity
+async fn reset_service_account_secret(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    if let Err(e) = check_service_account_admin(&state,user_id,&credentials,"reset secret").await {
+        return e.json_response()
+    }
+    let client_secret = random_string("",32);
+    if let Err(e) = state.dal.write().await.set_service_account_secret(user_id,&ApiToken::hash(&client_secret)).await {
+        return e.json_response()
+    }
+    let j = json!({"status":"OK","client_secret":client_secret});
+    (StatusCode::OK, Json(j))
+}
+
+/// Deactivates or reactivates a service account; requires admin rights on the owning entity. Deactivation revokes its API tokens.
+async fn set_service_account_deactivated(State(state): State<Arc<AppState>>, Path((user_id,deactivated)): Path<(usize,bool)>, credentials: Credentials,) -> impl IntoResponse {
+    let action = if deactivated { "deactivate" } else { "reactivate" };
+    let current_user_id = match check_service_account_admin(&state,user_id,&credentials,action).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    if let Err(e) = state.dal.write().await.set_service_account_deactivated(user_id,deactivated).await {
+        return e.json_response()
+    }
+    tracing::info!("User #{current_user_id}: {action} service account #{user_id}");
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// Deletes a service account with its rights and API tokens; requires admin rights on the owning entity
+async fn delete_service_account(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    let current_user_id = match check_service_account_admin(&state,user_id,&credentials,"delete").await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    if let Err(e) = state.dal.write().await.remove_service_account(user_id).await {
+        return e.json_response()
+    }
+    tracing::info!("User #{current_user_id} deleted service account #{user_id}");
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// OAuth 2.0 client credentials grant for service accounts; returns a short-lived API token.
+/// Credentials are accepted as form fields or via HTTP Basic authentication.
+async fn service_account_token(
+    State(state): State<Arc<AppState>>,
+    basic: Option<TypedHeader<headers::Authorization<headers::authorization::Basic>>>,
+    OAuthForm(params): OAuthForm<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if params.get("grant_type").map(|s|s.as_str())!=Some("client_credentials") {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error":"unsupported_grant_type"})))
+    }
+    let (client_id,client_secret) = match (&basic,params.get("client_id"),params.get("client_secret")) {
+        (Some(TypedHeader(basic)),_,_) => (basic.username().to_string(),basic.password().to_string()),
+        (None,Some(id),Some(secret)) => (id.to_owned(),secret.to_owned()),
+        _ => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_client"}))),
+    };
+    let authenticated = state.dal.read().await.authenticate_service_account(&client_id,&ApiToken::hash(&client_secret)).await;
+    let user_id = match authenticated {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_client"}))),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    };
+    let deactivated = state.dal.read().await.is_user_deactivated(user_id).await;
+    match deactivated {
+        Ok(false) => {}
+        Ok(true) => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_client"}))),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    }
+    let ttl = state.config["service_accounts"]["token_ttl_sec"].as_u64().unwrap_or(3600);
+    let (token,token_hash) = ApiToken::generate();
+    if let Err(e) = state.dal.write().await.add_api_token(user_id,"client_credentials",&token_hash,&[],&[],ttl).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()})))
+    }
+    let j = json!({"access_token":token,"token_type":"Bearer","expires_in":ttl});
+    (StatusCode::OK, Json(j))
+}
+
+/// OpenID Connect discovery document, if sauron is configured as an identity provider
+async fn oidc_discovery(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match &state.idp {
+        Some(idp) => (StatusCode::OK, Json(idp.discovery())),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))),
+    }
+}
+
+/// The public key for IdP-issued tokens
+async fn oidc_jwks(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match &state.idp {
+        Some(idp) => (StatusCode::OK, Json(idp.jwks())),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))),
+    }
+}
+
+/// OpenID Connect authorization endpoint (authorization code flow).
+/// Users who are not logged in are sent to the login page first, and return here afterwards.
+async fn oidc_authorize(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
+    RawQuery(query): RawQuery,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+) -> Result<Redirect,RingError> {
+    let idp = state.idp.as_ref().ok_or_else(||RingError::NotFound("No identity provider configured".into()))?;
+    let client = params.get("client_id").and_then(|id|idp.client(id)).ok_or_else(||RingError::Validation("Unknown client_id".into()))?;
+    // Never redirect to a URI that is not registered for the client
+    let redirect_uri = params.get("redirect_uri")
+        .filter(|uri|client.redirect_uris.contains(uri))
+        .ok_or_else(||RingError::Validation("redirect_uri is not registered for this client".into()))?;
+    let mut url_params = vec![];
+    if let Some(oauth_state) = params.get("state") {
+        url_params.push(("state",oauth_state.to_owned()));
+    }
+
+    let scope = params.get("scope").cloned().unwrap_or_default();
+    let error = if params.get("response_type").map(|s|s.as_str())!=Some("code") {
+        Some("unsupported_response_type")
+    } else if !scope.split(' ').any(|s|s=="openid") || !client.allows_scope(&scope) {
+        Some("invalid_scope")
+    } else if params.contains_key("code_challenge") && params.get("code_challenge_method").map(|s|s.as_str())!=Some("S256") {
+        Some("invalid_request")
+    } else {
+        None
+    };
+    match error {
+        Some(error) => url_params.push(("error",error.to_string())),
+        None => {
+            let credentials = Credentials { cookies, bearer: None };
+            let user_id = match get_current_user_id(&state,&credentials).await {
+                Ok(user_id) => user_id,
+                Err(_) => {
+                    let return_to = format!("/oidc/authorize?{}",query.unwrap_or_default());
+                    let url = reqwest::Url::parse_with_params(&format!("{}/",state.get_redirect_server()), &[("return_to",return_to)])
+                        .map_err(|e|RingError::String(e.to_string()))?;
+                    return Ok(Redirect::to(url.as_str()));
+                }
+            };
+            if !client.trusted {
+                let consented = state.dal.read().await.get_idp_consent(user_id,&client.client_id).await?;
+                if !idp::scope_covers(&consented,&scope) {
+                    let token = request_idp_consent(&state,&credentials,client,&scope,query.unwrap_or_default()).await?;
+                    return Ok(Redirect::to(&format!("/#/consent/{token}")));
+                }
+            }
+            let code = idp::IdpCode {
+                client_id: client.client_id.to_owned(),
+                user_id,
+                redirect_uri: redirect_uri.to_owned(),
+                scope,
+                nonce: params.get("nonce").cloned(),
+                code_challenge: params.get("code_challenge").cloned(),
+            };
+            let code_string = random_string("",32);
+            state.dal.write().await.add_idp_code(&ApiToken::hash(&code_string),&code,idp.code_ttl).await
+                .map_err(|e|RingError::String(e.to_string()))?;
+            url_params.push(("code",code_string));
+        }
+    }
+    let url = reqwest::Url::parse_with_params(redirect_uri, &url_params)
+        .map_err(|e|RingError::Validation(e.to_string()))?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+/// Stores an authorization request in the session until the user consents to it on the consent page. Returns the consent token.
+async fn request_idp_consent(state: &Arc<AppState>, credentials: &Credentials, client: &idp::IdpClient, scope: &str, query: String) -> Result<String,RingError> {
+    let mut session = session_from_cookies(state,&credentials.cookies).await
+        .ok_or_else(||RingError::Unauthenticated("No session".into()))?;
+    let pending = idp::PendingConsent {
+        token: random_string("",16),
+        query,
+        client_id: client.client_id.to_owned(),
+        scope: scope.to_string(),
+        expires: idp::Idp::now()+600,
+    };
+    session.insert(IDP_CONSENT_KEY, &pending)?;
+    state.dal.read().await.session_store.store_session(session).await
+        .map_err(|e|RingError::String(e.to_string()))?;
+    Ok(pending.token)
+}
+
+/// Returns the pending consent request with that token from the session; `take` removes it
+async fn pending_idp_consent(state: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>, token: &str, take: bool) -> Result<idp::PendingConsent,RingError> {
+    let mut session = session_from_cookies(state,cookies).await
+        .ok_or_else(||RingError::Unauthenticated("No session".into()))?;
+    let pending: idp::PendingConsent = session.get(IDP_CONSENT_KEY)
+        .filter(|pending: &idp::PendingConsent|pending.token==token && pending.expires>idp::Idp::now())
+        .ok_or_else(||RingError::NotFound("No such consent request, or it has expired".into()))?;
+    if take {
+        session.remove(IDP_CONSENT_KEY);
+        state.dal.read().await.session_store.store_session(session).await
+            .map_err(|e|RingError::String(e.to_string()))?;
+    }
+    Ok(pending)
+}
+
+/// Describes a pending consent request, for the consent page
+async fn oidc_consent_info(State(state): State<Arc<AppState>>, Path(token): Path<String>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
+    let pending = match pending_idp_consent(&state,&cookies,&token,false).await {
+        Ok(pending) => pending,
+        Err(e) => return e.json_response()
+    };
+    let client_name = state.idp.as_ref()
+        .and_then(|idp|idp.client(&pending.client_id))
+        .map(|client|client.name.to_owned())
+        .unwrap_or(pending.client_id);
+    let scopes: Vec<&str> = pending.scope.split(' ').filter(|s|!s.is_empty()).collect();
+    let j = json!({"status":"OK","client":client_name,"scopes":scopes});
+    (StatusCode::OK, Json(j))
+}
+
+/// Records the decision of the logged-in user on a consent request. Returns the URL to continue with:
+/// the authorization request if the user agreed, or the client's redirect URI with an `access_denied` error.
+async fn oidc_consent(state: &Arc<AppState>, cookies: Option<TypedHeader<headers::Cookie>>, token: &str, allow: bool) -> Result<String,RingError> {
+    let idp = state.idp.as_ref().ok_or_else(||RingError::NotFound("No identity provider configured".into()))?;
+    let credentials = Credentials { cookies: cookies.clone(), bearer: None };
+    let user_id = get_current_user_id(state,&credentials).await?;
+    let pending = pending_idp_consent(state,&cookies,token,true).await?;
+    if allow {
+        let consented = state.dal.read().await.get_idp_consent(user_id,&pending.client_id).await?;
+        let mut scopes: Vec<&str> = consented.split(' ').chain(pending.scope.split(' ')).filter(|s|!s.is_empty()).collect();
+        scopes.sort();
+        scopes.dedup();
+        state.dal.write().await.set_idp_consent(user_id,&pending.client_id,&scopes.join(" ")).await?;
+        return Ok(format!("/oidc/authorize?{}",pending.query));
+    }
+    let params: HashMap<String,String> = reqwest::Url::parse(&format!("{}/?{}",state.get_redirect_server(),pending.query))
+        .map(|url|url.query_pairs().into_owned().collect())
+        .unwrap_or_default();
+    let client = idp.client(&pending.client_id).ok_or_else(||RingError::Validation("Unknown client_id".into()))?;
+    let redirect_uri = params.get("redirect_uri")
+        .filter(|uri|client.redirect_uris.contains(uri))
+        .ok_or_else(||RingError::Validation("redirect_uri is not registered for this client".into()))?;
+    let mut url_params = vec![("error","access_denied")];
+    if let Some(oauth_state) = params.get("state") {
+        url_params.push(("state",oauth_state));
+    }
+    let url = reqwest::Url::parse_with_params(redirect_uri, &url_params)
+        .map_err(|e|RingError::Validation(e.to_string()))?;
+    Ok(url.to_string())
+}
+
+/// OpenID Connect token endpoint; exchanges an authorization code for an ID token and an access token.
+/// Client credentials are accepted as form fields or via HTTP Basic authentication.
+async fn oidc_token(
+    State(state): State<Arc<AppState>>,
+    basic: Option<TypedHeader<headers::Authorization<headers::authorization::Basic>>>,
+    OAuthForm(params): OAuthForm<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let idp = match &state.idp {
+        Some(idp) => idp,
+        None => return (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))),
+    };
+    if params.get("grant_type").map(|s|s.as_str())!=Some("authorization_code") {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error":"unsupported_grant_type"})))
+    }
+    let client = match (&basic,params.get("client_id"),params.get("client_secret")) {
+        (Some(TypedHeader(basic)),_,_) => idp.authenticate_client(basic.username(),basic.password()),
+        (None,Some(id),Some(secret)) => idp.authenticate_client(id,secret),
+        _ => None,
+    };
+    let client = match client {
+        Some(client) => client,
+        None => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_client"}))),
+    };
+    let code_hash = ApiToken::hash(params.get("code").map(|s|s.as_str()).unwrap_or_default());
+    let code = match state.dal.write().await.take_idp_code(&code_hash).await {
+        Ok(code) => code,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    };
+    let code = match code {
+        Some(code) if code.client_id==client.client_id && params.get("redirect_uri")==Some(&code.redirect_uri) && code.check_verifier(params.get("code_verifier")) => code,
+        _ => return (StatusCode::BAD_REQUEST, Json(json!({"error":"invalid_grant"}))),
+    };
+
+    if state.dal.read().await.is_user_deactivated(code.user_id).await.unwrap_or(true) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error":"invalid_grant"})))
+    }
+    let claims = match idp::user_claims(&*state.dal.read().await,code.user_id,&code.scope,client).await {
+        Ok(claims) => claims,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    };
+    let now = idp::Idp::now();
+    let mut id_token = claims.to_owned();
+    id_token["iss"] = json!(idp.issuer);
+    id_token["aud"] = json!(client.client_id);
+    id_token["iat"] = json!(now);
+    id_token["exp"] = json!(now+idp.id_token_ttl);
+    if let Some(nonce) = &code.nonce {
+        id_token["nonce"] = json!(nonce);
+    }
+    let mut access_token = claims;
+    access_token["iss"] = json!(idp.issuer);
+    access_token["aud"] = json!(client.client_id);
+    access_token["client_id"] = json!(client.client_id);
+    access_token["scope"] = json!(code.scope);
+    access_token["iat"] = json!(now);
+    access_token["exp"] = json!(now+idp.access_token_ttl);
+    access_token["jti"] = json!(random_string("",16));
+    let tokens = idp.sign("JWT",&id_token).and_then(|id_token|Ok((id_token,idp.sign(idp::JWT_TYPE_ACCESS_TOKEN,&access_token)?)));
+    let (id_token,access_token) = match tokens {
+        Ok(tokens) => tokens,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    };
+    let j = json!({
+        "access_token":access_token,
+        "token_type":"Bearer",
+        "expires_in":idp.access_token_ttl,
+        "id_token":id_token,
+        "scope":code.scope,
+    });
+    (StatusCode::OK, Json(j))
+}
+
+/// OpenID Connect userinfo endpoint, for IdP-issued access tokens. Rights are current, not those at token issue.
+async fn oidc_userinfo(State(state): State<Arc<AppState>>, bearer: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,) -> impl IntoResponse {
+    let idp = match &state.idp {
+        Some(idp) => idp,
+        None => return (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))),
+    };
+    let claims = match bearer.and_then(|TypedHeader(auth)|idp.verify(idp::JWT_TYPE_ACCESS_TOKEN,auth.token()).ok()) {
+        Some(claims) => claims,
+        None => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_token"}))),
+    };
+    let user_id = claims["sub"].as_str().and_then(|s|s.parse::<usize>().ok());
+    let client = claims["client_id"].as_str().and_then(|id|idp.client(id));
+    let (user_id,client) = match (user_id,client) {
+        (Some(user_id),Some(client)) => (user_id,client),
+        _ => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_token"}))),
+    };
+    if state.dal.read().await.is_user_deactivated(user_id).await.unwrap_or(true) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_token"})))
+    }
+    let scope = claims["scope"].as_str().unwrap_or_default();
+    match idp::user_claims(&*state.dal.read().await,user_id,scope,client).await {
+        Ok(claims) => (StatusCode::OK, Json(claims)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    }
+}
+
+/// Returns the caller if the client credentials belong to a registered IdP client or an active service account
+async fn authenticate_introspection_client(state: &Arc<AppState>, client_id: &str, client_secret: &str) -> Result<Option<IntrospectionClient>,RingError> {
+    if let Some(client) = state.idp.as_ref().and_then(|idp|idp.authenticate_client(client_id,client_secret)) {
+        return Ok(Some(IntrospectionClient { client_id: client.client_id.to_owned(), service_account_id: None, any_token: client.introspect }));
+    }
+    let user_id = match state.dal.read().await.authenticate_service_account(client_id,&ApiToken::hash(client_secret)).await? {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+    if state.dal.read().await.is_user_deactivated(user_id).await? {
+        return Ok(None);
+    }
+    let any_token = state.config["service_accounts"]["introspection_clients"].as_array()
+        .map(|clients|clients.iter().any(|c|c.as_str()==Some(client_id)))
+        .unwrap_or(false);
+    Ok(Some(IntrospectionClient { client_id: client_id.to_string(), service_account_id: Some(user_id), any_token }))
+}
+
+/// An authenticated caller of the introspection endpoint
+struct IntrospectionClient {
+    client_id: String,
+    service_account_id: Option<usize>, // Set for service accounts
+    any_token: bool, // Explicitly allowed to introspect tokens it was not issued to
+}
+
+/// Describes an active token for introspection: subject, external system, and the token-scoped rights.
+/// The caller must be the client the token was issued to (the IdP client, or the service account owning the API token), unless it is allowed to introspect any token.
+/// Rights are limited to the entities configured for the client (IdP tokens) or to the subtrees the API token is restricted to;
+/// `entity_ids`, if given, can only narrow these down.
+async fn introspect_token(state: &Arc<AppState>, caller: &IntrospectionClient, token: &str, entity_ids: Option<Vec<usize>>) -> Result<Option<Value>,RingError> {
+    let (user_id,default_entity_ids,api_token,mut j) = if token.starts_with(api_token::TOKEN_PREFIX) {
+        // Introspection is not a use of the token, so `last_used` is left alone
+        let api_token = match state.dal.read().await.peek_api_token(&ApiToken::hash(token)).await? {
+            Some(api_token) => api_token,
+            None => return Ok(None),
+        };
+        if !caller.any_token && caller.service_account_id!=Some(api_token.user_id) {
+            return Ok(None);
+        }
+        let j = json!({
+            "token_type":"Bearer",
+            "client_id":format!("api_token:{}",api_token.id),
+            "scope":api_token.rights.join(" "),
+        });
+        (api_token.user_id,api_token.entity_ids.to_owned(),Some(api_token),j)
+    } else {
+        let idp = match &state.idp {
+            Some(idp) => idp,
+            None => return Ok(None),
+        };
+        let claims = match idp.verify(idp::JWT_TYPE_ACCESS_TOKEN,token) {
+            Ok(claims) => claims,
+            Err(_) => return Ok(None),
+        };
+        let user_id = claims["sub"].as_str().and_then(|s|s.parse::<usize>().ok());
+        let client = claims["client_id"].as_str().and_then(|id|idp.client(id));
+        let (user_id,client) = match (user_id,client) {
+            (Some(user_id),Some(client)) => (user_id,client),
+            _ => return Ok(None),
+        };
+        let issued_to_caller = caller.service_account_id.is_none() && caller.client_id==client.client_id;
+        if !caller.any_token && !issued_to_caller {
+            return Ok(None);
+        }
+        let scope = claims["scope"].as_str().unwrap_or_default();
+        let entity_ids = match scope.split(' ').any(|s|s==idp::SCOPE_RIGHTS) {
+            true => client.entity_ids.to_owned(),
+            false => vec![], // Rights were not requested by the client
+        };
+        let j = json!({
+            "token_type":"Bearer",
+            "client_id":client.client_id,
+            "scope":scope,
+            "exp":claims["exp"],
+            "iat":claims["iat"],
+            "iss":claims["iss"],
+            "aud":claims["aud"],
+            "jti":claims["jti"],
+        });
+        (user_id,entity_ids,None,j)
+    };
+    if state.dal.read().await.is_user_deactivated(user_id).await? {
+        return Ok(None);
+    }
+    let entity_ids = match (entity_ids,&api_token) {
+        (None,_) => default_entity_ids,
+        // API tokens are checked against their subtrees in `user_entity_rights_for`
+        (Some(entity_ids),Some(_)) => entity_ids,
+        (Some(entity_ids),None) => entity_ids.into_iter().filter(|id|default_entity_ids.contains(id)).collect(),
+    };
+    let user = state.dal.read().await.get_user(user_id).await?;
+    let principal_id = state.dal.read().await.get_principal_id(user_id).await?;
+    let entities = user_entity_rights_for(state,user_id,&api_token,&entity_ids).await?;
+    let rights: HashMap<String,Vec<String>> = entities.iter().map(|e|(e.id.to_string(),e.rights.to_owned())).collect();
+    j["active"] = json!(true);
+    j["sub"] = json!(principal_id.to_string());
+    j["username"] = json!(user.name);
+    j["system"] = json!(user.system.as_str());
+    j["external_id"] = json!(user.external_id);
+    j["rights"] = json!(rights);
+    Ok(Some(j))
+}
+
+/// OAuth 2.0 token introspection (RFC 7662) for IdP access tokens and API tokens.
+/// Callers authenticate with the credentials of an IdP client or a service account, as form fields or via HTTP Basic authentication.
+/// An optional comma-separated `entity_ids` field narrows down the entities to report rights on.
+/// Tokens the caller may not introspect are reported as inactive.
+async fn oauth_introspect(
+    State(state): State<Arc<AppState>>,
+    basic: Option<TypedHeader<headers::Authorization<headers::authorization::Basic>>>,
+    OAuthForm(params): OAuthForm<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let (client_id,client_secret) = match (&basic,params.get("client_id"),params.get("client_secret")) {
+        (Some(TypedHeader(basic)),_,_) => (basic.username().to_string(),basic.password().to_string()),
+        (None,Some(id),Some(secret)) => (id.to_owned(),secret.to_owned()),
+        _ => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_client"}))),
+    };
+    let caller = match authenticate_introspection_client(&state,&client_id,&client_secret).await {
+        Ok(Some(caller)) => caller,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, Json(json!({"error":"invalid_client"}))),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    };
+    let token = match params.get("token") {
+        Some(token) => token,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({"error":"invalid_request"}))),
+    };
+    let entity_ids = match params.get("entity_ids").map(|ids|parse_token_entity_ids(ids)).transpose() {
+        Ok(entity_ids) => entity_ids,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({"error":"invalid_request","error_description":e.to_string()}))),
+    };
+    match introspect_token(&state,&caller,token,entity_ids).await {
+        Ok(Some(j)) => (StatusCode::OK, Json(j)),
+        Ok(None) => (StatusCode::OK, Json(json!({"active":false}))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error":"server_error","error_description":e.to_string()}))),
+    }
+}
+
+/// Folds one user into another, including their rights and requests. Requires superuser rights.
+async fn merge_users(State(state): State<Arc<AppState>>, Path((from_id,into_id)): Path<(usize,usize)>, credentials: Credentials,) -> impl IntoResponse {
+    let details = format!("user #{from_id} into user #{into_id}");
+    if let Err(e) = require_superuser(&state,&credentials,"merge users",&details).await {
+        return e.json_response()
+    }
+    if let Err(e) = state.dal.write().await.link_users(into_id,from_id).await {
+        return e.json_response()
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+async fn auth_info(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    let (user,token) = match credentials.user(&state).await {
+        Ok(Some((user,token))) => (Some(user),token),
+        Ok(None) => (None,None),
+        Err(e) => return e.json_response(),
+    };
+    let mut user_j = json!(user);
+    if let Some(user_id) = user.and_then(|u|u.id) {
+        // Linked identities act as their principal user
+        let principal_id = state.dal.read().await.get_principal_id(user_id as usize).await.unwrap_or(user_id as usize);
+        user_j["principal_id"] = json!(principal_id);
+        user_j["is_superuser"] = json!(state.is_superuser(principal_id).await.unwrap_or(false));
+    }
+    let j = json!({"status":"OK","user":user_j,"token":token});
+    (StatusCode::OK, Json(j))
+}
+
+async fn user_logout(State(state): State<Arc<AppState>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
+    if let Some(session) = session_from_cookies(&state,&cookies).await {
+        if let Err(e) = state.dal.read().await.session_store.destroy_session(session).await {
+            tracing::warn!("Could not destroy session on logout: {e}");
+        }
     };
     Redirect::to("/")
 }
 
-async fn parents_children_entities(state: Arc<AppState>, entities: &Vec<Entity>) -> Result<(EntityGroup,EntityGroup),RingError> {
-    let parents: Vec<usize> = entities.iter().map(|e|e.parent_ids.to_owned()).flatten().collect();
+/// Returns the IDs of all identities of the current user, whose sessions belong to that user
+async fn current_identity_ids(state: &Arc<AppState>, credentials: &Credentials) -> Result<Vec<usize>,RingError> {
+    let principal_id = get_current_user_id(state,credentials).await?;
+    let users = state.dal.read().await.get_linked_users(principal_id).await?;
+    Ok(users.iter().filter_map(|user|user.id).map(|id|id as usize).collect())
+}
+
+/// Lists the active sessions of the current user
+async fn user_sessions(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    let user_ids = match current_identity_ids(&state,&credentials).await {
+        Ok(ids) => ids,
+        Err(e) => return e.json_response()
+    };
+    let sessions = match state.dal.read().await.session_store.get_user_sessions(&user_ids).await {
+        Ok(sessions) => sessions,
+        Err(e) => return e.json_response()
+    };
+    let current = session_from_cookies(&state,&credentials.cookies).await.map(|session|session.id().to_string());
+    let sessions: Vec<Value> = sessions.into_iter().map(|session|{
+        let is_current = current.as_ref()==Some(&session.id_string);
+        let mut session_j = json!(session);
+        session_j["current"] = json!(is_current);
+        session_j
+    }).collect();
+    let j = json!({"status":"OK","sessions":sessions});
+    (StatusCode::OK, Json(j))
+}
+
+/// Revokes one session of the current user
+async fn revoke_user_session(State(state): State<Arc<AppState>>, Path(session_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    destroy_current_user_sessions(&state,&credentials,Some(session_id)).await
+}
+
+/// Revokes all sessions of the current user
+async fn revoke_all_user_sessions(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    destroy_current_user_sessions(&state,&credentials,None).await
+}
+
+async fn destroy_current_user_sessions(state: &Arc<AppState>, credentials: &Credentials, session_id: Option<usize>) -> (StatusCode, Json<Value>) {
+    let user_ids = match current_identity_ids(state,credentials).await {
+        Ok(ids) => ids,
+        Err(e) => return e.json_response()
+    };
+    let revoked = match state.dal.read().await.session_store.destroy_user_sessions(&user_ids,session_id).await {
+        Ok(revoked) => revoked,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","revoked":revoked});
+    (StatusCode::OK, Json(j))
+}
+
+/// Revokes all sessions of a user and their linked identities, e.g. for a compromised account. Requires superuser rights.
+async fn admin_revoke_user_sessions(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    let details = format!("user #{user_id}");
+    let current_user_id = match require_superuser(&state,&credentials,"revoke all sessions",&details).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let user_ids: Vec<usize> = match state.dal.read().await.get_principal_id(user_id).await {
+        Ok(principal_id) => match state.dal.read().await.get_linked_users(principal_id).await {
+            Ok(users) => users.iter().filter_map(|user|user.id).map(|id|id as usize).collect(),
+            Err(e) => return e.json_response(),
+        },
+        Err(e) => return e.json_response(),
+    };
+    let revoked = match state.dal.read().await.session_store.destroy_user_sessions(&user_ids,None).await {
+        Ok(revoked) => revoked,
+        Err(e) => return e.json_response()
+    };
+    tracing::info!("User #{current_user_id} revoked {revoked} sessions of user #{user_id}");
+    let j = json!({"status":"OK","revoked":revoked});
+    (StatusCode::OK, Json(j))
+}
+
+/// Deactivates or reactivates a user and their linked identities. Requires superuser rights.
+/// On deactivation, all their sessions are destroyed.
+async fn change_user_activation(state: &Arc<AppState>, user_id: usize, credentials: &Credentials, deactivated: bool) -> Result<usize,RingError> {
+    let current_user_id = get_current_user_id(state,credentials).await?;
+    let principal_id = state.dal.read().await.get_principal_id(user_id).await?;
+    if principal_id==current_user_id {
+        return Err(RingError::Validation("You can not change the activation of your own account".into()));
+    }
+    let action = if deactivated { "deactivate user" } else { "reactivate user" };
+    require_superuser(state,credentials,action,&format!("user #{user_id}")).await?;
+    state.dal.write().await.set_user_deactivated(principal_id,deactivated).await?;
+    if !deactivated {
+        return Ok(0);
+    }
+    let user_ids: Vec<usize> = state.dal.read().await.get_linked_users(principal_id).await?
+        .iter()
+        .filter_map(|user|user.id)
+        .map(|id|id as usize)
+        .collect();
+    let revoked = state.dal.read().await.session_store.destroy_user_sessions(&user_ids,None).await?;
+    tracing::info!("User #{current_user_id} deactivated user #{principal_id}, revoking {revoked} sessions");
+    Ok(revoked)
+}
+
+async fn deactivate_user(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    match change_user_activation(&state,user_id,&credentials,true).await {
+        Ok(revoked) => (StatusCode::OK, Json(json!({"status":"OK","revoked_sessions":revoked}))),
+        Err(e) => e.json_response(),
+    }
+}
+
+async fn reactivate_user(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    match change_user_activation(&state,user_id,&credentials,false).await {
+        Ok(_) => (StatusCode::OK, Json(json!({"status":"OK"}))),
+        Err(e) => e.json_response(),
+    }
+}
+
+/// Grants or revokes the superuser flag of a user; superusers only
+async fn set_superuser(State(state): State<Arc<AppState>>, Path((user_id,is_superuser)): Path<(usize,bool)>, credentials: Credentials,) -> impl IntoResponse {
+    let action = if is_superuser { "grant superuser" } else { "revoke superuser" };
+    let current_user_id = match require_superuser(&state,&credentials,action,&format!("user #{user_id}")).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    if let Err(e) = state.dal.write().await.set_superuser_flag(user_id,is_superuser,current_user_id).await {
+        return e.json_response()
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// Lists superusers, from the DB flag and from the config file; superusers only
+async fn list_superusers(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    if let Err(e) = require_superuser(&state,&credentials,"list superusers","").await {
+        return e.json_response()
+    }
+    let user_ids = match state.dal.read().await.get_superuser_ids().await {
+        Ok(ids) => ids,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","user_ids":user_ids,"config":state.config["superusers"]});
+    (StatusCode::OK, Json(j))
+}
+
+/// The latest superuser actions; superusers only. Optional `limit` query parameter (default 100).
+async fn superuser_log(State(state): State<Arc<AppState>>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    if let Err(e) = require_superuser(&state,&credentials,"view superuser log","").await {
+        return e.json_response()
+    }
+    let limit = params.get("limit").and_then(|l|l.parse::<usize>().ok()).unwrap_or(100);
+    let log = match state.dal.read().await.get_superuser_log(limit).await {
+        Ok(log) => log,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","log":log});
+    (StatusCode::OK, Json(j))
+}
+
+/// Adds a parent to an entity, e.g. to repair the entity tree; superusers only
+async fn admin_add_entity_parent(State(state): State<Arc<AppState>>, Path((entity_id,parent_id)): Path<(usize,usize)>, credentials: Credentials,) -> impl IntoResponse {
+    let details = format!("entity #{entity_id}, parent #{parent_id}");
+    if let Err(e) = require_superuser(&state,&credentials,"add entity parent",&details).await {
+        return e.json_response()
+    }
+    if let Err(e) = state.dal.write().await.add_entity_parent(entity_id,parent_id).await {
+        return e.json_response()
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// Removes a parent from an entity, e.g. to repair the entity tree; superusers only
+async fn admin_remove_entity_parent(State(state): State<Arc<AppState>>, Path((entity_id,parent_id)): Path<(usize,usize)>, credentials: Credentials,) -> impl IntoResponse {
+    let details = format!("entity #{entity_id}, parent #{parent_id}");
+    if let Err(e) = require_superuser(&state,&credentials,"remove entity parent",&details).await {
+        return e.json_response()
+    }
+    match state.dal.write().await.remove_entity_parent(entity_id,parent_id).await {
+        Ok(true) => {},
+        Ok(false) => return RingError::NotFound("No such parent".into()).json_response(),
+        Err(e) => return e.json_response(),
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+async fn parents_children_entities(state: Arc<AppState>, entities: &[Entity]) -> Result<(EntityGroup,EntityGroup),RingError> {
+    let parents: Vec<usize> = entities.iter().flat_map(|e|e.parent_ids.to_owned()).collect();
     let mut parents = state.dal.read().await.load_entities(&parents).await?;
     state.dal.read().await.annotate_entities(&mut parents).await?;
 
-    let children: Vec<usize> = entities.iter().map(|e|e.child_ids.to_owned()).flatten().collect();
+    let children: Vec<usize> = entities.iter().flat_map(|e|e.child_ids.to_owned()).collect();
     let mut children = state.dal.read().await.load_entities(&children).await?;
     state.dal.read().await.annotate_entities(&mut children).await?;
 
     Ok((parents,children))
 }
 
-async fn user_entities(State(state): State<Arc<AppState>>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let user = match ExternalSystemUser::from_cookies(&state, &cookies).await {
-        Some(user) => user,
-        None => return (StatusCode::OK, Json(json!({"status":"not_logged_in"}))),
+async fn user_entities(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    let (user,token) = match credentials.user(&state).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return RingError::Unauthenticated("not_logged_in".into()).json_response(),
+        Err(e) => return e.json_response(),
     };
     let entities = match user.get_entities_with_access(&state).await {
         Ok(x) => x.as_sorted_vec(),
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
+    };
+    let entities = match restrict_to_token(&state,&token,entities).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response(),
     };
     let (parents,children) = match parents_children_entities(state,&entities).await {
         Ok(x) => x,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
     let j = json!({
         "status":"OK",
@@ -95,19 +1053,59 @@ async fn user_entities(State(state): State<Arc<AppState>>, cookies: Option<Typed
     (StatusCode::OK, Json(j))
 }
 
+/// Access requests on entities the current user is a direct admin of, and requests on descendant entities once they have been escalated to those
+async fn user_access_requests(State(state): State<Arc<AppState>>, credentials: Credentials,) -> impl IntoResponse {
+    let (current_user_id,token) = match get_current_user(&state,&credentials).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let mut access_requests = match state.dal.read().await.get_access_requests_for_admin(current_user_id).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response(),
+    };
+    if token.is_some() {
+        let mut in_scope = vec![];
+        for ar in access_requests {
+            if check_token_scope(&state,&token,Some("admin"),&[ar.entity_id]).await.is_ok() {
+                in_scope.push(ar);
+            }
+        }
+        access_requests = in_scope;
+    }
+    let entity_ids: Vec<usize> = access_requests.iter().map(|ar|ar.entity_id).collect();
+    let entities = match state.dal.read().await.load_entities(&entity_ids).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response(),
+    };
+    let mut users = HashMap::new();
+    for user_id in access_requests.iter().map(|ar|ar.user_id) {
+        let user = match state.dal.read().await.get_user(user_id).await {
+            Ok(user) => user,
+            Err(e) => return e.json_response(),
+        };
+        users.insert(user_id,state.public_user_json(user));
+    }
+    let j = json!({
+        "status":"OK",
+        "access_requests":access_requests,
+        "entities":entities.as_sorted_vec(),
+        "users":users,
+    });
+    (StatusCode::OK, Json(j))
+}
+
 async fn search_user(State(state): State<Arc<AppState>>, Path(query): Path<String>,) -> impl IntoResponse {
     let user_ids = match state.dal.read().await.search_user_name(&query).await {
         Ok(ids) => ids,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
     let mut users = HashMap::new();
     for user_id in user_ids {
-        let mut user = match state.dal.read().await.get_user(user_id).await {
+        let user = match state.dal.read().await.get_user(user_id).await {
             Ok(user) => user,
-            Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+            Err(e) => return e.json_response(),
         };
-        user.strip_private_data();
-        users.insert(user_id,user);
+        users.insert(user_id,state.public_user_json(user));
     }
     let j = json!({
         "status":"OK",
@@ -119,7 +1117,7 @@ async fn search_user(State(state): State<Arc<AppState>>, Path(query): Path<Strin
 async fn search_access(State(state): State<Arc<AppState>>, Path(query): Path<String>,) -> impl IntoResponse {
     let rights = match state.dal.read().await.search_access_rights(&query).await {
         Ok(ids) => ids,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
     let j = json!({
         "status":"OK",
@@ -133,13 +1131,14 @@ async fn search_access(State(state): State<Arc<AppState>>, Path(query): Path<Str
 // }
 
 async fn user_info(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>,) -> impl IntoResponse {
-    let mut user = match state.dal.read().await.get_user(user_id).await {
+    let user = match state.dal.read().await.get_user(user_id).await {
         Ok(user) => user,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
-    user.strip_private_data(); // Prevent private data from leaking
-    let mut user_j = json!(user);
-    user_j["external_url"] = json!(user.external_url());
+    let mut user_j = state.public_user_json(user);
+    if let Ok(Some(entity_id)) = state.dal.read().await.get_service_account_entity(user_id).await {
+        user_j["service_account_entity_id"] = json!(entity_id);
+    }
     let j = json!({
         "status":"OK",
         "user":user_j,
@@ -156,7 +1155,7 @@ async fn get_rights_entities(State(state): State<Arc<AppState>>, Path(entity_ids
     for entity_id in &entity_ids {
         let r = match state.dal.read().await.get_all_rights_for_entity(*entity_id).await {
             Ok(r) => r,
-            Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+            Err(e) => return e.json_response(),
         };
         rights.insert(entity_id,r);
     }
@@ -165,29 +1164,38 @@ async fn get_rights_entities(State(state): State<Arc<AppState>>, Path(entity_ids
     for entity_id in &entity_ids {
         let mut access_requests_tmp = match state.dal.read().await.get_access_requests(*entity_id).await {
             Ok(data) => data,
-            Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+            Err(e) => return e.json_response(),
         };
         access_requests.append(&mut access_requests_tmp);
     }
 
-    let mut user_ids: Vec<usize> = rights.iter()
-        .map(|(_entity_id,user_right)|user_right)
+    let approvals = match state.dal.read().await.get_access_approvals(&entity_ids).await {
+        Ok(data) => data,
+        Err(e) => return e.json_response(),
+    };
+    let mut required_approvals = HashMap::new();
+    for entity_id in &entity_ids {
+        match state.dal.read().await.get_required_approvals(*entity_id).await {
+            Ok(n) => required_approvals.insert(entity_id,n),
+            Err(e) => return e.json_response(),
+        };
+    }
+
+    let mut user_ids: Vec<usize> = rights.values()
         .flatten()
         .map(|(user_id,_right)|*user_id)
         .collect();
     user_ids.append(&mut access_requests.iter().map(|ar|ar.user_id).collect());
+    user_ids.append(&mut approvals.iter().flat_map(|a|[a.user_id,a.approver_id]).collect());
     user_ids.sort();
     user_ids.dedup();
     let mut users = HashMap::new();
     for user_id in user_ids {
-        let mut user = match state.dal.read().await.get_user(user_id).await {
+        let user = match state.dal.read().await.get_user(user_id).await {
             Ok(user) => user,
-            Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+            Err(e) => return e.json_response(),
         };
-        user.strip_private_data(); // Prevent private data from leaking
-        let mut user_j = json!(user);
-        user_j["external_url"] = json!(user.external_url());
-        users.insert(user_id,user_j);
+        users.insert(user_id,state.public_user_json(user));
     }
 
     let j = json!({
@@ -195,6 +1203,8 @@ async fn get_rights_entities(State(state): State<Arc<AppState>>, Path(entity_ids
         "rights":rights,
         "users":users,
         "access_requests":access_requests,
+        "approvals":approvals,
+        "required_approvals":required_approvals,
     });
     (StatusCode::OK, Json(j))
 }
@@ -206,126 +1216,718 @@ fn parse_rights_string(rights: &str) -> Vec<String> {
         .collect()
 }
 
-async fn get_current_user_id(state: &Arc<AppState>, cookies: &Option<TypedHeader<headers::Cookie>>) -> Result<usize,RingError> {
-    let current_user = ExternalSystemUser::from_cookies(&state, &cookies).await.ok_or_else(||RingError::String("not logged in".into()))?;
+async fn get_current_user_id(state: &Arc<AppState>, credentials: &Credentials) -> Result<usize,RingError> {
+    Ok(get_current_user(state,credentials).await?.0)
+}
+
+/// Returns the logged-in (principal) user ID, and the API token used, if any
+async fn get_current_user(state: &Arc<AppState>, credentials: &Credentials) -> Result<(usize,Option<ApiToken>),RingError> {
+    let (current_user,token) = credentials.user(state).await?.ok_or_else(||RingError::Unauthenticated("not logged in".into()))?;
     let current_user_id = current_user.id.ok_or_else(||RingError::String("logged in but no user ID".into()))? as usize;
-    Ok(current_user_id)
+    let current_user_id = state.dal.read().await.get_principal_id(current_user_id).await?;
+    Ok((current_user_id,token))
+}
+
+/// Checks that the API token used (if any) covers a right on all these entities
+async fn check_token_scope(state: &Arc<AppState>, token: &Option<ApiToken>, right: Option<&str>, entity_ids: &[usize]) -> Result<(),RingError> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+    if let Some(right) = right {
+        if !token.allows_right(right) {
+            return Err(RingError::Forbidden(format!("This API token does not cover the '{right}' right")));
+        }
+    }
+    let dal = state.dal.read().await;
+    for entity_id in entity_ids {
+        if !token.covers(&dal,*entity_id).await? {
+            return Err(RingError::Forbidden(format!("This API token does not cover entity #{entity_id}")));
+        }
+    }
+    Ok(())
+}
+
+/// Removes entities and rights outside the scope of the API token used, if any
+async fn restrict_to_token(state: &Arc<AppState>, token: &Option<ApiToken>, entities: Vec<Entity>) -> Result<Vec<Entity>,RingError> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(entities),
+    };
+    let dal = state.dal.read().await;
+    let mut ret = vec![];
+    for mut entity in entities {
+        entity.rights.retain(|right|token.allows_right(right));
+        if !entity.rights.is_empty() && token.covers(&dal,entity.id).await? {
+            ret.push(entity);
+        }
+    }
+    Ok(ret)
 }
 
-async fn user_rights_prep(state: &Arc<AppState>, entity_ids: String, cookies: &Option<TypedHeader<headers::Cookie>>) -> Result<Vec<usize>,RingError> {
-    let current_user_id = get_current_user_id(state,cookies).await?;
+/// Returns the logged-in user ID, and the parsed entity IDs
+async fn user_rights_prep(state: &Arc<AppState>, entity_ids: String, credentials: &Credentials, action: &str) -> Result<(usize,Vec<usize>),RingError> {
+    let (current_user_id,token) = get_current_user(state,credentials).await?;
 
     // Parse entity IDs from String, and check that the logged-in user has admin rights on all of them
     let entity_ids: Vec<usize> = entity_ids
         .split(',')
         .filter_map(|e|e.parse::<usize>().ok())
         .collect();
-    let allowed_entities = state.dal.read().await.get_all_user_rights_for_entities(current_user_id,Some("admin".into())).await?;
-    if entity_ids.iter().any(|entity_id|!allowed_entities.has(*entity_id)) {
-        return Err(RingError::String("You do not have admin rights to all these entities".into()));
+    check_token_scope(state,&token,Some("admin"),&entity_ids).await?;
+    check_admin_rights(state,current_user_id,&entity_ids,action,"You do not have admin rights to all these entities").await?;
+    Ok((current_user_id,entity_ids))
+}
+
+/// Checks that a user has admin rights on all these entities. Superusers always pass; that is logged as a superuser action.
+async fn check_admin_rights(state: &Arc<AppState>, user_id: usize, entity_ids: &[usize], action: &str, denied: &str) -> Result<(),RingError> {
+    let allowed_entities = state.dal.read().await.get_all_user_rights_for_entities(user_id,Some("admin".into())).await?;
+    if entity_ids.iter().all(|entity_id|allowed_entities.has(*entity_id)) {
+        return Ok(());
+    }
+    if state.is_superuser(user_id).await? {
+        return log_superuser_action(state,user_id,action,&format!("entities {entity_ids:?}")).await;
     }
-    Ok(entity_ids)
+    Err(RingError::Forbidden(denied.into()))
 }
 
-async fn add_entity_child(State(state): State<Arc<AppState>>, Path((entity_id,name,ext_id)): Path<(usize,String,String)>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let current_user_id = match get_current_user_id(&state,&cookies).await {
-        Ok(id) => id,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+/// Checks that the current user is a superuser, not using a restricted API token, and logs the action
+async fn require_superuser(state: &Arc<AppState>, credentials: &Credentials, action: &str, details: &str) -> Result<usize,RingError> {
+    let (current_user_id,token) = get_current_user(state,credentials).await?;
+    if !is_unrestricted_token(&token) {
+        return Err(RingError::Forbidden("Superuser actions require an unrestricted API token".into()));
+    }
+    if !state.is_superuser(current_user_id).await? {
+        return Err(RingError::Forbidden("You need to be a superuser to do this".into()));
+    }
+    log_superuser_action(state,current_user_id,action,details).await?;
+    Ok(current_user_id)
+}
+
+/// Returns true unless the API token used (if any) is restricted to some rights or entities
+fn is_unrestricted_token(token: &Option<ApiToken>) -> bool {
+    token.as_ref().map(|t|t.allows_right("admin") && t.entity_ids.is_empty()).unwrap_or(true)
+}
+
+/// Records an action that was allowed because of superuser rights, in the superuser log
+async fn log_superuser_action(state: &Arc<AppState>, user_id: usize, action: &str, details: &str) -> Result<(),RingError> {
+    tracing::info!(target: "superuser", "User #{user_id}: {action} ({details})");
+    state.dal.read().await.log_superuser_action(user_id,action,details).await
+}
+
+/// Returns the IDs of those entities for which the user has a pending access request
+async fn pending_access_requests(state: &Arc<AppState>, user_id: usize, entity_ids: &[usize]) -> Result<Vec<usize>,RingError> {
+    let mut ret = vec![];
+    for entity_id in entity_ids {
+        let has_request = state.dal.read().await.get_access_requests(*entity_id).await?
+            .iter()
+            .any(|ar|ar.user_id==user_id);
+        if has_request {
+            ret.push(*entity_id);
+        }
+    }
+    Ok(ret)
+}
+
+/// Notifies a user about granted rights on entities they had requested access to, ignoring rights still waiting for approval
+async fn notify_access_granted(state: &Arc<AppState>, user_id: usize, requested: &[usize], rights: &[String], pending: &[(usize,String)]) {
+    for entity_id in requested {
+        let granted: Vec<String> = rights.iter()
+            .filter(|right|!pending.contains(&(*entity_id,right.to_string())))
+            .cloned()
+            .collect();
+        if granted.is_empty() {
+            continue;
+        }
+        if let Err(e) = notify_access_decided(state,user_id,*entity_id,Some(&granted)).await {
+            tracing::warn!("Could not send access granted notification: {e}");
+        }
+    }
+}
+
+/// Sets the required approvals for new rights. Any admin can raise it; lowering it needs as many admins as currently
+/// required (or a superuser), otherwise a single admin could lower it to 1 and then grant alone.
+/// Returns the IDs of entities where the change is still waiting for approvals.
+async fn set_approval_policy(State(state): State<Arc<AppState>>, Path((entity_ids,required_approvals)): Path<(String,usize)>, credentials: Credentials,) -> impl IntoResponse {
+    let required_approvals = required_approvals.max(1);
+    let action = format!("set required approvals to {required_approvals}");
+    let (current_user_id,entity_ids) = match user_rights_prep(&state,entity_ids,&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
     };
-    let allowed_entities = match state.dal.read().await.get_all_user_rights_for_entities(current_user_id,Some("admin".into())).await {
-        Ok(entity_ids) => entity_ids,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+    let is_superuser = match state.is_superuser(current_user_id).await {
+        Ok(is_superuser) => is_superuser,
+        Err(e) => return e.json_response()
+    };
+    let mut pending = vec![];
+    for entity_id in entity_ids {
+        let approved = if is_superuser {
+            let current = match state.dal.read().await.get_required_approvals(entity_id).await {
+                Ok(current) => current,
+                Err(e) => return e.json_response()
+            };
+            if required_approvals<current {
+                if let Err(e) = log_superuser_action(&state,current_user_id,&action,&format!("entity #{entity_id}, without further approvals")).await {
+                    return e.json_response()
+                }
+            }
+            true
+        } else {
+            match state.dal.write().await.approve_policy_change(current_user_id,entity_id,required_approvals).await {
+                Ok(approved) => approved,
+                Err(e) => return e.json_response()
+            }
+        };
+        if !approved {
+            pending.push(entity_id);
+            continue;
+        }
+        if let Err(e) = state.dal.write().await.set_required_approvals(entity_id,required_approvals).await {
+            return e.json_response()
+        }
+    }
+    let j = json!({"status":"OK","pending":pending});
+    (StatusCode::OK, Json(j))
+}
+
+async fn add_entity_child(State(state): State<Arc<AppState>>, Path((entity_id,name,ext_id)): Path<(usize,String,String)>, credentials: Credentials,) -> impl IntoResponse {
+    let (current_user_id,token) = match get_current_user(&state,&credentials).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
     };
-    if !allowed_entities.has(entity_id) {
-        return (StatusCode::OK, Json(json!({"status":"You do not have admin rights to create a child entity here"})))
+    if let Err(e) = check_token_scope(&state,&token,Some("admin"),&[entity_id]).await {
+        return e.json_response()
+    }
+    let action = format!("create child entity '{name}'");
+    if let Err(e) = check_admin_rights(&state,current_user_id,&[entity_id],&action,"You do not have admin rights to create a child entity here").await {
+        return e.json_response()
     }
     let child_id = match state.dal.write().await.create_child_entity(entity_id,&name,&ext_id).await {
         Ok(id) => id,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+        Err(e) => return e.json_response()
     };
     let j = json!({"status":"OK","child_id":child_id});
     (StatusCode::OK, Json(j))
 }
 
-async fn set_user_rights(State(state): State<Arc<AppState>>, Path((entity_ids,user_id,rights)): Path<(String,usize,String)>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
+/// Checks the `force` query parameter, which allows superusers to override the last-admin protection. The override is logged.
+async fn force_requested(state: &Arc<AppState>, params: &HashMap<String, String>, current_user_id: usize, action: &str) -> Result<bool,RingError> {
+    if !matches!(params.get("force").map(|s|s.as_str()),Some("1") | Some("true")) {
+        return Ok(false);
+    }
+    if !state.is_superuser(current_user_id).await? {
+        return Err(RingError::Forbidden("Only superusers can force this change".into()));
+    }
+    log_superuser_action(state,current_user_id,action,"forced, without last-admin protection").await?;
+    Ok(true)
+}
+
+async fn set_user_rights(State(state): State<Arc<AppState>>, Path((entity_ids,user_id,rights)): Path<(String,usize,String)>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    let rights = parse_rights_string(&rights);
+    let action = format!("set rights '{}' for user #{user_id}",rights.join(","));
+    let (current_user_id,entity_ids) = match user_rights_prep(&state,entity_ids,&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let requested = match pending_access_requests(&state,user_id,&entity_ids).await {
+        Ok(ids) => ids,
+        Err(e) => return e.json_response()
+    };
+    let force = match force_requested(&state,&params,current_user_id,&action).await {
+        Ok(force) => force,
+        Err(e) => return e.json_response()
+    };
+    let pending = match state.dal.write().await.set_access_rights(user_id,entity_ids,rights.to_owned(),Some(current_user_id),force).await {
+        Ok(pending) => pending,
+        Err(e) => return e.json_response()
+    };
+    notify_access_granted(&state,user_id,&requested,&rights,&pending).await;
+    let j = json!({"status":"OK","pending":pending});
+    (StatusCode::OK, Json(j))
+}
+
+async fn add_user_rights(State(state): State<Arc<AppState>>, Path((entity_ids,user_id,rights)): Path<(String,usize,String)>, credentials: Credentials,) -> impl IntoResponse {
     let rights = parse_rights_string(&rights);
-    let entity_ids = match user_rights_prep(&state,entity_ids,&cookies).await {
+    let action = format!("add rights '{}' for user #{user_id}",rights.join(","));
+    let (current_user_id,entity_ids) = match user_rights_prep(&state,entity_ids,&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let requested = match pending_access_requests(&state,user_id,&entity_ids).await {
         Ok(ids) => ids,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+        Err(e) => return e.json_response()
+    };
+    let pending = match state.dal.write().await.add_access_rights(user_id,entity_ids,rights.to_owned(),Some(current_user_id)).await {
+        Ok(pending) => pending,
+        Err(e) => return e.json_response()
+    };
+    notify_access_granted(&state,user_id,&requested,&rights,&pending).await;
+    let j = json!({"status":"OK","pending":pending});
+    (StatusCode::OK, Json(j))
+}
+
+async fn remove_user_rights(State(state): State<Arc<AppState>>, Path((entity_ids,user_id,rights)): Path<(String,usize,String)>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    let rights = parse_rights_string(&rights);
+    let action = format!("remove rights '{}' from user #{user_id}",rights.join(","));
+    let (current_user_id,entity_ids) = match user_rights_prep(&state,entity_ids,&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let force = match force_requested(&state,&params,current_user_id,&action).await {
+        Ok(force) => force,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","rights":rights,"entities":entity_ids,"user":user_id});
+    if let Err(e) = state.dal.write().await.remove_access_rights(user_id,entity_ids,rights,force).await {
+        return e.json_response()
+    }
+    (StatusCode::OK, Json(j))
+}
+
+/// Returns the grants of one user that a copy would add to another user, as (entity_id,rights), optionally
+/// restricted to the subtree below an entity. Rights from auto-grant rules, and rights the target already has, are skipped.
+async fn rights_to_copy(state: &Arc<AppState>, from_user_id: usize, to_user_id: usize, subtree: Option<usize>) -> Result<Vec<(usize,Vec<String>)>,RingError> {
+    let dal = state.dal.read().await;
+    let from_user_id = dal.get_principal_id(from_user_id).await?;
+    let to_user_id = dal.get_principal_id(to_user_id).await?;
+    if from_user_id==to_user_id {
+        return Err(RingError::Validation("Can not copy rights of a user onto themselves".into()));
+    }
+    let to_user = dal.get_user(to_user_id).await?;
+    if to_user.deactivated || to_user.is_service_account() {
+        return Err(RingError::Validation(format!("Can not copy rights to user #{to_user_id}, which is deactivated or a service account")));
+    }
+    let subtree = match subtree {
+        Some(entity_id) => Some(dal.get_entity_subtree(&[entity_id]).await?),
+        None => None,
+    };
+    let auto_grants = dal.get_auto_grants(from_user_id).await?;
+    let existing = dal.get_direct_grants(to_user_id).await?;
+    let mut ret: Vec<(usize,Vec<String>)> = vec![];
+    for (entity_id,right) in dal.get_direct_grants(from_user_id).await? {
+        if subtree.as_ref().is_some_and(|subtree|!subtree.contains(&entity_id))
+            || auto_grants.iter().any(|(e,r,_)|*e==entity_id && *r==right)
+            || existing.contains(&(entity_id,right.to_owned())) {
+            continue;
+        }
+        match ret.iter_mut().find(|(e,_)|*e==entity_id) {
+            Some((_,rights)) => rights.push(right),
+            None => ret.push((entity_id,vec![right])),
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Copies the direct grants of one user to another, e.g. for a replacement, through the usual approval process.
+/// The target must be an active user, not a service account. Requires admin rights on `entity_id` if given, superuser rights otherwise,
+/// and admin rights on every entity involved. Optional query parameters: `entity_id` to copy only the subtree
+/// below that entity, and `dry_run=1` to only return what would be copied.
+async fn copy_user_rights(State(state): State<Arc<AppState>>, Path((from_user_id,to_user_id)): Path<(usize,usize)>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    let (current_user_id,token) = match get_current_user(&state,&credentials).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let subtree = match params.get("entity_id").map(|s|s.parse::<usize>()).transpose() {
+        Ok(subtree) => subtree,
+        Err(_) => return RingError::Validation("Invalid entity_id".into()).json_response()
+    };
+    let dry_run = matches!(params.get("dry_run").map(|s|s.as_str()),Some("1") | Some("true"));
+    let action = format!("copy rights of user #{from_user_id} to user #{to_user_id}");
+    // The grants of the source user are only looked at once the caller may see them
+    let allowed = match subtree {
+        Some(entity_id) => match check_token_scope(&state,&token,Some("admin"),&[entity_id]).await {
+            Ok(()) => check_admin_rights(&state,current_user_id,&[entity_id],&action,"You do not have admin rights to this entity").await,
+            Err(e) => Err(e),
+        },
+        None => match state.is_superuser(current_user_id).await {
+            Ok(true) if is_unrestricted_token(&token) => log_superuser_action(&state,current_user_id,&action,"all entities").await,
+            Err(e) => Err(e),
+            Ok(_) => Err(RingError::Forbidden("Copying all rights of a user requires superuser rights; give an entity_id to copy a subtree".into())),
+        },
+    };
+    if let Err(e) = allowed {
+        return e.json_response()
+    }
+    let grants = match rights_to_copy(&state,from_user_id,to_user_id,subtree).await {
+        Ok(grants) => grants,
+        Err(e) => return e.json_response()
+    };
+    let entity_ids: Vec<usize> = grants.iter().map(|(entity_id,_)|*entity_id).collect();
+    if let Err(e) = check_token_scope(&state,&token,Some("admin"),&entity_ids).await {
+        return e.json_response()
+    }
+    if let Err(e) = check_admin_rights(&state,current_user_id,&entity_ids,&action,"You do not have admin rights to all entities these rights are on").await {
+        return e.json_response()
+    }
+    let grants_json: Vec<Value> = grants.iter().map(|(entity_id,rights)|json!({"entity_id":entity_id,"rights":rights})).collect();
+    if dry_run {
+        return (StatusCode::OK, Json(json!({"status":"OK","dry_run":true,"grants":grants_json})))
+    }
+    // All grants are added in one transaction, so a failure leaves the target user unchanged
+    let pairs: Vec<(usize,String)> = grants.into_iter()
+        .flat_map(|(entity_id,rights)|rights.into_iter().map(move |right|(entity_id,right)))
+        .collect();
+    let pending = match state.dal.write().await.add_access_right_pairs(to_user_id,pairs,Some(current_user_id)).await {
+        Ok(pending) => pending,
+        Err(e) => return e.json_response()
     };
-    if let Err(e) = state.dal.write().await.set_access_rights(user_id,entity_ids,rights).await {
-        return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+    let j = json!({"status":"OK","dry_run":false,"grants":grants_json,"pending":pending});
+    (StatusCode::OK, Json(j))
+}
+
+/// Offboarding: removes every direct right a user holds on an entity and its descendants, at once.
+/// Requires admin rights on the entity and on every entity a right is removed from; superusers can `?force=1` past the last-admin protection.
+/// Returns a report of the removed rights. Rights from auto-grant rules return while the rule still matches the user.
+async fn revoke_user_subtree_rights(State(state): State<Arc<AppState>>, Path((entity_id,user_id)): Path<(usize,usize)>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    let (current_user_id,token) = match get_current_user(&state,&credentials).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let subtree = match state.dal.read().await.get_entity_subtree(&[entity_id]).await {
+        Ok(subtree) => subtree,
+        Err(e) => return e.json_response()
+    };
+    let mut entity_ids: Vec<usize> = match state.dal.read().await.get_direct_grants(user_id).await {
+        Ok(grants) => grants.into_iter().map(|(entity_id,_right)|entity_id).filter(|id|subtree.contains(id)).collect(),
+        Err(e) => return e.json_response()
+    };
+    entity_ids.push(entity_id);
+    entity_ids.sort();
+    entity_ids.dedup();
+    let action = format!("revoke all rights of user #{user_id} below entity #{entity_id}");
+    if let Err(e) = check_token_scope(&state,&token,Some("admin"),&entity_ids).await {
+        return e.json_response()
+    }
+    if let Err(e) = check_admin_rights(&state,current_user_id,&entity_ids,&action,"You do not have admin rights to all entities this user has rights on").await {
+        return e.json_response()
+    }
+    let force = match force_requested(&state,&params,current_user_id,&action).await {
+        Ok(force) => force,
+        Err(e) => return e.json_response()
+    };
+    let removed = match state.dal.write().await.revoke_subtree_rights(user_id,entity_id,&entity_ids,force).await {
+        Ok(removed) => removed,
+        Err(e) => return e.json_response()
+    };
+    let removed_entity_ids: Vec<usize> = removed.iter().map(|(entity_id,_right)|*entity_id).collect();
+    let entities = match state.dal.read().await.load_entities(&removed_entity_ids).await {
+        Ok(entities) => entities,
+        Err(e) => return e.json_response()
+    };
+    let report: Vec<Value> = removed.iter()
+        .map(|(entity_id,right)|{
+            let name = entities.get(*entity_id).map(|e|e.name.to_owned()).unwrap_or_default();
+            json!({"entity_id":entity_id,"entity_name":name,"right":right})
+        })
+        .collect();
+    tracing::info!("User #{current_user_id} revoked {} rights of user #{user_id} below entity #{entity_id}",removed.len());
+    let j = json!({"status":"OK","user":user_id,"entity_id":entity_id,"removed":report});
+    (StatusCode::OK, Json(j))
+}
+
+async fn request_access_rights(State(state): State<Arc<AppState>>, Path((entity_ids,note)): Path<(String,String)>, credentials: Credentials,) -> impl IntoResponse {
+    let (current_user_id,token) = match get_current_user(&state,&credentials).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let entity_ids: Vec<usize> = entity_ids
+        .split(',')
+        .filter_map(|e|e.parse::<usize>().ok())
+        .collect();
+    if let Err(e) = check_token_scope(&state,&token,None,&entity_ids).await {
+        return e.json_response()
+    }
+    if let Err(e) = state.dal.write().await.request_access_rights(current_user_id,entity_ids.to_owned(),&note).await {
+        return e.json_response()
+    }
+    if let Err(e) = notify_access_requested(&state,current_user_id,&entity_ids,&note).await {
+        tracing::warn!("Could not send access request notification: {e}");
     }
     let j = json!({"status":"OK"});
     (StatusCode::OK, Json(j))
 }
 
-async fn add_user_rights(State(state): State<Arc<AppState>>, Path((entity_ids,user_id,rights)): Path<(String,usize,String)>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let rights = parse_rights_string(&rights);
-    let entity_ids = match user_rights_prep(&state,entity_ids,&cookies).await {
+async fn deny_access_request(State(state): State<Arc<AppState>>, Path((entity_ids,user_id)): Path<(String,usize)>, credentials: Credentials,) -> impl IntoResponse {
+    let action = format!("deny access requests of user #{user_id}");
+    let (_current_user_id,entity_ids) = match user_rights_prep(&state,entity_ids,&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let requested = match pending_access_requests(&state,user_id,&entity_ids).await {
         Ok(ids) => ids,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+        Err(e) => return e.json_response()
+    };
+    if let Err(e) = state.dal.write().await.deny_access_requests(user_id,requested.to_owned()).await {
+        return e.json_response()
+    }
+    for entity_id in requested {
+        if let Err(e) = notify_access_decided(&state,user_id,entity_id,None).await {
+            tracing::warn!("Could not send access denied notification: {e}");
+        }
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// Creates an invitation on entities the current user is admin of. For invite links, the URL is only returned here.
+async fn create_invitation(state: &Arc<AppState>, credentials: &Credentials, kind: &str, entity_ids: String, rights: &str, target: Option<&str>) -> Result<Value,RingError> {
+    let rights = parse_rights_string(rights);
+    let action = format!("invite {} with rights '{}'",target.unwrap_or("via link"),rights.join(","));
+    let (current_user_id,entity_ids) = user_rights_prep(state,entity_ids,credentials,&action).await?;
+    if entity_ids.is_empty() || rights.is_empty() {
+        return Err(RingError::Validation("An invitation needs entities and rights".into()));
+    }
+    let (target,link) = match target {
+        Some(target) => (invitation::Invitation::normalize_target(kind,target)?,None),
+        None => {
+            let (token,token_hash) = invitation::Invitation::generate_link_token();
+            (token_hash,Some(format!("{}/invitation/accept/{token}",state.get_redirect_server())))
+        }
+    };
+    let valid_sec = state.config["invitations"]["valid_days"].as_u64().unwrap_or(30)*24*3600;
+    let invitation_id = state.dal.write().await.add_invitation(kind,&target,&entity_ids,&rights,current_user_id,valid_sec).await?;
+    if kind==invitation::KIND_EMAIL {
+        if let Err(e) = notify_invited(state,&target,current_user_id,&entity_ids,&rights).await {
+            tracing::warn!("Could not send invitation email: {e}");
+        }
+    }
+    Ok(json!({"status":"OK","invitation_id":invitation_id,"url":link}))
+}
+
+/// Invites an ORCID iD or an email address
+async fn invite_user(State(state): State<Arc<AppState>>, Path((kind,entity_ids,rights,target)): Path<(String,String,String,String)>, credentials: Credentials,) -> impl IntoResponse {
+    if kind!=invitation::KIND_ORCID && kind!=invitation::KIND_EMAIL {
+        return RingError::Validation(format!("Unknown invitation type '{kind}'")).json_response()
+    }
+    match create_invitation(&state,&credentials,&kind,entity_ids,&rights,Some(&target)).await {
+        Ok(j) => (StatusCode::OK, Json(j)),
+        Err(e) => e.json_response(),
+    }
+}
+
+/// Creates a one-time invite link
+async fn invite_link(State(state): State<Arc<AppState>>, Path((entity_ids,rights)): Path<(String,String)>, credentials: Credentials,) -> impl IntoResponse {
+    match create_invitation(&state,&credentials,invitation::KIND_LINK,entity_ids,&rights,None).await {
+        Ok(j) => (StatusCode::OK, Json(j)),
+        Err(e) => e.json_response(),
+    }
+}
+
+async fn list_invitations(State(state): State<Arc<AppState>>, Path(entity_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    if let Err(e) = user_rights_prep(&state,entity_id.to_string(),&credentials,"list invitations").await {
+        return e.json_response()
+    }
+    let mut invitations = match state.dal.read().await.get_entity_invitations(entity_id).await {
+        Ok(invitations) => invitations,
+        Err(e) => return e.json_response()
+    };
+    invitations.iter_mut()
+        .filter(|i|i.kind==invitation::KIND_LINK)
+        .for_each(|i|i.target.clear()); // Token hash
+    let j = json!({"status":"OK","invitations":invitations});
+    (StatusCode::OK, Json(j))
+}
+
+/// Revokes an open invitation; requires admin rights on all its entities
+async fn revoke_invitation(State(state): State<Arc<AppState>>, Path(invitation_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    let entity_ids = match state.dal.read().await.get_invitation(invitation_id).await {
+        Ok(Some(invitation)) => invitation.entity_ids,
+        Ok(None) => return RingError::NotFound("No such invitation".into()).json_response(),
+        Err(e) => return e.json_response(),
+    };
+    let entity_ids = entity_ids.iter().map(|id|id.to_string()).collect::<Vec<String>>().join(",");
+    let action = format!("revoke invitation #{invitation_id}");
+    if let Err(e) = user_rights_prep(&state,entity_ids,&credentials,&action).await {
+        return e.json_response()
+    }
+    match state.dal.write().await.revoke_invitation(invitation_id).await {
+        Ok(true) => {},
+        Ok(false) => return RingError::Conflict("This invitation was already accepted".into()).json_response(),
+        Err(e) => return e.json_response(),
+    }
+    let j = json!({"status":"OK"});
+    (StatusCode::OK, Json(j))
+}
+
+/// Follows a one-time invite link. Users who are not logged in are sent to the login page first, and return here afterwards.
+async fn accept_invitation(State(state): State<Arc<AppState>>, Path(token): Path<String>, cookies: Option<TypedHeader<headers::Cookie>>,) -> Result<Redirect,RingError> {
+    let invitation = state.dal.read().await.get_link_invitation(&ApiToken::hash(&token)).await?
+        .ok_or_else(||RingError::NotFound("No such invitation".into()))?;
+    let credentials = Credentials { cookies, bearer: None };
+    let user_id = match get_current_user_id(&state,&credentials).await {
+        Ok(user_id) => user_id,
+        Err(_) => {
+            let return_to = format!("/invitation/accept/{token}");
+            let url = reqwest::Url::parse_with_params(&format!("{}/",state.get_redirect_server()), &[("return_to",return_to)])
+                .map_err(|e|RingError::String(e.to_string()))?;
+            return Ok(Redirect::to(url.as_str()));
+        }
+    };
+    if !invitation::accept(&state,&invitation,user_id).await? {
+        return Err(RingError::Conflict("This invitation has already been accepted".into()));
+    }
+    let url = match invitation.entity_ids.first() {
+        Some(entity_id) => format!("/#/entity/{entity_id}"),
+        None => "/".to_string(),
+    };
+    Ok(Redirect::to(&url))
+}
+
+/// Creates an auto-grant rule on an entity. For rules on `bespoke_data`, the JSON pointer is passed as `?field=`.
+/// Rules take effect at the next login of a user, or the next periodic re-evaluation.
+async fn create_auto_grant_rule(State(state): State<Arc<AppState>>, Path((entity_id,kind,rights,value)): Path<(usize,String,String,String)>, Query(params): Query<HashMap<String, String>>, credentials: Credentials,) -> impl IntoResponse {
+    let rights = parse_rights_string(&rights);
+    let field = params.get("field").map(|s|s.as_str()).unwrap_or_default();
+    let action = format!("create auto-grant rule {kind} '{value}' with rights '{}'",rights.join(","));
+    let (current_user_id,_entity_ids) = match user_rights_prep(&state,entity_id.to_string(),&credentials,&action).await {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    if rights.is_empty() {
+        return RingError::Validation("An auto-grant rule needs rights".into()).json_response()
+    }
+    // Rules bypass approvals, so they would undermine a multi-admin approval policy
+    match state.dal.read().await.get_required_approvals(entity_id).await {
+        Ok(1) => {},
+        Ok(_) => return RingError::Conflict("Auto-grant rules are not available on entities that require multiple approvals".into()).json_response(),
+        Err(e) => return e.json_response(),
+    }
+    let (field,value) = match auto_grant::AutoGrantRule::normalize(&kind,field,&value) {
+        Ok(x) => x,
+        Err(e) => return e.json_response()
+    };
+    let rule_id = match state.dal.write().await.add_auto_grant_rule(entity_id,&kind,&field,&value,&rights,current_user_id).await {
+        Ok(id) => id,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","rule_id":rule_id});
+    (StatusCode::OK, Json(j))
+}
+
+async fn list_auto_grant_rules(State(state): State<Arc<AppState>>, Path(entity_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    if let Err(e) = user_rights_prep(&state,entity_id.to_string(),&credentials,"list auto-grant rules").await {
+        return e.json_response()
+    }
+    let rules = match state.dal.read().await.get_entity_auto_grant_rules(entity_id).await {
+        Ok(rules) => rules,
+        Err(e) => return e.json_response()
+    };
+    let j = json!({"status":"OK","rules":rules});
+    (StatusCode::OK, Json(j))
+}
+
+/// Deletes an auto-grant rule, and removes the rights it granted unless another rule grants them as well
+async fn remove_auto_grant_rule(State(state): State<Arc<AppState>>, Path(rule_id): Path<usize>, credentials: Credentials,) -> impl IntoResponse {
+    let entity_id = match state.dal.read().await.get_auto_grant_rule(rule_id).await {
+        Ok(Some(rule)) => rule.entity_id,
+        Ok(None) => return RingError::NotFound("No such auto-grant rule".into()).json_response(),
+        Err(e) => return e.json_response(),
+    };
+    let action = format!("remove auto-grant rule #{rule_id}");
+    if let Err(e) = user_rights_prep(&state,entity_id.to_string(),&credentials,&action).await {
+        return e.json_response()
+    }
+    let user_ids = match state.dal.read().await.get_users_with_auto_grants(Some(rule_id)).await {
+        Ok(user_ids) => user_ids,
+        Err(e) => return e.json_response(),
     };
-    if let Err(e) = state.dal.write().await.add_access_rights(user_id,entity_ids,rights).await {
-        return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+    if let Err(e) = state.dal.write().await.remove_auto_grant_rule(rule_id).await {
+        return e.json_response()
+    }
+    let rules = match state.dal.read().await.get_auto_grant_rules().await {
+        Ok(rules) => rules,
+        Err(e) => return e.json_response(),
+    };
+    if let Err(e) = auto_grant::apply_to_users(&state,&user_ids,&rules).await {
+        return e.json_response()
     }
     let j = json!({"status":"OK"});
     (StatusCode::OK, Json(j))
 }
 
-async fn remove_user_rights(State(state): State<Arc<AppState>>, Path((entity_ids,user_id,rights)): Path<(String,usize,String)>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let rights = parse_rights_string(&rights);
-    let entity_ids = match user_rights_prep(&state,entity_ids,&cookies).await {
-        Ok(ids) => ids,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
-    };
-    let j = json!({"status":"OK","rights":rights,"entities":entity_ids,"user":user_id});
-    if let Err(e) = state.dal.write().await.remove_access_rights(user_id,entity_ids,rights).await {
-        return (StatusCode::OK, Json(json!({"status":e.to_string()})))
-    }
-    (StatusCode::OK, Json(j))
+/// Returns the current user, and their effective rights on the given entities, restricted to the API token used (if any)
+async fn current_user_entity_rights(state: &Arc<AppState>, credentials: &Credentials, entity_ids: &str) -> Result<(ExternalSystemUser,Vec<Entity>),RingError> {
+    let (user,token) = credentials.user(state).await?.ok_or_else(||RingError::Unauthenticated("not_logged_in".into()))?;
+    let user_id = user.id.ok_or_else(||RingError::String("logged in but no user ID".into()))? as usize;
+    let entity_ids: Vec<usize> = entity_ids.split(',').filter_map(|e|e.parse::<usize>().ok()).collect();
+    let entities = user_entity_rights_for(state,user_id,&token,&entity_ids).await?;
+    Ok((user,entities))
 }
 
-async fn request_access_rights(State(state): State<Arc<AppState>>, Path((entity_ids,note)): Path<(String,String)>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let current_user_id = match get_current_user_id(&state,&cookies).await {
-        Ok(id) => id,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()})))
-    };
-    let entity_ids: Vec<usize> = entity_ids
-        .split(',')
-        .filter_map(|e|e.parse::<usize>().ok())
+/// Returns the effective rights of a user on the given entities, restricted to an API token (if any)
+async fn user_entity_rights_for(state: &Arc<AppState>, user_id: usize, token: &Option<ApiToken>, entity_ids: &[usize]) -> Result<Vec<Entity>,RingError> {
+    let allowed_entities = state.dal.read().await.get_all_user_rights_for_entities(user_id,None).await?;
+    let entities: Vec<Entity> = entity_ids
+        .iter()
+        .filter_map(|entity_id| allowed_entities.get(*entity_id))
+        .cloned()
         .collect();
-    if let Err(e) = state.dal.write().await.request_access_rights(current_user_id,entity_ids,&note).await {
-        return (StatusCode::OK, Json(json!({"status":e.to_string()})))
-    }
-    let j = json!({"status":"OK"});
-    (StatusCode::OK, Json(j))
+    restrict_to_token(state,token,entities).await
 }
 
-async fn user_entity_rights(State(state): State<Arc<AppState>>, Path(entity_ids): Path<String>, cookies: Option<TypedHeader<headers::Cookie>>,) -> impl IntoResponse {
-    let user = match ExternalSystemUser::from_cookies(&state, &cookies).await {
-        Some(user) => user,
-        None => return (StatusCode::OK, Json(json!({"status":"not_logged_in"}))),
-    };
-    let user_id = match user.id {
-        Some(id) => id as usize,
-        None => return (StatusCode::OK, Json(json!({"status":"logged in but no user ID"}))),
+async fn user_entity_rights(State(state): State<Arc<AppState>>, Path(entity_ids): Path<String>, credentials: Credentials,) -> impl IntoResponse {
+    let entities = match current_user_entity_rights(&state,&credentials,&entity_ids).await {
+        Ok((_user,entities)) => entities,
+        Err(e) => return e.json_response(),
     };
 
-    let allowed_entities = match state.dal.read().await.get_all_user_rights_for_entities(user_id,None).await {
+    let j = json!({
+        "status":"OK",
+        "entities":entities,
+    });
+    (StatusCode::OK, Json(j))
+}
+
+/// Issues a short-lived signed JWT asserting the current user's effective rights on the given entities.
+/// It can be verified offline with the key from `/rights/assertion/key`.
+async fn user_rights_assertion(State(state): State<Arc<AppState>>, Path(entity_ids): Path<String>, credentials: Credentials,) -> impl IntoResponse {
+    let idp = match &state.idp {
+        Some(idp) => idp,
+        None => return RingError::NotFound("No signing key configured".into()).json_response(),
+    };
+    let (user,entities) = match current_user_entity_rights(&state,&credentials,&entity_ids).await {
         Ok(x) => x,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
+    let user_id = user.id.unwrap_or_default() as usize;
+    let principal_id = state.dal.read().await.get_principal_id(user_id).await.unwrap_or(user_id);
+    let rights: HashMap<String,Vec<String>> = entities.iter().map(|e|(e.id.to_string(),e.rights.to_owned())).collect();
+    let now = idp::Idp::now();
+    let claims = json!({
+        "iss":idp.issuer,
+        "sub":principal_id.to_string(),
+        "system":user.system.as_str(),
+        "external_id":user.external_id,
+        "rights":rights,
+        "iat":now,
+        "exp":now+idp.assertion_ttl,
+        "jti":random_string("",16),
+    });
+    match idp.sign(idp::JWT_TYPE_ASSERTION,&claims) {
+        Ok(assertion) => (StatusCode::OK, Json(json!({"status":"OK","assertion":assertion,"expires_in":idp.assertion_ttl}))),
+        Err(e) => e.json_response(),
+    }
+}
 
-    let entities: Vec<&Entity> = entity_ids
-        .split(',')
-        .filter_map(|e|e.parse::<usize>().ok())
-        .filter_map(|entity_id| allowed_entities.get(entity_id))
-        .collect();
-
+/// The public key to verify rights assertions, as JWKS and PEM
+async fn rights_assertion_key(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let idp = match &state.idp {
+        Some(idp) => idp,
+        None => return RingError::NotFound("No signing key configured".into()).json_response(),
+    };
+    let pem = match idp.public_key_pem() {
+        Ok(pem) => pem,
+        Err(e) => return e.json_response(),
+    };
     let j = json!({
         "status":"OK",
-        "entities":entities,
+        "alg":"RS256",
+        "kid":idp.key_id(),
+        "typ":idp::JWT_TYPE_ASSERTION,
+        "issuer":idp.issuer,
+        "jwks":idp.jwks(),
+        "pem":pem,
     });
     (StatusCode::OK, Json(j))
 }
@@ -338,15 +1940,15 @@ async fn entities(State(state): State<Arc<AppState>>, Path(entity_ids): Path<Str
         .collect();
     let mut entities = match state.dal.read().await.load_entities(&entity_ids).await {
         Ok(x) => x,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
     if let Err(e) = state.dal.read().await.annotate_entities(&mut entities).await {
-        return (StatusCode::OK, Json(json!({"status":e.to_string()})))
+        return e.json_response()
     }
     let entities = entities.as_sorted_vec();
     let (parents,children) = match parents_children_entities(state,&entities).await {
         Ok(x) => x,
-        Err(e) => return (StatusCode::OK, Json(json!({"status":e.to_string()}))),
+        Err(e) => return e.json_response(),
     };
     let j = json!({
         "status":"OK",
@@ -359,134 +1961,714 @@ async fn entities(State(state): State<Arc<AppState>>, Path(entity_ids): Path<Str
 
 async fn redirect_google(State(state): State<Arc<AppState>>, 
     Query(params): Query<HashMap<String, String>>, 
-    _cookies: Option<TypedHeader<headers::Cookie>>,
-) -> impl IntoResponse {
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+) -> LoginRedirect {
+    let login = finish_login(&state,&cookies,"google",&params).await?;
     let code = match params.get("code") {
         Some(code) => code,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        None => return Err(RingError::Validation("No authorization code".into())),
     };
     
     let redirect_url = format!("{}/redirect/google",state.get_redirect_server());
-    let client_id = state.config["systems"]["google"]["client_id"].as_str().expect("Google client_id");
-    let client_secret = state.config["systems"]["google"]["client_secret"].as_str().expect("Google client_secret");
-    let body = format!("client_id={client_id}&client_secret={client_secret}&grant_type=authorization_code&code={code}&redirect_uri={redirect_url}");
+    let client_id = system_config(&state,"google","client_id")?;
+    let client_secret = system_config(&state,"google","client_secret")?;
+    let mut form = vec![
+        ("client_id",client_id),
+        ("client_secret",client_secret),
+        ("grant_type","authorization_code"),
+        ("code",code.as_str()),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    form.append(&mut login.token_params());
 
     let j = reqwest::Client::new()
         .post("https://oauth2.googleapis.com/token")
         .header(ACCEPT, "application/json")
-        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .body(body)
+        .form(&form)
         .send()
-        .await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?
-        .json::<Value>().await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .await?
+        .json::<Value>().await?;
 
     // let _access_token = j["access_token"].as_str()
     //     .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
     //     .to_string();
     let id_token = j["id_token"].as_str()
-        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(||RingError::Upstream("No 'id_token' from Google".into()))?
         .to_string();
 
     let client = AsyncClient::new(client_id);
     let data = match client.validate_id_token(id_token).await {
         Ok(data) => data,
-        Err(_e) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(e) => return Err(RingError::Upstream(format!("Invalid ID token from Google: {e}"))),
     };
 
     let j = json!(data);
 
     let name = j["name"].as_str()
-        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(||RingError::Upstream("No 'name' from Google".into()))?
         .to_string();
     let external_id = j["sub"].as_str()
-        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(||RingError::Upstream("No 'sub' from Google".into()))?
         .to_string();
     let email = j["email"].as_str()
-        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(||RingError::Upstream("No 'email' from Google".into()))?
         .to_string();
+    let email_verified = j["email_verified"].as_bool().unwrap_or(false);
 
-    let mut user = ExternalSystemUser {
+    let user = ExternalSystemUser {
         id: None,
         system: ExternalSystem::GOOGLE,
         name,
         external_id,
         email,
+        email_verified,
         bespoke_data: j,
+        principal_id: None,
+        deactivated: false,
     };
-    let _user_id = user
-        .add_to_database(state.clone())
-        .await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let cookie = user.set_cookie(state).await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Set cookie
-    let mut headers = HeaderMap::new();
-    let val = cookie.parse().map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
-    headers.insert(SET_COOKIE, val);
-
-    Ok((headers, Redirect::to("/")))    
+    complete_login(state, user, &cookies, &user_agent, &login).await
 }
 
 async fn redirect_orcid(State(state): State<Arc<AppState>>, 
     Query(params): Query<HashMap<String, String>>, 
-    _cookies: Option<TypedHeader<headers::Cookie>>,
-) -> impl IntoResponse {
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+) -> LoginRedirect {
+    let login = finish_login(&state,&cookies,"orcid",&params).await?;
     let code = match params.get("code") {
         Some(code) => code,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        None => return Err(RingError::Validation("No authorization code".into())),
     };
     
     let redirect_url = format!("{}/redirect/orcid",state.get_redirect_server());
-    let client_id = state.config["systems"]["orcid"]["client_id"].as_str().expect("ORCID client_id");
-    let client_secret = state.config["systems"]["orcid"]["client_secret"].as_str().expect("ORCID client_secret");
-    let body = format!("client_id={client_id}&client_secret={client_secret}&grant_type=authorization_code&code={code}&redirect_uri={redirect_url}");
+    let client_id = system_config(&state,"orcid","client_id")?;
+    let client_secret = system_config(&state,"orcid","client_secret")?;
+    let mut form = vec![
+        ("client_id",client_id),
+        ("client_secret",client_secret),
+        ("grant_type","authorization_code"),
+        ("code",code.as_str()),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    form.append(&mut login.token_params());
 
     let j = reqwest::Client::new()
         .post("https://orcid.org/oauth/token")
         .header(ACCEPT, "application/json")
-        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .body(body)
+        .form(&form)
         .send()
-        .await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?
-        .json::<Value>().await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .await?
+        .json::<Value>().await?;
 
     let name = j["name"].as_str()
-        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(||RingError::Upstream("No 'name' from ORCID".into()))?
         .to_string();
     let external_id = j["orcid"].as_str()
-        .ok_or_else(|| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(||RingError::Upstream("No 'orcid' from ORCID".into()))?
         .to_string();
 
-    let mut user = ExternalSystemUser {
+    let user = ExternalSystemUser {
         id: None,
         system: ExternalSystem::ORCID,
         name,
         external_id,
         email: String::new(),
+        email_verified: false,
+        bespoke_data: j,
+        principal_id: None,
+        deactivated: false,
+    };
+    complete_login(state, user, &cookies, &user_agent, &login).await
+}
+
+/// Exchanges a Wikimedia authorization code for the profile of the user
+async fn fetch_wikimedia_user(base_url: &str, client_id: &str, client_secret: &str, code: &str, redirect_url: &str, code_verifier: Option<&str>) -> Result<ExternalSystemUser,RingError> {
+    let client = reqwest::Client::new();
+    let mut form = vec![
+        ("client_id",client_id),
+        ("client_secret",client_secret),
+        ("grant_type","authorization_code"),
+        ("code",code),
+        ("redirect_uri",redirect_url),
+    ];
+    if let Some(code_verifier) = code_verifier {
+        form.push(("code_verifier",code_verifier));
+    }
+    let j = client
+        .post(format!("{base_url}/w/rest.php/oauth2/access_token"))
+        .header(ACCEPT, "application/json")
+        .form(&form)
+        .send()
+        .await?
+        .json::<Value>().await?;
+    let access_token = j["access_token"].as_str()
+        .ok_or_else(||RingError::Upstream("No 'access_token' from Wikimedia".into()))?;
+
+    let j = client
+        .get(format!("{base_url}/w/rest.php/oauth2/resource/profile"))
+        .header(ACCEPT, "application/json")
+        .bearer_auth(access_token)
+        .send()
+        .await?
+        .json::<Value>().await?;
+
+    // The central user ID is stable across renames; the user name is kept in bespoke_data, see ExternalSystemUser::external_name
+    let external_id = match &j["sub"] {
+        Value::String(sub) if !sub.is_empty() => sub.to_owned(),
+        Value::Number(sub) => sub.to_string(),
+        _ => return Err(RingError::Upstream("No 'sub' from Wikimedia".into())),
+    };
+    let name = j["realname"].as_str()
+        .filter(|s|!s.is_empty())
+        .or(j["username"].as_str())
+        .unwrap_or(&external_id)
+        .to_string();
+    let email = j["email"].as_str().unwrap_or_default().to_string();
+    let email_verified = j["confirmed_email"].as_bool().unwrap_or(false);
+
+    Ok(ExternalSystemUser {
+        id: None,
+        system: ExternalSystem::WIKIMEDIA,
+        name,
+        external_id,
+        email,
+        email_verified,
         bespoke_data: j,
+        principal_id: None,
+        deactivated: false,
+    })
+}
+
+async fn redirect_wikimedia(State(state): State<Arc<AppState>>, 
+    Query(params): Query<HashMap<String, String>>, 
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+) -> LoginRedirect {
+    let login = finish_login(&state,&cookies,"wikimedia",&params).await?;
+    let code = match params.get("code") {
+        Some(code) => code,
+        None => return Err(RingError::Validation("No authorization code".into())),
+    };
+
+    let base_url = state.wikimedia_base_url();
+    let redirect_url = format!("{}/redirect/wikimedia",state.get_redirect_server());
+    let client_id = system_config(&state,"wikimedia","client_id")?;
+    let client_secret = system_config(&state,"wikimedia","client_secret")?;
+    let user = fetch_wikimedia_user(&base_url,client_id,client_secret,code,&redirect_url,login.pkce_verifier.as_deref()).await?;
+    complete_login(state, user, &cookies, &user_agent, &login).await
+}
+
+async fn redirect_github(State(state): State<Arc<AppState>>, 
+    Query(params): Query<HashMap<String, String>>, 
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+) -> LoginRedirect {
+    let login = finish_login(&state,&cookies,"github",&params).await?;
+    let code = match params.get("code") {
+        Some(code) => code,
+        None => return Err(RingError::Validation("No authorization code".into())),
+    };
+
+    let redirect_url = format!("{}/redirect/github",state.get_redirect_server());
+    let client_id = system_config(&state,"github","client_id")?;
+    let client_secret = system_config(&state,"github","client_secret")?;
+    let client = reqwest::Client::new();
+
+    let mut form = vec![
+        ("client_id",client_id),
+        ("client_secret",client_secret),
+        ("code",code.as_str()),
+        ("redirect_uri",redirect_url.as_str()),
+    ];
+    form.append(&mut login.token_params());
+    let j = client
+        .post("https://github.com/login/oauth/access_token")
+        .header(ACCEPT, "application/json")
+        .form(&form)
+        .send()
+        .await?
+        .json::<Value>().await?;
+    let access_token = j["access_token"].as_str()
+        .ok_or_else(||RingError::Upstream("No 'access_token' from GitHub".into()))?;
+
+    // The GitHub API requires a User-Agent header
+    let github_api = |path: &str| client
+        .get(format!("https://api.github.com{path}"))
+        .header(ACCEPT, "application/vnd.github+json")
+        .header(USER_AGENT, "sauron")
+        .bearer_auth(access_token)
+        .send();
+
+    let mut j = github_api("/user").await?
+        .json::<Value>().await?;
+    // A missing organisation list would remove all organisation-based grants, so a failure here fails the login
+    let mut orgs: Vec<String> = vec![];
+    let mut next_page = Some("/user/orgs?per_page=100".to_string());
+    while let Some(path) = next_page {
+        let response = github_api(&path).await?.error_for_status()?;
+        next_page = response.headers().get(LINK)
+            .and_then(|link|link.to_str().ok())
+            .and_then(github_next_page);
+        let page = response.json::<Vec<Value>>().await?;
+        orgs.extend(page.iter().filter_map(|org|org["login"].as_str()).map(|org|org.to_string()));
+    }
+    j["orgs"] = json!(orgs);
+
+    // The numeric ID is stable across renames; the login is kept in bespoke_data, see ExternalSystemUser::external_name
+    let external_id = j["id"].as_u64()
+        .ok_or_else(||RingError::Upstream("No 'id' from GitHub".into()))?
+        .to_string();
+    let name = j["name"].as_str()
+        .filter(|s|!s.is_empty())
+        .or(j["login"].as_str())
+        .unwrap_or(&external_id)
+        .to_string();
+    let emails = github_api("/user/emails").await?
+        .json::<Vec<Value>>().await
+        .unwrap_or_default();
+    let is_verified = |email: &str| emails.iter().any(|e|e["email"].as_str()==Some(email) && e["verified"].as_bool()==Some(true));
+    let (email,email_verified) = match j["email"].as_str() {
+        Some(email) => (email.to_string(),is_verified(email)),
+        None => match emails.iter().find(|e|e["primary"].as_bool()==Some(true) && e["verified"].as_bool()==Some(true)).and_then(|e|e["email"].as_str()) {
+            Some(email) => (email.to_string(),true),
+            None => (String::new(),false),
+        },
+    };
+
+    let user = ExternalSystemUser {
+        id: None,
+        system: ExternalSystem::GITHUB,
+        name,
+        external_id,
+        email,
+        email_verified,
+        bespoke_data: j,
+        principal_id: None,
+        deactivated: false,
     };
-    let _user_id = user
+    complete_login(state, user, &cookies, &user_agent, &login).await
+}
+
+/// Returns the API path of the next page from a GitHub `Link` header, if there is one
+fn github_next_page(link: &str) -> Option<String> {
+    link.split(',')
+        .find(|part|part.split(';').skip(1).any(|param|param.trim()=="rel=\"next\""))
+        .and_then(|part|part.split(';').next())
+        .map(|url|url.trim().trim_start_matches('<').trim_end_matches('>'))
+        .and_then(|url|url.strip_prefix("https://api.github.com"))
+        .map(|path|path.to_string())
+}
+
+/// Brings the rights configured in `systems.github.org_grants` in line with the GitHub organisations of a user's identities:
+/// rights of matching organisations are added, and organisation-based rights that no longer match are removed.
+/// Manually granted rights are never touched.
+async fn apply_github_org_grants(state: &Arc<AppState>, user_id: usize) -> Result<(),RingError> {
+    let org_grants = state.config["systems"]["github"]["org_grants"].as_array().cloned().unwrap_or_default();
+    let mut dal = state.dal.write().await;
+    let principal_id = dal.get_principal_id(user_id).await?;
+    let orgs: Vec<String> = dal.get_linked_users(principal_id).await?
+        .iter()
+        .flat_map(|user|user.github_orgs())
+        .collect();
+    let mut wanted: Vec<(usize,String,String)> = vec![];
+    for org_grant in &org_grants {
+        let org = org_grant["org"].as_str().unwrap_or_default().to_lowercase();
+        if !orgs.contains(&org) {
+            continue;
+        }
+        let entity_ids = org_grant["entity_ids"].as_array().cloned().unwrap_or_default();
+        let rights = org_grant["rights"].as_array().cloned().unwrap_or_default();
+        for entity_id in entity_ids.iter().filter_map(|id|id.as_u64()).map(|id|id as usize) {
+            for right in rights.iter().filter_map(|r|r.as_str()).map(|r|r.to_lowercase()) {
+                if !wanted.iter().any(|(e,r,_)|*e==entity_id && *r==right) {
+                    wanted.push((entity_id,right,org.to_owned()));
+                }
+            }
+        }
+    }
+    for (entity_id,right,org) in dal.get_github_org_grants(principal_id).await? {
+        if !wanted.iter().any(|(e,r,_)|*e==entity_id && *r==right) {
+            dal.remove_github_org_right(principal_id,entity_id,&right,&org).await?;
+            tracing::info!("Removed '{right}' on entity #{entity_id} from user #{principal_id}, no longer in GitHub organisation '{org}'");
+        }
+    }
+    for (entity_id,right,org) in &wanted {
+        if dal.add_github_org_right(principal_id,*entity_id,right,org).await? {
+            tracing::info!("Granted '{right}' on entity #{entity_id} to user #{principal_id} via GitHub organisation '{org}'");
+        }
+    }
+    Ok(())
+}
+
+async fn redirect_oidc(State(state): State<Arc<AppState>>, 
+    Path(key): Path<String>,
+    Query(params): Query<HashMap<String, String>>, 
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+) -> LoginRedirect {
+    let login = finish_login(&state,&cookies,&key,&params).await?;
+    let code = match params.get("code") {
+        Some(code) => code,
+        None => return Err(RingError::Validation("No authorization code".into())),
+    };
+    let provider = state.oidc_providers.get(&key).ok_or_else(||RingError::NotFound(format!("No login system '{key}'")))?;
+    let redirect_url = format!("{}/redirect/oidc/{key}",state.get_redirect_server());
+    let user = provider.fetch_user(code,&redirect_url,login.pkce_verifier.as_deref()).await?;
+    complete_login(state.clone(), user, &cookies, &user_agent, &login).await
+}
+
+/// Adds/updates a user who has logged in via an external system, sets the session cookie,
+/// and redirects to the (already validated) return URL
+async fn complete_login(state: Arc<AppState>, mut user: ExternalSystemUser, cookies: &Option<TypedHeader<headers::Cookie>>, user_agent: &Option<TypedHeader<headers::UserAgent>>, login: &login_flow::PendingLogin) -> LoginRedirect {
+    let user_id = user
         .add_to_database(state.clone())
-        .await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .await?;
+
+    // Deactivated users can not log in
+    if state.dal.read().await.is_user_deactivated(user_id as usize).await? {
+        tracing::info!("Rejected login of deactivated user #{user_id}");
+        return Err(RingError::Forbidden("This account has been deactivated".into()));
+    }
+
+    if user.system==ExternalSystem::GITHUB {
+        apply_github_org_grants(&state,user_id as usize).await?;
+    }
+
+    // Rule-based grants, e.g. by email domain
+    if let Err(e) = auto_grant::apply_at_login(&state,user_id as usize).await {
+        tracing::warn!("Could not apply auto-grant rules for user #{user_id}: {e}");
+    }
+
+    // Linking another identity to the logged-in user; keep the current session
+    if let Some(principal_id) = login.link_principal {
+        if state.dal.read().await.get_principal_id(user_id as usize).await.ok()!=Some(principal_id) {
+            state.dal.write().await.link_users(principal_id,user_id as usize).await?;
+        }
+        return Ok((HeaderMap::new(), Redirect::to(&login.return_to)));
+    }
 
-    let cookie = user.set_cookie(state).await
-        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // The pre-login session is replaced by a new one
+    if let Some(session) = session_from_cookies(&state,cookies).await {
+        let _ = state.dal.read().await.session_store.destroy_session(session).await;
+    }
+    let user_agent = user_agent.as_ref().map(|TypedHeader(ua)|ua.as_str()).unwrap_or_default();
+    let cookie = user.set_cookie(state,user_agent).await?;
 
     // Set cookie
     let mut headers = HeaderMap::new();
-    let val = cookie.parse().map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let val = cookie.parse().map_err(|_e| RingError::String("Invalid session cookie".into()))?;
     headers.insert(SET_COOKIE, val);
 
-    Ok((headers, Redirect::to("/")))
+    Ok((headers, Redirect::to(&login.return_to)))
+}
+
+
+// REST API v1: state changes via POST/PUT/PATCH/DELETE with JSON bodies. These delegate to the handlers of the
+// deprecated GET routes. JSON bodies can not be sent cross-site without a CORS preflight, which sauron does not allow.
+
+#[derive(Deserialize)]
+struct ApiRightsBody {
+    entity_ids: Vec<usize>,
+    user_id: usize,
+    rights: Vec<String>,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiCopyRightsBody {
+    from_user_id: usize,
+    to_user_id: usize,
+    entity_id: Option<usize>,
+    #[serde(default)]
+    dry_run: bool,
 }
 
+#[derive(Deserialize)]
+struct ApiRevokeSubtreeBody {
+    entity_id: usize,
+    user_id: usize,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiAccessRequestBody {
+    entity_ids: Vec<usize>,
+    #[serde(default)]
+    note: String,
+}
+
+#[derive(Deserialize)]
+struct ApiDenyAccessRequestBody {
+    entity_ids: Vec<usize>,
+    user_id: usize,
+}
+
+#[derive(Deserialize)]
+struct ApiEntityChildBody {
+    name: String,
+    #[serde(default)]
+    external_id: String,
+}
+
+#[derive(Deserialize)]
+struct ApiApprovalPolicyBody {
+    entity_ids: Vec<usize>,
+    required_approvals: usize,
+}
+
+#[derive(Deserialize)]
+struct ApiTokenBody {
+    name: String,
+    days: u64,
+    #[serde(default)]
+    rights: Vec<String>,
+    #[serde(default)]
+    entity_ids: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct ApiConsentBody {
+    token: String,
+    allow: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiUserBody {
+    deactivated: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiMergeUsersBody {
+    from_id: usize,
+    into_id: usize,
+}
+
+#[derive(Deserialize)]
+struct ApiServiceAccountBody {
+    entity_id: usize,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ApiSuperuserBody {
+    is_superuser: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiInvitationBody {
+    kind: String,
+    entity_ids: Vec<usize>,
+    rights: Vec<String>,
+    target: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiAutoGrantRuleBody {
+    entity_id: usize,
+    kind: String,
+    #[serde(default)]
+    field: String,
+    value: String,
+    rights: Vec<String>,
+}
+
+fn ids_string(ids: &[usize]) -> String {
+    ids.iter().map(|id|id.to_string()).collect::<Vec<String>>().join(",")
+}
+
+/// Builds the query parameters that the old GET routes take for boolean options
+fn flag_params(flags: &[(&str,bool)]) -> Query<HashMap<String, String>> {
+    Query(flags.iter().filter(|(_,set)|*set).map(|(key,_)|(key.to_string(),"1".to_string())).collect())
+}
+
+async fn api_set_rights(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiRightsBody>) -> Response {
+    let path = Path((ids_string(&body.entity_ids),body.user_id,body.rights.join(",")));
+    set_user_rights(State(state),path,flag_params(&[("force",body.force)]),credentials).await.into_response()
+}
+
+async fn api_add_rights(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiRightsBody>) -> Response {
+    let path = Path((ids_string(&body.entity_ids),body.user_id,body.rights.join(",")));
+    add_user_rights(State(state),path,credentials).await.into_response()
+}
+
+async fn api_remove_rights(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiRightsBody>) -> Response {
+    let path = Path((ids_string(&body.entity_ids),body.user_id,body.rights.join(",")));
+    remove_user_rights(State(state),path,flag_params(&[("force",body.force)]),credentials).await.into_response()
+}
+
+async fn api_copy_rights(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiCopyRightsBody>) -> Response {
+    let mut params = flag_params(&[("dry_run",body.dry_run)]);
+    if let Some(entity_id) = body.entity_id {
+        params.0.insert("entity_id".into(),entity_id.to_string());
+    }
+    copy_user_rights(State(state),Path((body.from_user_id,body.to_user_id)),params,credentials).await.into_response()
+}
+
+async fn api_revoke_subtree_rights(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiRevokeSubtreeBody>) -> Response {
+    let params = flag_params(&[("force",body.force)]);
+    revoke_user_subtree_rights(State(state),Path((body.entity_id,body.user_id)),params,credentials).await.into_response()
+}
+
+async fn api_request_access(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiAccessRequestBody>) -> Response {
+    request_access_rights(State(state),Path((ids_string(&body.entity_ids),body.note)),credentials).await.into_response()
+}
+
+async fn api_deny_access_request(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiDenyAccessRequestBody>) -> Response {
+    deny_access_request(State(state),Path((ids_string(&body.entity_ids),body.user_id)),credentials).await.into_response()
+}
+
+async fn api_add_entity_child(State(state): State<Arc<AppState>>, Path(entity_id): Path<usize>, credentials: Credentials, JsonBody(body): JsonBody<ApiEntityChildBody>) -> Response {
+    add_entity_child(State(state),Path((entity_id,body.name,body.external_id)),credentials).await.into_response()
+}
+
+async fn api_set_approval_policy(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiApprovalPolicyBody>) -> Response {
+    set_approval_policy(State(state),Path((ids_string(&body.entity_ids),body.required_approvals)),credentials).await.into_response()
+}
+
+async fn api_add_entity_parent(State(state): State<Arc<AppState>>, path: Path<(usize,usize)>, credentials: Credentials) -> Response {
+    admin_add_entity_parent(State(state),path,credentials).await.into_response()
+}
+
+async fn api_remove_entity_parent(State(state): State<Arc<AppState>>, path: Path<(usize,usize)>, credentials: Credentials) -> Response {
+    admin_remove_entity_parent(State(state),path,credentials).await.into_response()
+}
+
+async fn api_create_token(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiTokenBody>) -> Response {
+    let params = HashMap::from([
+        ("rights".to_string(),body.rights.join(",")),
+        ("entity_ids".to_string(),ids_string(&body.entity_ids)),
+    ]);
+    create_user_token(State(state),Path((body.name,body.days)),Query(params),credentials).await.into_response()
+}
+
+async fn api_revoke_token(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    revoke_user_token(State(state),path,credentials).await.into_response()
+}
+
+async fn api_revoke_session(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    revoke_user_session(State(state),path,credentials).await.into_response()
+}
+
+async fn api_revoke_all_sessions(State(state): State<Arc<AppState>>, credentials: Credentials) -> Response {
+    revoke_all_user_sessions(State(state),credentials).await.into_response()
+}
+
+async fn api_admin_revoke_sessions(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    admin_revoke_user_sessions(State(state),path,credentials).await.into_response()
+}
+
+/// Changes a user; currently only (de)activation
+async fn api_update_user(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials, JsonBody(body): JsonBody<ApiUserBody>) -> Response {
+    match body.deactivated {
+        true => deactivate_user(State(state),path,credentials).await.into_response(),
+        false => reactivate_user(State(state),path,credentials).await.into_response(),
+    }
+}
+
+async fn api_merge_users(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiMergeUsersBody>) -> Response {
+    merge_users(State(state),Path((body.from_id,body.into_id)),credentials).await.into_response()
+}
+
+async fn api_create_service_account(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiServiceAccountBody>) -> Response {
+    create_service_account(State(state),Path((body.entity_id,body.name)),credentials).await.into_response()
+}
+
+async fn api_reset_service_account_secret(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    reset_service_account_secret(State(state),path,credentials).await.into_response()
+}
+
+async fn api_update_service_account(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials, JsonBody(body): JsonBody<ApiUserBody>) -> Response {
+    set_service_account_deactivated(State(state),Path((user_id,body.deactivated)),credentials).await.into_response()
+}
+
+async fn api_delete_service_account(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    delete_service_account(State(state),path,credentials).await.into_response()
+}
+
+async fn api_oidc_consent(State(state): State<Arc<AppState>>, cookies: Option<TypedHeader<headers::Cookie>>, JsonBody(body): JsonBody<ApiConsentBody>) -> Response {
+    match oidc_consent(&state,cookies,&body.token,body.allow).await {
+        Ok(redirect) => (StatusCode::OK, Json(json!({"status":"OK","redirect":redirect}))).into_response(),
+        Err(e) => e.json_response().into_response(),
+    }
+}
+
+async fn api_set_superuser(State(state): State<Arc<AppState>>, Path(user_id): Path<usize>, credentials: Credentials, JsonBody(body): JsonBody<ApiSuperuserBody>) -> Response {
+    set_superuser(State(state),Path((user_id,body.is_superuser)),credentials).await.into_response()
+}
+
+async fn api_create_invitation(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiInvitationBody>) -> Response {
+    let entity_ids = ids_string(&body.entity_ids);
+    let rights = body.rights.join(",");
+    match body.target {
+        Some(target) => invite_user(State(state),Path((body.kind,entity_ids,rights,target)),credentials).await.into_response(),
+        None if body.kind==invitation::KIND_LINK => invite_link(State(state),Path((entity_ids,rights)),credentials).await.into_response(),
+        None => RingError::Validation("This invitation needs a target".into()).json_response().into_response(),
+    }
+}
+
+async fn api_revoke_invitation(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    revoke_invitation(State(state),path,credentials).await.into_response()
+}
+
+async fn api_create_auto_grant_rule(State(state): State<Arc<AppState>>, credentials: Credentials, JsonBody(body): JsonBody<ApiAutoGrantRuleBody>) -> Response {
+    let path = Path((body.entity_id,body.kind,body.rights.join(","),body.value));
+    let params = HashMap::from([("field".to_string(),body.field)]);
+    create_auto_grant_rule(State(state),path,Query(params),credentials).await.into_response()
+}
+
+async fn api_remove_auto_grant_rule(State(state): State<Arc<AppState>>, path: Path<usize>, credentials: Credentials) -> Response {
+    remove_auto_grant_rule(State(state),path,credentials).await.into_response()
+}
+
+fn api_v1_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/rights", put(api_set_rights).post(api_add_rights))
+        .route("/rights/remove", post(api_remove_rights))
+        .route("/rights/copy", post(api_copy_rights))
+        .route("/rights/revoke_subtree", post(api_revoke_subtree_rights))
+        .route("/access_requests", post(api_request_access))
+        .route("/access_requests/remove", post(api_deny_access_request))
+        .route("/entities/approval_policy", put(api_set_approval_policy))
+        .route("/entities/:entity_id/children", post(api_add_entity_child))
+        .route("/entities/:entity_id/parents/:parent_id", post(api_add_entity_parent).delete(api_remove_entity_parent))
+        .route("/user/tokens", post(api_create_token))
+        .route("/user/tokens/:token_id", delete(api_revoke_token))
+        .route("/user/sessions", delete(api_revoke_all_sessions))
+        .route("/user/sessions/:session_id", delete(api_revoke_session))
+        .route("/users/merge", post(api_merge_users))
+        .route("/users/:user_id", patch(api_update_user))
+        .route("/users/:user_id/sessions", delete(api_admin_revoke_sessions))
+        .route("/service_accounts", post(api_create_service_account))
+        .route("/service_accounts/:user_id", patch(api_update_service_account).delete(api_delete_service_account))
+        .route("/service_accounts/:user_id/secret", post(api_reset_service_account_secret))
+        .route("/superusers/:user_id", put(api_set_superuser))
+        .route("/oidc/consent", post(api_oidc_consent))
+        .route("/invitations", post(api_create_invitation))
+        .route("/invitations/:invitation_id", delete(api_revoke_invitation))
+        .route("/auto_grant_rules", post(api_create_auto_grant_rule))
+        .route("/auto_grant_rules/:rule_id", delete(api_remove_auto_grant_rule))
+        .fallback(api_not_found)
+}
+
+async fn api_not_found() -> RingError {
+    RingError::NotFound("No such API route".into())
+}
+
+/// Removes the login cookie with every response of a login callback, successful or not
+async fn clear_login_cookie(mut response: Response) -> Response {
+    login_flow::clear_login_cookie(response.headers_mut());
+    response
+}
+
+/// Marks responses of the old GET routes that change state as deprecated, pointing to their successor
+async fn mark_deprecated(mut response: Response) -> Response {
+    response.headers_mut().insert("Deprecation", HeaderValue::from_static("true"));
+    response.headers_mut().insert(LINK, HeaderValue::from_static("</api/v1>; rel=\"successor-version\""));
+    response
+}
 
 pub async fn run_server(state: Arc<AppState>) -> Result<(), RingError> {
     tracing_subscriber::fmt::init();
@@ -503,32 +2685,73 @@ pub async fn run_server(state: Arc<AppState>) -> Result<(), RingError> {
     let config = RustlsConfig::from_pem_file(cert_path,key_path).await.unwrap();
 
 
+    // Old GET routes that change state; use the REST API under /api/v1 instead
+    let deprecated = Router::new()
+        .route("/rights/set/:entity_ids/:user_id/:rights", get(set_user_rights))
+        .route("/rights/add/:entity_ids/:user_id/:rights", get(add_user_rights))
+        .route("/rights/remove/:entity_ids/:user_id/:rights", get(remove_user_rights))
+        .route("/rights/request/:entity_ids/:note", get(request_access_rights))
+        .route("/entity/add/child/:entity_id/:name/:ext_id", get(add_entity_child))
+        .layer(middleware::map_response(mark_deprecated));
+
+    // Login callbacks; a login attempt can only be completed once
+    let login_callbacks = Router::new()
+        .route("/redirect/orcid", get(redirect_orcid))
+        .route("/redirect/google", get(redirect_google))
+        .route("/redirect/wikimedia", get(redirect_wikimedia))
+        .route("/redirect/github", get(redirect_github))
+        .route("/redirect/oidc/:key", get(redirect_oidc))
+        .layer(middleware::map_response(clear_login_cookie));
+
     let app = Router::new()
         .route("/redirect_to/orcid", get(redirect_to_orcid))
         .route("/redirect_to/google", get(redirect_to_google))
-        .route("/redirect/orcid", get(redirect_orcid))
-        .route("/redirect/google", get(redirect_google))
+        .route("/redirect_to/wikimedia", get(redirect_to_wikimedia))
+        .route("/redirect_to/github", get(redirect_to_github))
+        .route("/redirect_to/oidc/:key", get(redirect_to_oidc))
+        .route("/auth/systems", get(auth_systems))
         .route("/auth/info", get(auth_info))
         .route("/user/entities", get(user_entities))
         .route("/user/entity_rights/:ids", get(user_entity_rights))
-        .route("/rights/set/:entity_ids/:user_id/:rights", get(set_user_rights))
-        .route("/rights/add/:entity_ids/:user_id/:rights", get(add_user_rights))
-        .route("/rights/remove/:entity_ids/:user_id/:rights", get(remove_user_rights))
-        .route("/rights/request/:entity_ids/:note", get(request_access_rights))
+        .route("/user/access_requests", get(user_access_requests))
         .route("/rights/get/entities/:ids", get(get_rights_entities))
+        .route("/rights/assertion/key", get(rights_assertion_key))
+        .route("/rights/assertion/:ids", get(user_rights_assertion))
         .route("/user/logout", get(user_logout))
+        .route("/user/sessions", get(user_sessions))
         .route("/user/info/:id", get(user_info))
+        .route("/user/link/:key", get(user_link))
+        .route("/user/identities", get(user_identities))
+        .route("/user/tokens", get(user_tokens))
+        .route("/service_account/list/:entity_id", get(list_service_accounts))
+        .route("/service_account/token", post(service_account_token))
+        .route("/.well-known/openid-configuration", get(oidc_discovery))
+        .route("/oidc/authorize", get(oidc_authorize))
+        .route("/oidc/consent/:token", get(oidc_consent_info))
+        .route("/oidc/token", post(oidc_token))
+        .route("/oidc/userinfo", get(oidc_userinfo).post(oidc_userinfo))
+        .route("/oidc/jwks", get(oidc_jwks))
+        .route("/oauth/introspect", post(oauth_introspect))
         .route("/entities/:ids", get(entities))
-        .route("/entity/add/child/:entity_id/:name/:ext_id", get(add_entity_child))
+        .route("/invitation/list/:entity_id", get(list_invitations))
+        .route("/invitation/accept/:token", get(accept_invitation))
+        .route("/auto_grant/list/:entity_id", get(list_auto_grant_rules))
+        .route("/admin/superusers", get(list_superusers))
+        .route("/admin/log", get(superuser_log))
         .route("/search/user/:query", get(search_user))
         .route("/search/access/:query", get(search_access))
         // .route("/search/entity/:query", get(search_entity))
+        .merge(login_callbacks)
+        .merge(deprecated)
+        .nest("/api/v1", api_v1_router())
         .nest_service("/", ServeDir::new("html"))
         .with_state(state.clone())
         .layer(TraceLayer::new_for_http())
         .layer(CompressionLayer::new())
         ;
 
+    jobs::start(state.clone());
+
     let addr = SocketAddr::from(([0, 0, 0, 0], state.port_https));
     tracing::info!("listening on {}", addr);
     axum_server::bind_rustls(addr, config).serve(app.into_make_service()).await?;
@@ -549,3 +2772,87 @@ async fn main() -> Result<(), RingError> {
     run_server(state).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Form;
+
+    /// Serves the token and profile endpoints of a Wikimedia OAuth 2.0 server
+    async fn wikimedia_stand_in() -> String {
+        async fn access_token(Form(params): Form<HashMap<String,String>>) -> Json<Value> {
+            assert_eq!(params.get("code").map(|s|s.as_str()),Some("the_code"));
+            assert_eq!(params.get("code_verifier").map(|s|s.as_str()),Some("the_verifier"));
+            assert_eq!(params.get("client_secret").map(|s|s.as_str()),Some("the_secret"));
+            Json(json!({"access_token":"the_token","token_type":"Bearer"}))
+        }
+        async fn profile(headers: HeaderMap) -> Json<Value> {
+            assert_eq!(headers.get(http::header::AUTHORIZATION).and_then(|v|v.to_str().ok()),Some("Bearer the_token"));
+            Json(json!({"sub":"12345","username":"Some User","realname":"","email":"some@example.org","confirmed_email":true}))
+        }
+        let app = Router::new()
+            .route("/w/rest.php/oauth2/access_token", post(access_token))
+            .route("/w/rest.php/oauth2/resource/profile", get(profile));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}",listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service());
+        tokio::spawn(server);
+        base_url
+    }
+
+    #[tokio::test]
+    async fn wikimedia_user_is_keyed_on_central_id() {
+        let base_url = wikimedia_stand_in().await;
+        let user = fetch_wikimedia_user(&base_url,"the_client","the_secret","the_code","https://localhost/redirect/wikimedia",Some("the_verifier")).await.unwrap();
+        assert_eq!(user.system,ExternalSystem::WIKIMEDIA);
+        assert_eq!(user.external_id,"12345");
+        assert_eq!(user.external_name(),"Some User");
+        assert_eq!(user.name,"Some User");
+        assert_eq!(user.verified_email(),Some("some@example.org"));
+    }
+
+    #[tokio::test]
+    async fn unreachable_login_provider_is_an_upstream_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}",listener.local_addr().unwrap());
+        drop(listener);
+        let e = fetch_wikimedia_user(&base_url,"the_client","the_secret","the_code","https://localhost/redirect/wikimedia",None).await.unwrap_err();
+        assert_eq!(e.kind(),crate::error::ErrorKind::Upstream);
+        assert_eq!(e.json_response().0,StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_json_errors() {
+        async fn echo(Path(id): Path<usize>, JsonBody(body): JsonBody<Value>) -> Json<Value> {
+            Json(json!({"id":id,"body":body}))
+        }
+        let app = Router::new().route("/echo/:id", post(echo));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}",listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        let client = reqwest::Client::new();
+        for (path,body) in [("/echo/abc","{}"),("/echo/1","{not json")] {
+            let response = client.post(format!("{base_url}{path}")).header(http::header::CONTENT_TYPE,"application/json").body(body).send().await.unwrap();
+            assert_eq!(response.status(),reqwest::StatusCode::BAD_REQUEST);
+            let j: Value = response.json().await.unwrap();
+            assert_eq!(j["error"],"validation");
+        }
+    }
+
+    #[test]
+    fn github_next_page_follows_rel_next() {
+        let link = r#"<https://api.github.com/user/orgs?per_page=100&page=2>; rel="next", <https://api.github.com/user/orgs?per_page=100&page=3>; rel="last""#;
+        assert_eq!(github_next_page(link).as_deref(),Some("/user/orgs?per_page=100&page=2"));
+        let link = r#"<https://api.github.com/user/orgs?per_page=100&page=2>; rel="prev", <https://api.github.com/user/orgs?per_page=100&page=1>; rel="first""#;
+        assert_eq!(github_next_page(link),None);
+        // Only GitHub API pages are followed, as they are fetched with the user's access token
+        assert_eq!(github_next_page(r#"<https://example.org/orgs?page=2>; rel="next""#),None);
+    }
+
+    #[test]
+    fn token_entity_ids_must_all_be_numbers() {
+        assert_eq!(parse_token_entity_ids("12, 13,").unwrap(),vec![12,13]);
+        assert!(parse_token_entity_ids("").unwrap().is_empty());
+        assert!(parse_token_entity_ids("12,1x3").is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde_json::Value;
+use crate::app_state::AppState;
+use crate::error::RingError;
+use crate::external_system::ExternalAccessRequest;
+
+pub static TEMPLATE_ACCESS_REQUESTED: &str = "access_requested";
+pub static TEMPLATE_ACCESS_GRANTED: &str = "access_granted";
+pub static TEMPLATE_ACCESS_DENIED: &str = "access_denied";
+pub static TEMPLATE_ACCESS_ESCALATED: &str = "access_request_escalated";
+pub static TEMPLATE_INVITATION: &str = "invitation";
+
+#[derive(Clone, Debug)]
+pub struct EmailTemplate {
+    pub subject: String,
+    pub body: String,
+}
+
+impl EmailTemplate {
+    fn new(subject: &str, body: &str) -> Self {
+        Self {
+            subject: subject.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Replaces all `{{key}}` placeholders in subject and body
+    pub fn render(&self, values: &HashMap<String,String>) -> (String,String) {
+        (Self::fill(&self.subject,values),Self::fill(&self.body,values))
+    }
+
+    /// Substitutes placeholders in a single pass, so placeholders within values (e.g. a note) are left as they are
+    fn fill(template: &str, values: &HashMap<String,String>) -> String {
+        let mut ret = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            ret.push_str(&rest[..start]);
+            let after = &rest[start+2..];
+            match after.find("}}").and_then(|end|values.get(&after[..end]).map(|value|(end,value))) {
+                Some((end,value)) => {
+                    ret.push_str(value);
+                    rest = &after[end+2..];
+                }
+                None => {
+                    ret.push_str("{{");
+                    rest = after;
+                }
+            }
+        }
+        ret.push_str(rest);
+        ret
+    }
+
+    fn defaults() -> HashMap<String,EmailTemplate> {
+        let mut ret = HashMap::new();
+        ret.insert(TEMPLATE_ACCESS_REQUESTED.to_string(), Self::new(
+            "[SAURON] Access request for {{entity_name}}",
+            "Hello {{recipient_name}},\n\n{{requester_name}} has requested access to \"{{entity_name}}\" (#{{entity_id}}).\nNote: {{note}}\n\nYou can review this request here:\n{{url}}\n",
+        ));
+        ret.insert(TEMPLATE_ACCESS_GRANTED.to_string(), Self::new(
+            "[SAURON] Access granted for {{entity_name}}",
+            "Hello {{recipient_name}},\n\nYour access request for \"{{entity_name}}\" (#{{entity_id}}) has been granted.\nRights: {{rights}}\n\n{{url}}\n",
+        ));
+        ret.insert(TEMPLATE_ACCESS_DENIED.to_string(), Self::new(
+            "[SAURON] Access denied for {{entity_name}}",
+            "Hello {{recipient_name}},\n\nYour access request for \"{{entity_name}}\" (#{{entity_id}}) has been denied.\n\n{{url}}\n",
+        ));
+        ret.insert(TEMPLATE_ACCESS_ESCALATED.to_string(), Self::new(
+            "[SAURON] Unanswered access request for {{entity_name}}",
+            "Hello {{recipient_name}},\n\n{{requester_name}} requested access to \"{{entity_name}}\" (#{{entity_id}}) on {{created}}, and nobody has answered yet.\nAs an admin of a parent entity, you can decide on this request.\nNote: {{note}}\n\n{{url}}\n",
+        ));
+        ret.insert(TEMPLATE_INVITATION.to_string(), Self::new(
+            "[SAURON] Invitation to {{entity_names}}",
+            "Hello,\n\n{{inviter_name}} has invited you to \"{{entity_names}}\", with these rights: {{rights}}.\nThe rights will be granted when you log in with an account that uses this email address:\n{{url}}\n",
+        ));
+        ret
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    templates: HashMap<String,EmailTemplate>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Notifier {
+    /// Creates a Notifier from the `notifications` config object.
+    /// Returns `None` if notifications are not configured.
+    pub fn from_config(config: &Value) -> Result<Option<Self>,RingError> {
+        let smtp = &config["smtp"];
+        let host = match smtp["host"].as_str() {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+        let from: Mailbox = config["from"].as_str()
+            .ok_or_else(||RingError::String("notifications.from missing in config".into()))?
+            .parse()
+            .map_err(|e: lettre::address::AddressError|RingError::String(e.to_string()))?;
+
+        // "none" is meant for local SMTP stand-ins (MailHog, smtp4dev etc.)
+        let mut builder = match smtp["tls"].as_str().unwrap_or("starttls") {
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(host).map_err(|e|RingError::String(e.to_string()))?,
+            _ => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host).map_err(|e|RingError::String(e.to_string()))?,
+        };
+        if let Some(port) = smtp["port"].as_u64() {
+            builder = builder.port(port as u16);
+        }
+        if let (Some(username),Some(password)) = (smtp["username"].as_str(),smtp["password"].as_str()) {
+            if !username.is_empty() {
+                builder = builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+            }
+        }
+
+        let mut templates = EmailTemplate::defaults();
+        if let Some(custom) = config["templates"].as_object() {
+            for (name,template) in custom {
+                let default = templates.get(name).cloned().unwrap_or_else(||EmailTemplate::new("",""));
+                let template = EmailTemplate {
+                    subject: template["subject"].as_str().map(|s|s.to_string()).unwrap_or(default.subject),
+                    body: template["body"].as_str().map(|s|s.to_string()).unwrap_or(default.body),
+                };
+                templates.insert(name.to_owned(), template);
+            }
+        }
+
+        Ok(Some(Self {
+            from,
+            transport: builder.build(),
+            templates,
+            max_attempts: config["max_attempts"].as_u64().unwrap_or(5).max(1) as u32,
+            retry_delay: Duration::from_secs(config["retry_delay_sec"].as_u64().unwrap_or(30)),
+        }))
+    }
+
+    /// Renders a template and sends it in the background, retrying with exponential backoff
+    pub fn send(&self, to: &str, template_name: &str, values: HashMap<String,String>) -> Result<(),RingError> {
+        let template = self.templates.get(template_name)
+            .ok_or_else(||RingError::String(format!("No email template '{template_name}'")))?;
+        let (subject,body) = template.render(&values);
+        let to: Mailbox = to.parse().map_err(|e: lettre::address::AddressError|RingError::String(e.to_string()))?;
+        let message = Message::builder()
+            .from(self.from.to_owned())
+            .to(to.to_owned())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e|RingError::String(e.to_string()))?;
+
+        let transport = self.transport.clone();
+        let max_attempts = self.max_attempts;
+        let retry_delay = self.retry_delay;
+        tokio::spawn(async move {
+            let mut delay = retry_delay;
+            for attempt in 1..=max_attempts {
+                match transport.send(message.clone()).await {
+                    Ok(_) => return,
+                    Err(e) => {
+                        tracing::warn!("Sending email to {to} failed (attempt {attempt}/{max_attempts}): {e}");
+                        if attempt < max_attempts {
+                            tokio::time::sleep(delay).await;
+                            delay *= 2;
+                        }
+                    }
+                }
+            }
+            tracing::error!("Giving up sending email to {to}");
+        });
+        Ok(())
+    }
+}
+
+
+/// Emails all admins (direct or inherited) of the entities that a user has requested access to
+pub async fn notify_access_requested(state: &Arc<AppState>, user_id: usize, entity_ids: &[usize], note: &str) -> Result<(),RingError> {
+    let notifier = match &state.notifier {
+        Some(notifier) => notifier,
+        None => return Ok(()),
+    };
+    let dal = state.dal.read().await;
+    let requester = dal.get_user(user_id).await?;
+    let entities = dal.load_entities(entity_ids).await?;
+    for entity in entities.as_sorted_vec() {
+        let mut admin_ids: Vec<usize> = dal.get_all_rights_for_entity(entity.id).await?
+            .into_iter()
+            .filter(|(_user_id,right)|right=="admin")
+            .map(|(user_id,_right)|user_id)
+            .collect();
+        admin_ids.sort();
+        admin_ids.dedup();
+        for admin_id in admin_ids {
+            let admin = match dal.get_user(admin_id).await {
+                Ok(admin) => admin,
+                Err(e) => {
+                    tracing::warn!("Could not load admin #{admin_id}: {e}");
+                    continue;
+                }
+            };
+            if admin.email.is_empty() {
+                continue;
+            }
+            let values = HashMap::from([
+                ("recipient_name".to_string(), admin.name.to_owned()),
+                ("requester_name".to_string(), requester.name.to_owned()),
+                ("entity_id".to_string(), entity.id.to_string()),
+                ("entity_name".to_string(), entity.name.to_owned()),
+                ("note".to_string(), note.to_string()),
+                ("url".to_string(), format!("{}/#/entity/{}",state.get_redirect_server(),entity.id)),
+            ]);
+            if let Err(e) = notifier.send(&admin.email, TEMPLATE_ACCESS_REQUESTED, values) {
+                tracing::warn!("Could not notify admin #{admin_id} about an access request: {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Emails a user that their access request for an entity was granted or denied
+pub async fn notify_access_decided(state: &Arc<AppState>, user_id: usize, entity_id: usize, granted_rights: Option<&[String]>) -> Result<(),RingError> {
+    let notifier = match &state.notifier {
+        Some(notifier) => notifier,
+        None => return Ok(()),
+    };
+    let dal = state.dal.read().await;
+    let requester = dal.get_user(user_id).await?;
+    if requester.email.is_empty() {
+        return Ok(());
+    }
+    let entity_name = dal.load_entities(&[entity_id]).await?
+        .get(entity_id)
+        .map(|e|e.name.to_owned())
+        .unwrap_or_default();
+    let mut values = HashMap::from([
+        ("recipient_name".to_string(), requester.name.to_owned()),
+        ("entity_id".to_string(), entity_id.to_string()),
+        ("entity_name".to_string(), entity_name),
+        ("url".to_string(), format!("{}/#/entity/{}",state.get_redirect_server(),entity_id)),
+    ]);
+    let template = match granted_rights {
+        Some(rights) => {
+            values.insert("rights".to_string(), rights.join(", "));
+            TEMPLATE_ACCESS_GRANTED
+        }
+        None => TEMPLATE_ACCESS_DENIED,
+    };
+    notifier.send(&requester.email, template, values)
+}
+
+/// Emails an invitation to an email address
+pub async fn notify_invited(state: &Arc<AppState>, email: &str, inviter_id: usize, entity_ids: &[usize], rights: &[String]) -> Result<(),RingError> {
+    let notifier = match &state.notifier {
+        Some(notifier) => notifier,
+        None => return Ok(()),
+    };
+    let dal = state.dal.read().await;
+    let inviter = dal.get_user(inviter_id).await?;
+    let entity_names: Vec<String> = dal.load_entities(entity_ids).await?
+        .as_sorted_vec()
+        .iter()
+        .map(|e|e.name.to_owned())
+        .collect();
+    let values = HashMap::from([
+        ("inviter_name".to_string(), inviter.name.to_owned()),
+        ("entity_names".to_string(), entity_names.join(", ")),
+        ("rights".to_string(), rights.join(", ")),
+        ("url".to_string(), state.get_redirect_server()),
+    ]);
+    notifier.send(email, TEMPLATE_INVITATION, values)
+}
+
+/// Emails the direct admins of ancestor entities about an access request that was escalated to them
+pub async fn notify_access_escalated(state: &Arc<AppState>, request: &ExternalAccessRequest, ancestor_ids: &[usize]) -> Result<(),RingError> {
+    let notifier = match &state.notifier {
+        Some(notifier) => notifier,
+        None => return Ok(()),
+    };
+    let dal = state.dal.read().await;
+    let requester = dal.get_user(request.user_id).await?;
+    let entity_name = dal.load_entities(&[request.entity_id]).await?
+        .get(request.entity_id)
+        .map(|e|e.name.to_owned())
+        .unwrap_or_default();
+    let mut admin_ids: Vec<usize> = dal.get_all_direct_access_for_entities(ancestor_ids).await?
+        .into_iter()
+        .filter(|(_user_id,_entity_id,right)|right=="admin")
+        .map(|(user_id,_entity_id,_right)|user_id)
+        .collect();
+    admin_ids.sort();
+    admin_ids.dedup();
+    let admin_ids = dal.active_users(admin_ids).await?;
+    for admin_id in admin_ids {
+        let admin = match dal.get_user(admin_id).await {
+            Ok(admin) => admin,
+            Err(e) => {
+                tracing::warn!("Could not load admin #{admin_id}: {e}");
+                continue;
+            }
+        };
+        if admin.email.is_empty() {
+            continue;
+        }
+        let values = HashMap::from([
+            ("recipient_name".to_string(), admin.name.to_owned()),
+            ("requester_name".to_string(), requester.name.to_owned()),
+            ("entity_id".to_string(), request.entity_id.to_string()),
+            ("entity_name".to_string(), entity_name.to_owned()),
+            ("note".to_string(), request.note.to_owned()),
+            ("created".to_string(), request.created.to_owned()),
+            ("url".to_string(), format!("{}/#/entity/{}",state.get_redirect_server(),request.entity_id)),
+        ]);
+        if let Err(e) = notifier.send(&admin.email, TEMPLATE_ACCESS_ESCALATED, values) {
+            tracing::warn!("Could not notify admin #{admin_id} about an escalated access request: {e}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn render_substitutes_in_a_single_pass() {
+        let template = EmailTemplate::new("Access to {{entity_name}}", "Note: {{note}}\n{{url}} {{unknown}}");
+        let values = HashMap::from([
+            ("entity_name".to_string(), "{{note}}".to_string()),
+            ("note".to_string(), "see {{url}}".to_string()),
+            ("url".to_string(), "https://example.org".to_string()),
+        ]);
+        let (subject,body) = template.render(&values);
+        assert_eq!(subject, "Access to {{note}}");
+        assert_eq!(body, "Note: see {{url}}\nhttps://example.org {{unknown}}");
+    }
+
+    /// A minimal SMTP server that passes the DATA of every message it receives to a channel.
+    /// Several connections are served, as the lettre pool opens an idle connection of its own.
+    async fn smtp_stand_in(listener: TcpListener, messages: mpsc::UnboundedSender<String>) {
+        loop {
+            let (socket,_) = listener.accept().await.unwrap();
+            let messages = messages.clone();
+            tokio::spawn(async move {
+                let (reader,mut writer) = socket.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let command = line.to_uppercase();
+                    if command.starts_with("DATA") {
+                        writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                        let mut data = String::new();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if line=="." {
+                                break;
+                            }
+                            data.push_str(&line);
+                            data.push('\n');
+                        }
+                        let _ = messages.send(data);
+                        writer.write_all(b"250 queued\r\n").await.unwrap();
+                    } else if command.starts_with("QUIT") {
+                        let _ = writer.write_all(b"221 bye\r\n").await;
+                        return;
+                    } else if command.starts_with("EHLO") || command.starts_with("HELO") {
+                        writer.write_all(b"250 localhost\r\n").await.unwrap();
+                    } else {
+                        writer.write_all(b"250 OK\r\n").await.unwrap();
+                    }
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn send_delivers_to_smtp_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender,mut messages) = mpsc::unbounded_channel();
+        tokio::spawn(smtp_stand_in(listener,sender));
+        let config = json!({
+            "from": "sauron@example.org",
+            "smtp": {"host":"127.0.0.1","port":port,"tls":"none"},
+            "retry_delay_sec": 0,
+        });
+        let notifier = Notifier::from_config(&config).unwrap().unwrap();
+        let values = HashMap::from([
+            ("recipient_name".to_string(), "Admin".to_string()),
+            ("entity_id".to_string(), "5".to_string()),
+            ("entity_name".to_string(), "Lab".to_string()),
+            ("url".to_string(), "https://example.org/#/entity/5".to_string()),
+        ]);
+        notifier.send("admin@example.org", TEMPLATE_ACCESS_DENIED, values).unwrap();
+        let data = tokio::time::timeout(Duration::from_secs(10), messages.recv()).await.unwrap().unwrap();
+        assert!(data.contains("To: admin@example.org"));
+        assert!(data.contains("Subject: [SAURON] Access denied for Lab"));
+        assert!(data.contains("https://example.org/#/entity/5"));
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use http::header::ACCEPT;
+use reqwest::Url;
+use serde_json::Value;
+use crate::error::RingError;
+use crate::external_system::{ExternalSystem, ExternalSystemUser};
+use crate::login_flow::LoginFlow;
+
+/// A generic OpenID Connect login provider, configured under `systems` with `"type":"oidc"`
+#[derive(Clone, Debug)]
+pub struct OidcProvider {
+    pub key: String,
+    pub label: String,
+    pub discovery_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+    pub claim_id: String,
+    pub claim_name: String,
+    pub claim_email: String,
+    pub claim_email_verified: String,
+    pub profile_url: String,
+}
+
+impl OidcProvider {
+    /// Returns all OIDC providers from the `systems` config object
+    pub fn all_from_config(systems: &Value) -> Result<HashMap<String,Self>,RingError> {
+        let mut ret = HashMap::new();
+        if let Some(systems) = systems.as_object() {
+            for (key,config) in systems {
+                if config["type"].as_str()==Some("oidc") {
+                    // Users are stored by system key, so a built-in system's key would be read back as that system
+                    let is_oidc_key = |system: ExternalSystem| system==ExternalSystem::OIDC(key.to_owned());
+                    if !is_oidc_key(ExternalSystem::from_name(key)) || !is_oidc_key(ExternalSystem::from(key.to_owned())) {
+                        return Err(RingError::String(format!("systems.{key}: this key is reserved for a built-in login system")));
+                    }
+                    ret.insert(key.to_owned(), Self::from_config(key,config)?);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn from_config(key: &str, config: &Value) -> Result<Self,RingError> {
+        let get = |name: &str| -> Result<String,RingError> {
+            config[name].as_str()
+                .map(|s|s.to_string())
+                .ok_or_else(||RingError::String(format!("systems.{key}.{name} missing in config")))
+        };
+        let claims = &config["claims"];
+        Ok(Self {
+            key: key.to_string(),
+            label: config["label"].as_str().unwrap_or(key).to_string(),
+            discovery_url: get("discovery_url")?,
+            client_id: get("client_id")?,
+            client_secret: get("client_secret")?,
+            scopes: config["scopes"].as_str().unwrap_or("openid profile email").to_string(),
+            claim_id: claims["id"].as_str().unwrap_or("sub").to_string(),
+            claim_name: claims["name"].as_str().unwrap_or("name").to_string(),
+            claim_email: claims["email"].as_str().unwrap_or("email").to_string(),
+            claim_email_verified: claims["email_verified"].as_str().unwrap_or("email_verified").to_string(),
+            profile_url: config["profile_url"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    /// Fetches the provider metadata from the discovery URL
+    pub async fn discover(&self) -> Result<Value,RingError> {
+        let j = reqwest::Client::new()
+            .get(&self.discovery_url)
+            .header(ACCEPT, "application/json")
+            .send()
+            .await?
+            .json::<Value>().await?;
+        Ok(j)
+    }
+
+    pub async fn authorize_url(&self, redirect_url: &str, flow: &LoginFlow) -> Result<String,RingError> {
+        let metadata = self.discover().await?;
+        let endpoint = Self::endpoint(&metadata,"authorization_endpoint")?;
+        let mut params = vec![
+            ("client_id",self.client_id.as_str()),
+            ("response_type","code"),
+            ("scope",self.scopes.as_str()),
+            ("redirect_uri",redirect_url),
+        ];
+        params.append(&mut flow.params());
+        let url = Url::parse_with_params(endpoint, &params).map_err(|e|RingError::Upstream(e.to_string()))?;
+        Ok(url.to_string())
+    }
+
+    /// Exchanges an authorization code for an access token, and creates a user from the userinfo claims
+    pub async fn fetch_user(&self, code: &str, redirect_url: &str, code_verifier: Option<&str>) -> Result<ExternalSystemUser,RingError> {
+        let metadata = self.discover().await?;
+        let client = reqwest::Client::new();
+        let mut form = vec![
+            ("client_id",self.client_id.as_str()),
+            ("client_secret",self.client_secret.as_str()),
+            ("grant_type","authorization_code"),
+            ("code",code),
+            ("redirect_uri",redirect_url),
+        ];
+        if let Some(code_verifier) = code_verifier {
+            form.push(("code_verifier",code_verifier));
+        }
+        let j = client
+            .post(Self::endpoint(&metadata,"token_endpoint")?)
+            .header(ACCEPT, "application/json")
+            .form(&form)
+            .send()
+            .await?
+            .json::<Value>().await?;
+        let access_token = j["access_token"].as_str()
+            .ok_or_else(||RingError::Upstream(format!("No access token from {}",self.key)))?;
+
+        let claims = client
+            .get(Self::endpoint(&metadata,"userinfo_endpoint")?)
+            .header(ACCEPT, "application/json")
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .json::<Value>().await?;
+
+        let external_id = Self::claim(&claims,&self.claim_id)
+            .ok_or_else(||RingError::Upstream(format!("No '{}' claim from {}",self.claim_id,self.key)))?;
+        let name = Self::claim(&claims,&self.claim_name).unwrap_or_else(||external_id.to_owned());
+        let email = Self::claim(&claims,&self.claim_email).unwrap_or_default();
+        let email_verified = Self::claim(&claims,&self.claim_email_verified).as_deref()==Some("true");
+        Ok(ExternalSystemUser {
+            id: None,
+            system: ExternalSystem::OIDC(self.key.to_owned()),
+            name,
+            external_id,
+            email,
+            email_verified,
+            bespoke_data: claims,
+            principal_id: None,
+            deactivated: false,
+        })
+    }
+
+    /// Returns the profile URL for a user of this provider, if configured (`{id}` is replaced by the external ID)
+    pub fn as_url(&self, external_id: &str) -> String {
+        self.profile_url.replace("{id}", external_id)
+    }
+
+    fn endpoint<'a>(metadata: &'a Value, name: &str) -> Result<&'a str,RingError> {
+        metadata[name].as_str().ok_or_else(||RingError::Upstream(format!("No {name} in OIDC discovery document")))
+    }
+
+    /// Reads a claim either by top-level name, or by JSON pointer (starting with '/')
+    fn claim(claims: &Value, name: &str) -> Option<String> {
+        let value = match name.starts_with('/') {
+            true => claims.pointer(name)?,
+            false => claims.get(name)?,
+        };
+        match value {
+            Value::String(s) => Some(s.to_owned()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider_config() -> Value {
+        json!({"type":"oidc","discovery_url":"https://idp.example.org/.well-known/openid-configuration","client_id":"id","client_secret":"secret"})
+    }
+
+    #[test]
+    fn providers_are_read_from_the_systems_config() {
+        let providers = OidcProvider::all_from_config(&json!({"orcid":{"client_id":"x"},"my_idp":provider_config()})).unwrap();
+        assert_eq!(providers.len(),1);
+        let provider = &providers["my_idp"];
+        assert_eq!(provider.label,"my_idp");
+        assert_eq!(provider.claim_id,"sub");
+        let mut config = provider_config();
+        config["client_secret"] = Value::Null;
+        assert!(OidcProvider::all_from_config(&json!({"my_idp":config})).is_err());
+    }
+
+    #[test]
+    fn built_in_system_keys_are_reserved() {
+        assert!(OidcProvider::all_from_config(&json!({"orcid":provider_config()})).is_err());
+        assert!(OidcProvider::all_from_config(&json!({"Google":provider_config()})).is_err());
+    }
+
+    #[test]
+    fn claims_are_read_by_name_or_pointer() {
+        let claims = json!({"sub":"abc","uid":42,"email_verified":true,"profile":{"email":"some@example.org"}});
+        assert_eq!(OidcProvider::claim(&claims,"sub").as_deref(),Some("abc"));
+        assert_eq!(OidcProvider::claim(&claims,"uid").as_deref(),Some("42"));
+        assert_eq!(OidcProvider::claim(&claims,"email_verified").as_deref(),Some("true"));
+        assert_eq!(OidcProvider::claim(&claims,"/profile/email").as_deref(),Some("some@example.org"));
+        assert_eq!(OidcProvider::claim(&claims,"profile"),None);
+        assert_eq!(OidcProvider::claim(&claims,"missing"),None);
+    }
+}